@@ -1,10 +1,165 @@
 use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::time::Duration;
 
 use crate::cache::Error;
+use crate::models::Version;
 
 #[async_trait]
 pub trait Cache<K, V> {
     async fn get(&self, k: &K) -> Result<Option<V>, Error>;
     async fn set(&self, k: K, v: V) -> Result<(), Error>;
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error>;
     async fn delete(&self, k: &K) -> Result<(), Error>;
+
+    // Number of live entries.
+    async fn len(&self) -> Result<usize, Error>;
+
+    async fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len().await? == 0)
+    }
+
+    // Batch operations. The default implementations loop over the single-key
+    // ones; backends override them when they can do better.
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error>
+    where
+        K: Sync,
+        V: Send,
+    {
+        let mut values = Vec::with_capacity(ks.len());
+        for k in ks {
+            values.push(self.get(k).await?);
+        }
+
+        Ok(values)
+    }
+
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error>
+    where
+        K: Send + 'async_trait,
+        V: Send + 'async_trait,
+    {
+        for (k, v) in items {
+            self.set(k, v).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error>
+    where
+        K: Sync,
+    {
+        for k in ks {
+            self.delete(k).await?;
+        }
+
+        Ok(())
+    }
+}
+
+// TaggedCache groups entries under tags so they can be invalidated together
+// without knowing their keys.
+#[async_trait]
+pub trait TaggedCache<K, V>: Cache<K, V> {
+    // Like `set` (or `set_with_ttl` when a TTL is given), attaching the tags
+    // to the entry.
+    async fn set_tagged(
+        &self,
+        k: K,
+        v: V,
+        tags: &[&str],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error>;
+
+    // Deletes every entry carrying the tag and returns how many were deleted.
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize, Error>;
+}
+
+// VersionedCache gives optimistic concurrency: every write produces a new
+// version, and `compare_and_set` only writes if the entry was not changed
+// since it was read.
+#[async_trait]
+pub trait VersionedCache<K, V>: Cache<K, V> {
+    async fn get_versioned(&self, k: &K) -> Result<Option<(V, Version)>, Error>;
+
+    // Stores the value if the current version is `expected`, `None` meaning
+    // that the key must not exist. Fails with `Error::Conflict` otherwise.
+    // Returns the version of the stored value.
+    async fn compare_and_set(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: V,
+    ) -> Result<Version, Error>;
+
+    // Like `compare_and_set`, with the value expiring after `ttl`.
+    async fn compare_and_set_with_ttl(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: V,
+        ttl: Duration,
+    ) -> Result<Version, Error>;
+}
+
+// AtomicCache has conditional writes, each applied atomically, to coordinate
+// writers sharing the cache.
+#[async_trait]
+pub trait AtomicCache<K, V>: Cache<K, V> {
+    // Stores the value only if the key does not exist. Returns whether it was
+    // stored.
+    async fn set_if_absent(&self, k: K, v: V, ttl: Option<Duration>) -> Result<bool, Error>;
+
+    // Deletes the entry only if it holds `v`. Returns whether it was deleted.
+    async fn delete_if_equals(&self, k: &K, v: &V) -> Result<bool, Error>;
+
+    // Makes the entry expire after `ttl` only if it holds `v`. Returns whether
+    // the expiration was changed.
+    async fn expire_if_equals(&self, k: &K, v: &V, ttl: Duration) -> Result<bool, Error>;
+}
+
+// ScanCache lists and deletes entries by key pattern. Patterns are matched
+// against serialized keys segment by segment, segments being separated by
+// dots, and `*` matches any characters within a segment: `user.*` matches
+// `user.1` but not `user.1.posts`.
+#[async_trait]
+pub trait ScanCache<K> {
+    // Keys of the live entries matching `pattern`, in no particular order.
+    fn scan(&self, pattern: &str) -> BoxStream<'_, Result<K, Error>>;
+
+    // Deletes the entries matching `pattern` and returns how many were
+    // deleted.
+    async fn delete_matching(&self, pattern: &str) -> Result<usize, Error>;
+}
+
+// Change made to an entry of a cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<K, V> {
+    Set { key: K, value: V },
+    // Deleted explicitly or evicted to make room for other entries.
+    Deleted { key: K },
+    // Reported when the expiration is noticed, by a read or by a purge, not
+    // when the entry expires.
+    Expired { key: K },
+}
+
+impl<K, V> Change<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Change::Set { key, .. } => key,
+            Change::Deleted { key } => key,
+            Change::Expired { key } => key,
+        }
+    }
+}
+
+// WatchCache streams changes as they are made, so they can be reacted to
+// without polling. A watcher too slow to keep up gets `Error::Lagged` with the
+// number of changes it missed, and then continues with the next ones.
+pub trait WatchCache<K, V> {
+    fn watch(&self, k: &K) -> BoxStream<'static, Result<Change<K, V>, Error>>;
+
+    // Changes of the entries whose serialized keys start with `prefix`.
+    fn watch_prefix(&self, prefix: &str) -> BoxStream<'static, Result<Change<K, V>, Error>>;
 }
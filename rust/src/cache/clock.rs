@@ -0,0 +1,84 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Clock
+pub trait Clock: Sync + Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+// SystemClock
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// ManualClock: only moves when told to, so expiration can be tested without sleeping.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = expiration(*now, d).unwrap_or(*now);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new(Utc::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+// Returns the instant at which something created at `now` with the given
+// TTL expires. A TTL too large to be represented never expires.
+pub(crate) fn expiration(now: DateTime<Utc>, ttl: Duration) -> Option<DateTime<Utc>> {
+    ChronoDuration::from_std(ttl)
+        .ok()
+        .and_then(|ttl| now.checked_add_signed(ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let start = Utc::now();
+        let clock = ManualClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(clock.now(), start + ChronoDuration::seconds(10));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn huge_ttl_never_expires() {
+        assert!(expiration(Utc::now(), Duration::MAX).is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cache::Error;
+
+// Codec turns values into the bytes stored by byte-level caches.
+pub trait Codec: Sync + Send {
+    fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(v).map_err(|err| Error::SerializingValue(Box::new(err)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(data).map_err(|err| Error::DeserializingValue(Box::new(err)))
+    }
+}
+
+// MsgPackCodec encodes structs as maps so fields can be added or reordered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec_named(v).map_err(|err| Error::SerializingValue(Box::new(err)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error> {
+        rmp_serde::from_slice(data).map_err(|err| Error::DeserializingValue(Box::new(err)))
+    }
+}
+
+// BincodeCodec is the most compact, but it is not self-describing: values must
+// be decoded with exactly the type they were encoded with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(v).map_err(|err| Error::SerializingValue(err))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(data).map_err(|err| Error::DeserializingValue(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+        scores: HashMap<String, f64>,
+        manager: Option<Box<User>>,
+    }
+
+    fn user() -> User {
+        User {
+            id: 1,
+            name: "Alan".to_string(),
+            tags: vec!["admin".to_string()],
+            scores: HashMap::from([("math".to_string(), 9.5)]),
+            manager: Some(Box::new(User {
+                id: 2,
+                name: "Ada".to_string(),
+                tags: Vec::new(),
+                scores: HashMap::new(),
+                manager: None,
+            })),
+        }
+    }
+
+    fn round_trip<C: Codec>(codec: C) {
+        let data = codec.encode(&user()).unwrap();
+        assert_eq!(codec.decode::<User>(&data).unwrap(), user());
+
+        assert!(matches!(
+            codec.decode::<User>(&[0xc1, 0xff]),
+            Err(Error::DeserializingValue(_))
+        ));
+    }
+
+    #[test]
+    fn json() {
+        round_trip(JsonCodec);
+    }
+
+    #[test]
+    fn msgpack() {
+        round_trip(MsgPackCodec);
+    }
+
+    #[test]
+    fn bincode() {
+        round_trip(BincodeCodec);
+    }
+
+    #[test]
+    fn serialization_errors() {
+        // JSON maps only accept string keys.
+        let map = HashMap::from([((1, 2), "value")]);
+
+        assert!(matches!(
+            JsonCodec.encode(&map),
+            Err(Error::SerializingValue(_))
+        ));
+    }
+}
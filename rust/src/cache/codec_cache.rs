@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::cache::{Cache, Codec, Error, JsonCodec, ScanCache, TaggedCache};
+
+// CodecCache turns a cache of bytes, such as `RedisCache`, into a cache of
+// typed values.
+pub struct CodecCache<C, V, D = JsonCodec> {
+    cache: C,
+    codec: D,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<C, V> CodecCache<C, V> {
+    pub fn new(cache: C) -> CodecCache<C, V> {
+        CodecCache::with_codec(cache, JsonCodec)
+    }
+}
+
+impl<C, V, D> CodecCache<C, V, D> {
+    pub fn with_codec(cache: C, codec: D) -> CodecCache<C, V, D> {
+        CodecCache {
+            cache,
+            codec,
+            value: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.cache
+    }
+}
+
+impl<C, V, D> Clone for CodecCache<C, V, D>
+where
+    C: Clone,
+    D: Clone,
+{
+    fn clone(&self) -> Self {
+        CodecCache::with_codec(self.cache.clone(), self.codec.clone())
+    }
+}
+
+#[async_trait]
+impl<K, V, C, D> Cache<K, V> for CodecCache<C, V, D>
+where
+    K: Sync + Send + 'static,
+    V: Serialize + DeserializeOwned + Sync + Send + 'static,
+    C: Cache<K, Vec<u8>> + Sync + Send,
+    D: Codec,
+{
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        match self.cache.get(k).await? {
+            Some(data) => Ok(Some(self.codec.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        let data = self.codec.encode(&v)?;
+
+        self.cache.set(k, data).await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        let data = self.codec.encode(&v)?;
+
+        self.cache.set_with_ttl(k, data, ttl).await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.cache.delete(k).await
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        self.cache.len().await
+    }
+
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error> {
+        self.cache
+            .get_many(ks)
+            .await?
+            .into_iter()
+            .map(|data| data.map(|data| self.codec.decode(&data)).transpose())
+            .collect()
+    }
+
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error> {
+        let items = items
+            .into_iter()
+            .map(|(k, v)| Ok((k, self.codec.encode(&v)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        self.cache.set_many(items).await
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        self.cache.delete_many(ks).await
+    }
+}
+
+#[async_trait]
+impl<K, V, C, D> TaggedCache<K, V> for CodecCache<C, V, D>
+where
+    K: Sync + Send + 'static,
+    V: Serialize + DeserializeOwned + Sync + Send + 'static,
+    C: TaggedCache<K, Vec<u8>> + Sync + Send,
+    D: Codec,
+{
+    async fn set_tagged(
+        &self,
+        k: K,
+        v: V,
+        tags: &[&str],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let data = self.codec.encode(&v)?;
+
+        self.cache.set_tagged(k, data, tags, ttl).await
+    }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize, Error> {
+        self.cache.invalidate_tag(tag).await
+    }
+}
+
+#[async_trait]
+impl<K, V, C, D> ScanCache<K> for CodecCache<C, V, D>
+where
+    K: Send + 'static,
+    C: ScanCache<K> + Sync + Send,
+    D: Codec,
+{
+    fn scan(&self, pattern: &str) -> BoxStream<'_, Result<K, Error>> {
+        self.cache.scan(pattern)
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<usize, Error> {
+        self.cache.delete_matching(pattern).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    use crate::cache::resp_server::RespServer;
+    use crate::cache::{BincodeCodec, InMemCache, MsgPackCodec, RedisCache};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        user_id: String,
+        roles: Vec<String>,
+    }
+
+    fn session() -> Session {
+        Session {
+            user_id: "user#01".to_string(),
+            roles: vec!["admin".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_values_over_redis() {
+        let server = RespServer::start().await;
+        let redis = RedisCache::connect(server.addr()).await.unwrap();
+        let cache: CodecCache<_, Session, _> = CodecCache::with_codec(redis, MsgPackCodec);
+
+        cache.set("session", session()).await.unwrap();
+        assert_eq!(cache.get(&"session").await.unwrap(), Some(session()));
+
+        cache
+            .set_many(vec![("a", session()), ("b", session())])
+            .await
+            .unwrap();
+        let values = cache.get_many(&["a", "b", "c"]).await.unwrap();
+        assert_eq!(values, vec![Some(session()), Some(session()), None]);
+    }
+
+    #[tokio::test]
+    async fn codecs() {
+        let json: CodecCache<_, Session> = CodecCache::new(InMemCache::new());
+        let bincode: CodecCache<_, Session, _> =
+            CodecCache::with_codec(InMemCache::new(), BincodeCodec);
+
+        json.set(1, session()).await.unwrap();
+        bincode.set(1, session()).await.unwrap();
+
+        assert_eq!(
+            json.inner().get(&1).await.unwrap().unwrap(),
+            br#"{"user_id":"user#01","roles":["admin"]}"#
+        );
+        assert_eq!(bincode.get(&1).await.unwrap(), Some(session()));
+    }
+
+    #[tokio::test]
+    async fn decoding_errors() {
+        let bytes = InMemCache::new();
+        let cache: CodecCache<_, Session> = CodecCache::new(bytes.clone());
+
+        bytes.set(1, b"not json".to_vec()).await.unwrap();
+
+        assert!(matches!(
+            cache.get(&1).await,
+            Err(Error::DeserializingValue(_))
+        ));
+    }
+}
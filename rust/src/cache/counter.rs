@@ -0,0 +1,71 @@
+use async_trait::async_trait;
+use std::time::Duration;
+
+use crate::cache::Error;
+
+// CounterCache holds integer counters updated atomically.
+#[async_trait]
+pub trait CounterCache<K> {
+    // Adds `delta` to the counter, which starts at 0 when missing, and returns
+    // the new value. The TTL only applies when the counter is created, so a
+    // counter created with a TTL is a fixed window.
+    async fn incr_by(&self, k: &K, delta: i64, ttl: Option<Duration>) -> Result<i64, Error>;
+
+    async fn decr_by(&self, k: &K, delta: i64, ttl: Option<Duration>) -> Result<i64, Error>
+    where
+        K: Sync,
+    {
+        let delta = delta.checked_neg().ok_or(Error::InvalidCounter)?;
+        self.incr_by(k, delta, ttl).await
+    }
+}
+
+// CounterValue is a value type counters can be stored as. Like in Redis,
+// text and bytes hold counters as decimal numbers.
+pub trait CounterValue: Sized {
+    fn to_counter(&self) -> Option<i64>;
+    fn from_counter(n: i64) -> Self;
+}
+
+impl CounterValue for i64 {
+    fn to_counter(&self) -> Option<i64> {
+        Some(*self)
+    }
+
+    fn from_counter(n: i64) -> Self {
+        n
+    }
+}
+
+impl CounterValue for String {
+    fn to_counter(&self) -> Option<i64> {
+        self.parse().ok()
+    }
+
+    fn from_counter(n: i64) -> Self {
+        n.to_string()
+    }
+}
+
+impl CounterValue for Vec<u8> {
+    fn to_counter(&self) -> Option<i64> {
+        std::str::from_utf8(self).ok()?.parse().ok()
+    }
+
+    fn from_counter(n: i64) -> Self {
+        n.to_string().into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_values() {
+        assert_eq!(i64::from_counter(3).to_counter(), Some(3));
+        assert_eq!(String::from_counter(-3), "-3");
+        assert_eq!(Vec::from_counter(12).to_counter(), Some(12));
+        assert!(b"abc".to_vec().to_counter().is_none());
+    }
+}
@@ -1,7 +1,206 @@
+use serde::Serialize;
+use std::error::Error as StdError;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::time::error::Elapsed;
+
+use crate::cache::key::encode_key;
+use crate::errors::{self, Define, Metadata};
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("internal cache error")]
     Internal,
+    #[error("could not serialize key: {0}")]
+    SerializingKey(#[source] serde_json::Error),
+    #[error("could not deserialize key: {0}")]
+    DeserializingKey(#[source] serde_json::Error),
+    #[error("could not serialize value: {0}")]
+    SerializingValue(#[source] Box<dyn std::error::Error + Sync + Send>),
+    #[error("could not deserialize value: {0}")]
+    DeserializingValue(#[source] Box<dyn std::error::Error + Sync + Send>),
+    #[error("cache connection failed: {0}")]
+    Connection(#[source] std::io::Error),
+    #[error("cache storage failed: {0}")]
+    Storage(#[source] std::io::Error),
+    #[error("cache operation timed out after {0:?}")]
+    Timeout(Duration, #[source] Elapsed),
+    #[error("cache protocol error: {0}")]
+    Protocol(String),
+    #[error("cache command failed: {0}")]
+    Command(String),
+    #[error("cache is out of capacity: {0}")]
+    Capacity(String, #[source] Option<ServerError>),
+    #[error("could not load value: {0}")]
+    Loading(#[source] Arc<dyn std::error::Error + Sync + Send>),
+    #[error("version conflict: expected {expected:?}, found {actual:?}")]
+    Conflict {
+        expected: Option<i64>,
+        actual: Option<i64>,
+    },
+    #[error("value is not a counter or the counter would overflow")]
+    InvalidCounter,
+    #[error("watcher missed {0} changes")]
+    Lagged(u64),
+    #[error("could not propagate invalidation: {0}")]
+    Invalidating(#[source] crate::events::Error),
+}
+
+// Error reply sent by a server.
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct ServerError(pub String);
+
+impl Error {
+    // Converts into an `errors::Error` telling which backend and key failed.
+    pub fn into_error<K: Serialize>(self, backend: &str, key: &K) -> errors::Error {
+        let mut metadata = self.metadata().and("backend", backend);
+        if let Ok(key) = encode_key(key) {
+            metadata = metadata.and("key", key);
+        }
+
+        self.convert(metadata)
+    }
+
+    // The source of the error, if any, becomes the cause.
+    fn convert(self, metadata: Metadata) -> errors::Error {
+        let message = self.to_string();
+
+        match self.source().map(cause) {
+            Some(cause) => errors::Error::wrap(self, cause, message, metadata),
+            None => errors::Error::new(self, message, metadata),
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        match self {
+            Error::Timeout(timeout, _) => Metadata::with("timeout_ms", timeout.as_millis() as u64),
+            Error::Conflict { expected, actual } => {
+                Metadata::with("expected", expected).and("actual", actual)
+            }
+            Error::Lagged(missed) => Metadata::with("missed", missed),
+            _ => Metadata::new(),
+        }
+    }
+}
+
+// Codes are stable, so callers can rely on them, unlike on messages.
+impl Define for Error {
+    fn define(&self) -> &str {
+        match self {
+            Error::Internal => "cache_internal",
+            Error::SerializingKey(_)
+            | Error::DeserializingKey(_)
+            | Error::SerializingValue(_)
+            | Error::DeserializingValue(_) => "cache_serialization",
+            Error::Connection(_) | Error::Protocol(_) => "cache_connection",
+            Error::Storage(_) => "cache_storage",
+            Error::Timeout(..) => "cache_timeout",
+            Error::Command(_) => "cache_command",
+            Error::Capacity(..) => "cache_capacity",
+            Error::Loading(_) => "cache_loading",
+            Error::Conflict { .. } => "cache_conflict",
+            Error::InvalidCounter => "cache_invalid_counter",
+            Error::Lagged(_) => "cache_lagged",
+            Error::Invalidating(_) => "cache_invalidation",
+        }
+    }
+}
+
+impl From<Error> for errors::Error {
+    fn from(err: Error) -> Self {
+        let metadata = err.metadata();
+
+        err.convert(metadata)
+    }
+}
+
+// Code of the causes of converted errors, which can be of any type.
+struct Cause;
+
+impl Define for Cause {
+    fn define(&self) -> &str {
+        "cause"
+    }
+}
+
+fn cause(err: &(dyn StdError + 'static)) -> errors::Error {
+    match err.source() {
+        Some(source) => errors::Error::wrap(Cause, cause(source), err.to_string(), None),
+        None => errors::Error::new(Cause, err.to_string(), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    async fn elapsed() -> Elapsed {
+        tokio::time::timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn codes() {
+        let err = Error::SerializingValue("invalid".into());
+        assert_eq!(err.define(), "cache_serialization");
+        assert_eq!(
+            Error::DeserializingKey(serde_json::from_str::<u8>("x").unwrap_err()).define(),
+            "cache_serialization"
+        );
+        assert_eq!(
+            Error::Timeout(Duration::from_secs(1), elapsed().await).define(),
+            "cache_timeout"
+        );
+        assert_eq!(
+            Error::Capacity("OOM".to_string(), None).define(),
+            "cache_capacity"
+        );
+    }
+
+    #[tokio::test]
+    async fn into_structured_error() {
+        let err: errors::Error = Error::Conflict {
+            expected: Some(1),
+            actual: Some(2),
+        }
+        .into();
+        assert_eq!(err.code(), "cache_conflict");
+        assert_eq!(
+            err.message(),
+            "version conflict: expected Some(1), found Some(2)"
+        );
+        assert_eq!(err.metadata().values()["expected"], json!(1));
+        assert_eq!(err.metadata().values()["actual"], json!(2));
+
+        let err = Error::Timeout(Duration::from_millis(250), elapsed().await)
+            .into_error("redis", &("user", 1));
+        assert_eq!(err.code(), "cache_timeout");
+        assert_eq!(err.metadata().values()["backend"], json!("redis"));
+        assert_eq!(err.metadata().values()["key"], json!("[\"user\",1]"));
+        assert_eq!(err.metadata().values()["timeout_ms"], json!(250));
+        assert_eq!(err.cause().unwrap().message(), "deadline has elapsed");
+    }
+
+    #[test]
+    fn causes() {
+        let err: errors::Error = Error::Storage(std::io::Error::other("disk full")).into();
+        assert_eq!(err.code(), "cache_storage");
+        assert_eq!(err.cause().unwrap().code(), "cause");
+        assert_eq!(err.cause().unwrap().message(), "disk full");
+
+        let err: errors::Error = Error::Capacity(
+            "server is out of memory".to_string(),
+            Some(ServerError("OOM command not allowed".to_string())),
+        )
+        .into();
+        assert_eq!(err.cause().unwrap().message(), "OOM command not allowed");
+
+        let err: errors::Error = Error::Capacity("entry too heavy".to_string(), None).into();
+        assert!(err.cause().is_none());
+    }
 }
@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// EvictionPolicy decides which key leaves a bounded cache when it is full. The
+// cache reports every insertion, read and removal so the policy can keep its
+// own bookkeeping.
+pub trait EvictionPolicy<K>: Sync + Send {
+    fn on_insert(&mut self, k: &K);
+    fn on_access(&mut self, k: &K);
+    fn on_remove(&mut self, k: &K);
+
+    // Chooses a victim and stops tracking it.
+    fn evict(&mut self) -> Option<K>;
+}
+
+// Policy
+pub enum Policy<K> {
+    Lru,
+    Lfu,
+    Fifo,
+    Custom(Box<dyn EvictionPolicy<K>>),
+}
+
+impl<K> Policy<K>
+where
+    K: Clone + Eq + Hash + Sync + Send + 'static,
+{
+    pub(crate) fn build(self) -> Box<dyn EvictionPolicy<K>> {
+        match self {
+            Policy::Lru => Box::new(LruPolicy::new()),
+            Policy::Lfu => Box::new(LfuPolicy::new()),
+            Policy::Fifo => Box::new(FifoPolicy::new()),
+            Policy::Custom(policy) => policy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionCause {
+    Capacity,
+    Expired,
+}
+
+// Keys ordered by a priority, the lowest one being the next victim.
+struct PriorityIndex<K, P> {
+    priorities: HashMap<K, P>,
+    order: BTreeMap<P, K>,
+}
+
+impl<K, P> PriorityIndex<K, P>
+where
+    K: Clone + Eq + Hash,
+    P: Clone + Ord,
+{
+    fn new() -> PriorityIndex<K, P> {
+        PriorityIndex {
+            priorities: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, k: &K) -> Option<&P> {
+        self.priorities.get(k)
+    }
+
+    fn update(&mut self, k: &K, priority: P) {
+        if let Some(old) = self.priorities.insert(k.clone(), priority.clone()) {
+            self.order.remove(&old);
+        }
+
+        self.order.insert(priority, k.clone());
+    }
+
+    fn remove(&mut self, k: &K) {
+        if let Some(old) = self.priorities.remove(k) {
+            self.order.remove(&old);
+        }
+    }
+
+    fn pop(&mut self) -> Option<K> {
+        let priority = self.order.keys().next()?.clone();
+        let k = self.order.remove(&priority)?;
+        self.priorities.remove(&k);
+
+        Some(k)
+    }
+}
+
+// LruPolicy evicts the least recently used key.
+pub struct LruPolicy<K> {
+    tick: u64,
+    index: PriorityIndex<K, u64>,
+}
+
+impl<K> LruPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    pub fn new() -> LruPolicy<K> {
+        LruPolicy {
+            tick: 0,
+            index: PriorityIndex::new(),
+        }
+    }
+
+    fn touch(&mut self, k: &K) {
+        self.tick += 1;
+        self.index.update(k, self.tick);
+    }
+}
+
+impl<K> Default for LruPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn default() -> Self {
+        LruPolicy::new()
+    }
+}
+
+impl<K> EvictionPolicy<K> for LruPolicy<K>
+where
+    K: Clone + Eq + Hash + Sync + Send,
+{
+    fn on_insert(&mut self, k: &K) {
+        self.touch(k);
+    }
+
+    fn on_access(&mut self, k: &K) {
+        self.touch(k);
+    }
+
+    fn on_remove(&mut self, k: &K) {
+        self.index.remove(k);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.index.pop()
+    }
+}
+
+// LfuPolicy evicts the least frequently used key. Ties are broken by
+// evicting the least recently used one.
+pub struct LfuPolicy<K> {
+    tick: u64,
+    index: PriorityIndex<K, (u64, u64)>,
+}
+
+impl<K> LfuPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    pub fn new() -> LfuPolicy<K> {
+        LfuPolicy {
+            tick: 0,
+            index: PriorityIndex::new(),
+        }
+    }
+
+    fn touch(&mut self, k: &K) {
+        let frequency = self.index.get(k).map(|(f, _)| *f).unwrap_or(0);
+
+        self.tick += 1;
+        self.index.update(k, (frequency + 1, self.tick));
+    }
+}
+
+impl<K> Default for LfuPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn default() -> Self {
+        LfuPolicy::new()
+    }
+}
+
+impl<K> EvictionPolicy<K> for LfuPolicy<K>
+where
+    K: Clone + Eq + Hash + Sync + Send,
+{
+    fn on_insert(&mut self, k: &K) {
+        self.touch(k);
+    }
+
+    fn on_access(&mut self, k: &K) {
+        self.touch(k);
+    }
+
+    fn on_remove(&mut self, k: &K) {
+        self.index.remove(k);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.index.pop()
+    }
+}
+
+// FifoPolicy evicts the oldest inserted key. Neither reads nor overwrites
+// change its position.
+pub struct FifoPolicy<K> {
+    tick: u64,
+    index: PriorityIndex<K, u64>,
+}
+
+impl<K> FifoPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    pub fn new() -> FifoPolicy<K> {
+        FifoPolicy {
+            tick: 0,
+            index: PriorityIndex::new(),
+        }
+    }
+}
+
+impl<K> Default for FifoPolicy<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn default() -> Self {
+        FifoPolicy::new()
+    }
+}
+
+impl<K> EvictionPolicy<K> for FifoPolicy<K>
+where
+    K: Clone + Eq + Hash + Sync + Send,
+{
+    fn on_insert(&mut self, k: &K) {
+        if self.index.get(k).is_none() {
+            self.tick += 1;
+            self.index.update(k, self.tick);
+        }
+    }
+
+    fn on_access(&mut self, _k: &K) {}
+
+    fn on_remove(&mut self, k: &K) {
+        self.index.remove(k);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.index.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut policy = LruPolicy::new();
+        policy.on_insert(&1);
+        policy.on_insert(&2);
+        policy.on_insert(&3);
+        policy.on_access(&1);
+
+        assert_eq!(policy.evict(), Some(2));
+        assert_eq!(policy.evict(), Some(3));
+        assert_eq!(policy.evict(), Some(1));
+        assert_eq!(policy.evict(), None);
+    }
+
+    #[test]
+    fn lfu() {
+        let mut policy = LfuPolicy::new();
+        policy.on_insert(&1);
+        policy.on_insert(&2);
+        policy.on_insert(&3);
+        policy.on_access(&1);
+        policy.on_access(&1);
+        policy.on_access(&3);
+
+        assert_eq!(policy.evict(), Some(2));
+        assert_eq!(policy.evict(), Some(3));
+
+        policy.on_remove(&1);
+        assert_eq!(policy.evict(), None);
+    }
+
+    #[test]
+    fn fifo() {
+        let mut policy = FifoPolicy::new();
+        policy.on_insert(&1);
+        policy.on_insert(&2);
+        policy.on_access(&1);
+        policy.on_insert(&1);
+
+        assert_eq!(policy.evict(), Some(1));
+        assert_eq!(policy.evict(), Some(2));
+    }
+}
@@ -1,68 +1,1106 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future;
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
 use serde::Serialize;
 use std::cmp::Eq;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::JoinHandle;
 
-use crate::cache::{Cache, Error};
+use crate::cache::key::encode_key;
+use crate::cache::pattern::key_matches;
+use crate::cache::{
+    expiration, AtomicCache, Cache, CacheStats, Change, Clock, CounterCache, CounterValue, Error,
+    EvictionCause, EvictionPolicy, Observer, Observers, Policy, ScanCache, SystemClock,
+    TaggedCache, VersionedCache, WatchCache,
+};
+use crate::models::Version;
+
+type EvictionListener<K, V> = dyn Fn(K, V, EvictionCause) + Sync + Send;
+type Weigher<K, V> = dyn Fn(&K, &V) -> u64 + Sync + Send;
+type Evicted<K, V> = Vec<(K, V, EvictionCause)>;
+
+// Changes kept for watchers that are behind.
+const WATCH_CAPACITY: usize = 1024;
+
+struct Entry<V> {
+    value: V,
+    expires_at: Option<DateTime<Utc>>,
+    tags: Vec<String>,
+    version: i64,
+    weight: u64,
+}
+
+impl<V> Entry<V> {
+    fn new(value: V, expires_at: Option<DateTime<Utc>>) -> Entry<V> {
+        Entry {
+            value,
+            expires_at,
+            tags: Vec::new(),
+            version: 0,
+            weight: 0,
+        }
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    fn version(&self) -> Version {
+        Version::new(self.version).unwrap()
+    }
+}
+
+enum Limit<K, V> {
+    Entries(usize),
+    Weight {
+        max: u64,
+        weigher: Box<Weigher<K, V>>,
+    },
+}
+
+struct Bound<K, V> {
+    limit: Limit<K, V>,
+    policy: Mutex<Box<dyn EvictionPolicy<K>>>,
+}
+
+struct Store<K, V> {
+    items: HashMap<K, Entry<V>>,
+    tags: HashMap<String, HashSet<K>>,
+    bound: Option<Bound<K, V>>,
+    // Sum of the weights of the entries, expired or not.
+    weight: u64,
+    // Earliest expiration of the entries, or earlier if that entry is gone.
+    next_expiry: Option<DateTime<Utc>>,
+    // Last version given to an entry. Versions are never reused, even
+    // across keys, so a deleted and recreated entry gets a new one.
+    version: i64,
+}
+
+impl<K, V> Store<K, V>
+where
+    K: Clone + Eq + Hash,
+{
+    fn new(bound: Option<Bound<K, V>>) -> Store<K, V> {
+        Store {
+            items: HashMap::new(),
+            tags: HashMap::new(),
+            bound,
+            weight: 0,
+            next_expiry: None,
+            version: 0,
+        }
+    }
+
+    fn touch(&self, k: &K) {
+        if let Some(bound) = &self.bound {
+            bound.policy.lock().unwrap().on_access(k);
+        }
+    }
+
+    // Inserts an entry, evicting as many entries as needed to stay within
+    // the limit. The evicted entries are returned, including the new one if
+    // it does not fit even in an empty cache.
+    fn insert(&mut self, k: K, mut entry: Entry<V>, now: DateTime<Utc>) -> Evicted<K, V> {
+        let mut evicted = Vec::new();
+
+        entry.version = self.next_version();
+        entry.weight = self.weigh(&k, &entry.value);
+
+        // Tags belong to the entry, the replaced one loses them. It is still
+        // known to the policy, which may pick it as a victim.
+        self.take(&k);
+
+        // Expired entries count toward the limit until they are reaped, so
+        // they are removed before evicting live ones.
+        if self.is_full(entry.weight) && self.next_expiry.is_some_and(|at| at <= now) {
+            for (k, v) in self.remove_expired(now) {
+                evicted.push((k, v, EvictionCause::Expired));
+            }
+        }
+
+        while self.is_full(entry.weight) {
+            let victim = match self.victim() {
+                Some(victim) => victim,
+                None => break,
+            };
+
+            if victim != k {
+                if let Some(entry) = self.take(&victim) {
+                    evicted.push((victim, entry.value, EvictionCause::Capacity));
+                }
+            }
+        }
+
+        if self.is_full(entry.weight) {
+            if let Some(bound) = &mut self.bound {
+                bound.policy.get_mut().unwrap().on_remove(&k);
+            }
+
+            evicted.push((k, entry.value, EvictionCause::Capacity));
+            return evicted;
+        }
+
+        if let Some(bound) = &mut self.bound {
+            bound.policy.get_mut().unwrap().on_insert(&k);
+        }
+
+        for tag in &entry.tags {
+            self.tags.entry(tag.clone()).or_default().insert(k.clone());
+        }
+        if let Some(expires_at) = entry.expires_at {
+            self.next_expiry = Some(self.next_expiry.map_or(expires_at, |at| at.min(expires_at)));
+        }
+        self.weight += entry.weight;
+        self.items.insert(k, entry);
+
+        evicted
+    }
+
+    fn weigh(&self, k: &K, v: &V) -> u64 {
+        match &self.bound {
+            Some(Bound {
+                limit: Limit::Weight { weigher, .. },
+                ..
+            }) => weigher(k, v),
+            _ => 0,
+        }
+    }
+
+    // Fails for an entry too heavy to fit even in an empty cache, for the
+    // writes that must not be evicted right away.
+    fn check_fits(&self, k: &K, v: &V) -> Result<(), Error> {
+        match &self.bound {
+            Some(Bound {
+                limit: Limit::Weight { max, weigher },
+                ..
+            }) if weigher(k, v) > *max => Err(Error::Capacity(
+                format!(
+                    "entry weighs {}, more than the maximum of {}",
+                    weigher(k, v),
+                    max
+                ),
+                None,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether an entry of the given weight would exceed the limit.
+    fn is_full(&self, weight: u64) -> bool {
+        match &self.bound {
+            Some(Bound {
+                limit: Limit::Entries(capacity),
+                ..
+            }) => self.items.len() >= *capacity,
+            Some(Bound {
+                limit: Limit::Weight { max, .. },
+                ..
+            }) => self.weight.saturating_add(weight) > *max,
+            None => false,
+        }
+    }
+
+    // Next key to evict to make room for a new one.
+    fn victim(&mut self) -> Option<K> {
+        let bound = self.bound.as_mut()?;
+
+        bound.policy.get_mut().unwrap().evict()
+    }
+
+    fn remove(&mut self, k: &K) -> Option<Entry<V>> {
+        let entry = self.take(k)?;
+
+        if let Some(bound) = &mut self.bound {
+            bound.policy.get_mut().unwrap().on_remove(k);
+        }
+
+        Some(entry)
+    }
+
+    // Removes an entry from the items and the tag index, but not from the
+    // eviction policy.
+    fn take(&mut self, k: &K) -> Option<Entry<V>> {
+        let entry = self.items.remove(k)?;
+        self.weight -= entry.weight;
+
+        for tag in &entry.tags {
+            if let Some(keys) = self.tags.get_mut(tag) {
+                keys.remove(k);
+                if keys.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+
+        Some(entry)
+    }
+
+    fn remove_expired(&mut self, now: DateTime<Utc>) -> Vec<(K, V)> {
+        let expired: Vec<K> = self
+            .items
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(k, _)| k.clone())
+            .collect();
+        self.next_expiry = self
+            .items
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .filter_map(|entry| entry.expires_at)
+            .min();
+
+        expired
+            .into_iter()
+            .filter_map(|k| self.remove(&k).map(|entry| (k, entry.value)))
+            .collect()
+    }
+
+    fn next_version(&mut self) -> i64 {
+        self.version += 1;
+        self.version
+    }
+
+    fn live(&self, k: &K, now: DateTime<Utc>) -> Option<&Entry<V>> {
+        self.items.get(k).filter(|entry| !entry.is_expired(now))
+    }
+
+    fn remove_tagged(&mut self, tag: &str) -> Vec<K> {
+        let keys = self.tags.remove(tag).unwrap_or_default();
+
+        keys.into_iter()
+            .filter(|k| self.remove(k).is_some())
+            .collect()
+    }
+}
+
+// Everything but the entries, shared by all the handles to a cache.
+struct Config<K, V> {
+    default_ttl: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    listener: Option<Arc<EvictionListener<K, V>>>,
+    observers: Observers<K>,
+    changes: broadcast::Sender<Change<K, V>>,
+}
+
+impl<K, V> Clone for Config<K, V> {
+    fn clone(&self) -> Self {
+        Config {
+            default_ttl: self.default_ttl,
+            clock: self.clock.clone(),
+            listener: self.listener.clone(),
+            observers: self.observers.clone(),
+            changes: self.changes.clone(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct InMemCache<K, V> {
-    items: Arc<RwLock<HashMap<K, V>>>,
+    store: Arc<RwLock<Store<K, V>>>,
+    config: Config<K, V>,
 }
 
 impl<K, V> InMemCache<K, V>
 where
-    K: Clone,
+    K: Clone + Eq + Hash,
     V: Clone,
 {
     pub fn new() -> InMemCache<K, V> {
+        InMemCache::with_store(Store::new(None))
+    }
+
+    // Bounded cache holding at most `capacity` entries. Once full, every new
+    // key evicts one chosen by the policy. `capacity` must be greater than 0.
+    pub fn with_capacity(capacity: usize, policy: Policy<K>) -> InMemCache<K, V>
+    where
+        K: Sync + Send + 'static,
+    {
+        assert!(capacity > 0, "cache capacity must be greater than 0");
+
+        InMemCache::with_store(Store::new(Some(Bound {
+            limit: Limit::Entries(capacity),
+            policy: Mutex::new(policy.build()),
+        })))
+    }
+
+    // Bounded cache whose entries weigh at most `max_weight` in total, as
+    // measured by `weigher`, for instance in bytes. Entries chosen by the
+    // policy are evicted until a new one fits. An entry heavier than
+    // `max_weight` is evicted right away, except by `compare_and_set`,
+    // `set_if_absent` and counters, which fail with `Error::Capacity`.
+    pub fn with_max_weight<F>(max_weight: u64, policy: Policy<K>, weigher: F) -> InMemCache<K, V>
+    where
+        K: Sync + Send + 'static,
+        F: Fn(&K, &V) -> u64 + Sync + Send + 'static,
+    {
+        InMemCache::with_store(Store::new(Some(Bound {
+            limit: Limit::Weight {
+                max: max_weight,
+                weigher: Box::new(weigher),
+            },
+            policy: Mutex::new(policy.build()),
+        })))
+    }
+
+    fn with_store(store: Store<K, V>) -> InMemCache<K, V> {
         InMemCache {
-            items: Arc::new(RwLock::new(HashMap::new())),
+            store: Arc::new(RwLock::new(store)),
+            config: Config {
+                default_ttl: None,
+                clock: Arc::new(SystemClock),
+                listener: None,
+                observers: Observers::new(),
+                changes: broadcast::channel(WATCH_CAPACITY).0,
+            },
         }
     }
 
+    // TTL applied by `set`. `set_with_ttl` always takes precedence.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> InMemCache<K, V> {
+        self.config.default_ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_clock<C>(mut self, clock: C) -> InMemCache<K, V>
+    where
+        C: Clock + 'static,
+    {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    // Called, outside of any lock, for every entry evicted because the cache
+    // was full or because it expired. Explicit deletes are not reported.
+    pub fn with_eviction_listener<F>(mut self, listener: F) -> InMemCache<K, V>
+    where
+        F: Fn(K, V, EvictionCause) + Sync + Send + 'static,
+    {
+        self.config.listener = Some(Arc::new(listener));
+        self
+    }
+
+    // Notified of every operation, including evictions, after the observers
+    // added before.
+    pub fn with_observer(mut self, observer: Arc<dyn Observer<K>>) -> InMemCache<K, V> {
+        self.config.observers = self.config.observers.with(observer);
+        self
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let now = self.config.clock.now();
+        let store = self.store.read().await;
+
+        let (size, weight) = store
+            .items
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .fold((0, 0), |(size, weight), entry| {
+                (size + 1, weight + entry.weight)
+            });
+        drop(store);
+
+        let mut stats = self.config.observers.stats(size);
+        stats.weighted_size = weight;
+
+        stats
+    }
+
+    async fn live_len(&self) -> usize {
+        let now = self.config.clock.now();
+        let store = self.store.read().await;
+
+        store
+            .items
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .count()
+    }
+
     pub async fn all(&self) -> HashMap<K, V> {
-        let items = self.items.read().await;
-        items.clone()
+        let now = self.config.clock.now();
+        let store = self.store.read().await;
+
+        store
+            .items
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(k, entry)| (k.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    // Removes every expired entry and returns how many were removed.
+    pub async fn purge_expired(&self) -> usize {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+        let expired: Evicted<K, V> = store
+            .remove_expired(now)
+            .into_iter()
+            .map(|(k, v)| (k, v, EvictionCause::Expired))
+            .collect();
+        self.evicted(&expired);
+        drop(store);
+
+        let count = expired.len();
+
+        self.notify(expired);
+
+        count
+    }
+
+    // Spawns a task that purges expired entries every `interval`. The task
+    // stops by itself once every handle to the cache has been dropped.
+    pub fn start_reaper(&self, interval: Duration) -> JoinHandle<()>
+    where
+        K: Sync + Send + 'static,
+        V: Sync + Send + 'static,
+    {
+        let cache = WeakInMemCache::from(self);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                match cache.upgrade() {
+                    Some(cache) => {
+                        cache.purge_expired().await;
+                    }
+                    None => break,
+                }
+            }
+        })
+    }
+
+    // Live entries with their expiration, for the persistence layer.
+    pub(crate) async fn entries(&self) -> Vec<(K, V, Option<DateTime<Utc>>)> {
+        let now = self.config.clock.now();
+        let store = self.store.read().await;
+
+        store
+            .items
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(k, entry)| (k.clone(), entry.value.clone(), entry.expires_at))
+            .collect()
+    }
+
+    // Sets an entry expiring at a point in time, instead of after a TTL.
+    pub(crate) async fn set_expiring(&self, k: K, v: V, expires_at: Option<DateTime<Utc>>) {
+        self.insert(k, Entry::new(v, expires_at)).await;
+    }
+
+    // Deletes the entry only if it is still at `version`, so a newer one is
+    // never lost.
+    pub(crate) async fn delete_if_version(&self, k: &K, version: &Version) -> bool {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        if !matches!(store.live(k, now), Some(entry) if entry.version == version.value()) {
+            return false;
+        }
+
+        store.remove(k);
+        self.changed(|| Change::Deleted { key: k.clone() });
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_delete(k));
+
+        true
+    }
+
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.config.clock.now()
+    }
+
+    // Expiration of an entry set now with `ttl`, or with the default TTL.
+    pub(crate) fn expires_at(&self, ttl: Option<Duration>) -> Option<DateTime<Utc>> {
+        ttl.or(self.config.default_ttl)
+            .and_then(|ttl| expiration(self.config.clock.now(), ttl))
+    }
+
+    // Inserts the entry if the current version is `expected`.
+    async fn compare_and_set_entry(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        entry: Entry<V>,
+    ) -> Result<Version, Error> {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        let actual = store.live(&k, now).map(|entry| entry.version);
+        let expected = expected.map(Version::value);
+        if actual != expected {
+            return Err(Error::Conflict { expected, actual });
+        }
+        store.check_fits(&k, &entry.value)?;
+
+        self.changed(|| Change::Set {
+            key: k.clone(),
+            value: entry.value.clone(),
+        });
+        let evicted = store.insert(k.clone(), entry, now);
+        self.evicted(&evicted);
+        let version = Version::new(store.version).unwrap();
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_set(&k));
+        self.notify(evicted);
+
+        Ok(version)
+    }
+
+    async fn insert(&self, k: K, entry: Entry<V>) {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+        self.changed(|| Change::Set {
+            key: k.clone(),
+            value: entry.value.clone(),
+        });
+        let evicted = store.insert(k.clone(), entry, now);
+        self.evicted(&evicted);
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_set(&k));
+        self.notify(evicted);
+    }
+
+    // Changes are only built when someone is watching. Writes send them while
+    // holding the lock, so they are received in the order they were applied.
+    // Observers and listeners are only called once unlocked.
+    fn changed<F>(&self, change: F)
+    where
+        F: FnOnce() -> Change<K, V>,
+    {
+        if self.config.changes.receiver_count() > 0 {
+            let _ = self.config.changes.send(change());
+        }
+    }
+
+    // Sends the removal of an entry, which may have already expired.
+    fn removed(&self, k: &K, entry: Option<Entry<V>>, now: DateTime<Utc>) {
+        match entry {
+            Some(entry) if entry.is_expired(now) => {
+                self.changed(|| Change::Expired { key: k.clone() })
+            }
+            Some(_) => self.changed(|| Change::Deleted { key: k.clone() }),
+            None => {}
+        }
+    }
+
+    // Sends the removal of evicted entries, while holding the lock.
+    fn evicted(&self, evicted: &[(K, V, EvictionCause)]) {
+        for (k, _, cause) in evicted {
+            self.changed(|| match cause {
+                EvictionCause::Capacity => Change::Deleted { key: k.clone() },
+                EvictionCause::Expired => Change::Expired { key: k.clone() },
+            });
+        }
+    }
+
+    // Reports evicted entries to observers and the listener, once unlocked.
+    fn notify(&self, evicted: Evicted<K, V>) {
+        for (k, v, cause) in evicted {
+            self.config.observers.notify(|o| o.on_evict(&k, cause));
+
+            if let Some(listener) = &self.config.listener {
+                listener(k, v, cause);
+            }
+        }
+    }
+}
+
+impl<K, V> Default for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    fn default() -> Self {
+        InMemCache::new()
+    }
+}
+
+struct WeakInMemCache<K, V> {
+    store: Weak<RwLock<Store<K, V>>>,
+    config: Config<K, V>,
+}
+
+impl<K, V> WeakInMemCache<K, V> {
+    fn from(cache: &InMemCache<K, V>) -> WeakInMemCache<K, V> {
+        WeakInMemCache {
+            store: Arc::downgrade(&cache.store),
+            config: cache.config.clone(),
+        }
+    }
+
+    fn upgrade(&self) -> Option<InMemCache<K, V>> {
+        Some(InMemCache {
+            store: self.store.upgrade()?,
+            config: self.config.clone(),
+        })
     }
 }
 
 #[async_trait]
 impl<K, V> Cache<K, V> for InMemCache<K, V>
 where
-    K: Eq + Hash + Sync + Send + Serialize,
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
     V: Clone + Sync + Send,
 {
     async fn get(&self, k: &K) -> Result<Option<V>, Error> {
-        let items = self.items.read().await;
+        let now = self.config.clock.now();
+
+        {
+            let store = self.store.read().await;
+
+            match store.items.get(k) {
+                Some(entry) if !entry.is_expired(now) => {
+                    store.touch(k);
+                    let v = entry.value.clone();
+                    drop(store);
+
+                    self.config.observers.notify(|o| o.on_hit(k));
+                    return Ok(Some(v));
+                }
+                Some(_) => {}
+                None => {
+                    drop(store);
 
-        Ok(items.get(k).cloned())
+                    self.config.observers.notify(|o| o.on_miss(k));
+                    return Ok(None);
+                }
+            }
+        }
+
+        // Lazy expiration: the entry is stale, so drop it. It is checked
+        // again because it could have been replaced while unlocked.
+        let mut store = self.store.write().await;
+        let expired = match store.items.get(k) {
+            Some(entry) if entry.is_expired(now) => store
+                .remove(k)
+                .map(|entry| (k.clone(), entry.value, EvictionCause::Expired)),
+            _ => None,
+        };
+        let expired: Evicted<K, V> = expired.into_iter().collect();
+        self.evicted(&expired);
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_miss(k));
+        self.notify(expired);
+
+        Ok(None)
     }
 
     async fn set(&self, k: K, v: V) -> Result<(), Error> {
-        let mut items = self.items.write().await;
+        let entry = Entry::new(v, self.expires_at(None));
+        self.insert(k, entry).await;
 
-        items.insert(k, v);
+        Ok(())
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        let entry = Entry::new(v, self.expires_at(Some(ttl)));
+        self.insert(k, entry).await;
 
         Ok(())
     }
 
     async fn delete(&self, k: &K) -> Result<(), Error> {
-        let mut items = self.items.write().await;
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        let entry = store.remove(k);
+        let deleted = matches!(&entry, Some(entry) if !entry.is_expired(now));
+        self.removed(k, entry, now);
+        drop(store);
+
+        if deleted {
+            self.config.observers.notify(|o| o.on_delete(k));
+        }
+
+        Ok(())
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        Ok(self.live_len().await)
+    }
+
+    // Expired entries are skipped but left for the reaper or the next `get`,
+    // so a read lock is enough.
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error> {
+        let now = self.config.clock.now();
+        let store = self.store.read().await;
 
-        items.remove(k);
+        let values: Vec<Option<V>> = ks
+            .iter()
+            .map(|k| match store.items.get(k) {
+                Some(entry) if !entry.is_expired(now) => {
+                    store.touch(k);
+                    Some(entry.value.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        drop(store);
+
+        for (k, v) in ks.iter().zip(values.iter()) {
+            match v {
+                Some(_) => self.config.observers.notify(|o| o.on_hit(k)),
+                None => self.config.observers.notify(|o| o.on_miss(k)),
+            }
+        }
+
+        Ok(values)
+    }
+
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error> {
+        let now = self.config.clock.now();
+        let expires_at = self.expires_at(None);
+        let mut store = self.store.write().await;
+
+        let mut keys = Vec::with_capacity(items.len());
+        let mut evicted = Vec::new();
+        for (k, v) in items {
+            self.changed(|| Change::Set {
+                key: k.clone(),
+                value: v.clone(),
+            });
+            let mut removed = store.insert(k.clone(), Entry::new(v, expires_at), now);
+            self.evicted(&removed);
+            evicted.append(&mut removed);
+            keys.push(k);
+        }
+        drop(store);
+
+        for k in keys.iter() {
+            self.config.observers.notify(|o| o.on_set(k));
+        }
+        self.notify(evicted);
+
+        Ok(())
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        let mut deleted = Vec::new();
+        for k in ks {
+            let entry = store.remove(k);
+            if matches!(&entry, Some(entry) if !entry.is_expired(now)) {
+                deleted.push(k);
+            }
+            self.removed(k, entry, now);
+        }
+        drop(store);
+
+        for k in deleted {
+            self.config.observers.notify(|o| o.on_delete(k));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K, V> TaggedCache<K, V> for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    async fn set_tagged(
+        &self,
+        k: K,
+        v: V,
+        tags: &[&str],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let mut entry = Entry::new(v, self.expires_at(ttl));
+        entry.tags = tags.iter().map(|tag| tag.to_string()).collect();
+
+        self.insert(k, entry).await;
 
         Ok(())
     }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize, Error> {
+        let mut store = self.store.write().await;
+        let deleted = store.remove_tagged(tag);
+        for k in deleted.iter() {
+            self.changed(|| Change::Deleted { key: k.clone() });
+        }
+        drop(store);
+
+        for k in deleted.iter() {
+            self.config.observers.notify(|o| o.on_delete(k));
+        }
+
+        Ok(deleted.len())
+    }
+}
+
+#[async_trait]
+impl<K, V> VersionedCache<K, V> for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    async fn get_versioned(&self, k: &K) -> Result<Option<(V, Version)>, Error> {
+        let now = self.config.clock.now();
+        let store = self.store.read().await;
+
+        let versioned = store.live(k, now).map(|entry| {
+            store.touch(k);
+            (entry.value.clone(), entry.version())
+        });
+        drop(store);
+
+        match &versioned {
+            Some(_) => self.config.observers.notify(|o| o.on_hit(k)),
+            None => self.config.observers.notify(|o| o.on_miss(k)),
+        }
+
+        Ok(versioned)
+    }
+
+    async fn compare_and_set(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: V,
+    ) -> Result<Version, Error> {
+        let entry = Entry::new(v, self.expires_at(None));
+        self.compare_and_set_entry(k, expected, entry).await
+    }
+
+    async fn compare_and_set_with_ttl(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: V,
+        ttl: Duration,
+    ) -> Result<Version, Error> {
+        let entry = Entry::new(v, self.expires_at(Some(ttl)));
+        self.compare_and_set_entry(k, expected, entry).await
+    }
+}
+
+#[async_trait]
+impl<K, V> AtomicCache<K, V> for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + PartialEq + Sync + Send,
+{
+    async fn set_if_absent(&self, k: K, v: V, ttl: Option<Duration>) -> Result<bool, Error> {
+        let now = self.config.clock.now();
+        let entry = Entry::new(v, self.expires_at(ttl));
+        let mut store = self.store.write().await;
+
+        if store.live(&k, now).is_some() {
+            return Ok(false);
+        }
+        store.check_fits(&k, &entry.value)?;
+
+        self.changed(|| Change::Set {
+            key: k.clone(),
+            value: entry.value.clone(),
+        });
+        let evicted = store.insert(k.clone(), entry, now);
+        self.evicted(&evicted);
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_set(&k));
+        self.notify(evicted);
+
+        Ok(true)
+    }
+
+    async fn delete_if_equals(&self, k: &K, v: &V) -> Result<bool, Error> {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        if !matches!(store.live(k, now), Some(entry) if entry.value == *v) {
+            return Ok(false);
+        }
+
+        store.remove(k);
+        self.changed(|| Change::Deleted { key: k.clone() });
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_delete(k));
+
+        Ok(true)
+    }
+
+    // The value is unchanged, so neither is its version.
+    async fn expire_if_equals(&self, k: &K, v: &V, ttl: Duration) -> Result<bool, Error> {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        match store.items.get_mut(k) {
+            Some(entry) if !entry.is_expired(now) && entry.value == *v => {
+                entry.expires_at = expiration(now, ttl);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+// Only the matching keys are cloned, under a single read lock.
+#[async_trait]
+impl<K, V> ScanCache<K> for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    fn scan(&self, pattern: &str) -> BoxStream<'_, Result<K, Error>> {
+        let pattern = pattern.to_string();
+
+        stream::once(async move {
+            let now = self.config.clock.now();
+            let store = self.store.read().await;
+
+            let mut keys = Vec::new();
+            for (k, entry) in store.items.iter() {
+                if !entry.is_expired(now) && key_matches(&pattern, &encode_key(k)?) {
+                    keys.push(k.clone());
+                }
+            }
+
+            Ok(stream::iter(keys.into_iter().map(Ok)))
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<usize, Error> {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        let mut keys = Vec::new();
+        for (k, entry) in store.items.iter() {
+            if !entry.is_expired(now) && key_matches(pattern, &encode_key(k)?) {
+                keys.push(k.clone());
+            }
+        }
+
+        for k in keys.iter() {
+            store.remove(k);
+            self.changed(|| Change::Deleted { key: k.clone() });
+        }
+        drop(store);
+
+        for k in keys.iter() {
+            self.config.observers.notify(|o| o.on_delete(k));
+        }
+
+        Ok(keys.len())
+    }
+}
+
+impl<K, V> WatchCache<K, V> for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + 'static,
+    V: Clone + Sync + Send + 'static,
+{
+    fn watch(&self, k: &K) -> BoxStream<'static, Result<Change<K, V>, Error>> {
+        let k = k.clone();
+
+        changes(self.config.changes.subscribe())
+            .try_filter(move |change| future::ready(change.key() == &k))
+            .boxed()
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> BoxStream<'static, Result<Change<K, V>, Error>> {
+        let prefix = prefix.to_string();
+
+        changes(self.config.changes.subscribe())
+            .try_filter(move |change| {
+                future::ready(
+                    encode_key(change.key()).is_ok_and(|key| key.starts_with(prefix.as_str())),
+                )
+            })
+            .boxed()
+    }
+}
+
+// The stream ends once every handle to the cache has been dropped.
+fn changes<K, V>(
+    receiver: broadcast::Receiver<Change<K, V>>,
+) -> impl Stream<Item = Result<Change<K, V>, Error>>
+where
+    K: Clone,
+    V: Clone,
+{
+    stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(change) => Some((Ok(change), receiver)),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                Some((Err(Error::Lagged(missed)), receiver))
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    })
+}
+
+#[async_trait]
+impl<K, V> CounterCache<K> for InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: CounterValue + Clone + Sync + Send,
+{
+    async fn incr_by(&self, k: &K, delta: i64, ttl: Option<Duration>) -> Result<i64, Error> {
+        let now = self.config.clock.now();
+        let mut store = self.store.write().await;
+
+        // The counter is written like any other entry, evicting others if it
+        // grew heavier. It keeps its expiration and tags.
+        let (n, entry) = match store.live(k, now) {
+            Some(current) => {
+                let n = current
+                    .value
+                    .to_counter()
+                    .and_then(|n| n.checked_add(delta))
+                    .ok_or(Error::InvalidCounter)?;
+                let mut entry = Entry::new(V::from_counter(n), current.expires_at);
+                entry.tags = current.tags.clone();
+                (n, entry)
+            }
+            None => (
+                delta,
+                Entry::new(V::from_counter(delta), self.expires_at(ttl)),
+            ),
+        };
+        store.check_fits(k, &entry.value)?;
+
+        self.changed(|| Change::Set {
+            key: k.clone(),
+            value: V::from_counter(n),
+        });
+        let evicted = store.insert(k.clone(), entry, now);
+        self.evicted(&evicted);
+        drop(store);
+
+        self.config.observers.notify(|o| o.on_set(k));
+        self.notify(evicted);
+
+        Ok(n)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::cache::ManualClock;
+
     #[tokio::test]
     async fn set_get_and_delete() {
         let cache = InMemCache::new();
@@ -75,14 +1113,14 @@ mod tests {
         assert!(res.unwrap().is_none());
 
         let res = cache.set(key1.clone(), value1).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
 
         let res = cache.get(&key1).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
         assert_eq!(res.unwrap().unwrap(), value1);
 
         let res = cache.delete(&key1).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
 
         let res = cache.get(&key1).await;
         assert!(res.unwrap().is_none());
@@ -105,11 +1143,768 @@ mod tests {
         let (_, _) = tokio::join!(t1, t2);
 
         let res = cache.get(&1).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
         assert_eq!(res.unwrap().unwrap(), 128);
 
         let res = cache.get(&2).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
         assert_eq!(res.unwrap().unwrap(), 256);
     }
+
+    #[tokio::test]
+    async fn set_with_ttl_expires_lazily() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        cache
+            .set_with_ttl("key".to_string(), 1, Duration::from_secs(10))
+            .await
+            .unwrap();
+        cache.set("forever".to_string(), 2).await.unwrap();
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(cache.get(&"key".to_string()).await.unwrap(), Some(1));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&"key".to_string()).await.unwrap().is_none());
+        assert_eq!(cache.get(&"forever".to_string()).await.unwrap(), Some(2));
+
+        // The expired entry was removed on read.
+        assert_eq!(cache.store.read().await.items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn default_ttl() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new()
+            .with_default_ttl(Duration::from_secs(5))
+            .with_clock(clock.clone());
+
+        cache.set(1, "default").await.unwrap();
+        cache
+            .set_with_ttl(2, "explicit", Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(5));
+        assert!(cache.get(&1).await.unwrap().is_none());
+        assert_eq!(cache.get(&2).await.unwrap(), Some("explicit"));
+        assert_eq!(cache.all().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn purge_expired() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        cache
+            .set_with_ttl(1, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl(2, 2, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl(3, 3, Duration::from_secs(3))
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.purge_expired().await, 2);
+        assert_eq!(cache.store.read().await.items.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reaper() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        cache
+            .set_with_ttl(1, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache.set(2, 2).await.unwrap();
+
+        let reaper = cache.start_reaper(Duration::from_secs(30));
+
+        clock.advance(Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(cache.store.read().await.items.len(), 1);
+
+        drop(cache);
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert!(reaper.is_finished());
+    }
+
+    #[tokio::test]
+    async fn bounded_lru() {
+        let cache = InMemCache::with_capacity(2, Policy::Lru);
+
+        cache.set(1, "one").await.unwrap();
+        cache.set(2, "two").await.unwrap();
+        cache.get(&1).await.unwrap();
+        cache.set(3, "three").await.unwrap();
+
+        let all = cache.all().await;
+        assert_eq!(all.len(), 2);
+        assert!(all.contains_key(&1));
+        assert!(all.contains_key(&3));
+
+        // Overwriting an existing key does not evict anything.
+        cache.set(3, "THREE").await.unwrap();
+        assert_eq!(cache.all().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn bounded_lfu_and_fifo() {
+        let lfu = InMemCache::with_capacity(2, Policy::Lfu);
+        let fifo = InMemCache::with_capacity(2, Policy::Fifo);
+
+        for cache in [&lfu, &fifo] {
+            cache.set(1, 1).await.unwrap();
+            cache.set(2, 2).await.unwrap();
+            cache.get(&2).await.unwrap();
+            cache.get(&2).await.unwrap();
+            cache.get(&1).await.unwrap();
+            cache.set(3, 3).await.unwrap();
+        }
+
+        assert!(lfu.get(&1).await.unwrap().is_none());
+        assert!(fifo.get(&1).await.unwrap().is_none());
+        assert_eq!(lfu.get(&2).await.unwrap(), Some(2));
+        assert_eq!(fifo.get(&2).await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn custom_policy() {
+        // Always evicts the greatest key.
+        struct Greatest(Vec<i32>);
+
+        impl EvictionPolicy<i32> for Greatest {
+            fn on_insert(&mut self, k: &i32) {
+                self.0.push(*k);
+                self.0.sort();
+            }
+
+            fn on_access(&mut self, _k: &i32) {}
+
+            fn on_remove(&mut self, k: &i32) {
+                self.0.retain(|i| i != k);
+            }
+
+            fn evict(&mut self) -> Option<i32> {
+                self.0.pop()
+            }
+        }
+
+        let cache = InMemCache::with_capacity(2, Policy::Custom(Box::new(Greatest(Vec::new()))));
+        cache.set(5, ()).await.unwrap();
+        cache.set(1, ()).await.unwrap();
+        cache.set(3, ()).await.unwrap();
+
+        assert!(cache.get(&5).await.unwrap().is_none());
+        assert_eq!(cache.all().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn eviction_listener() {
+        let clock = ManualClock::default();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let listened = evicted.clone();
+        let cache = InMemCache::with_capacity(2, Policy::Fifo)
+            .with_clock(clock.clone())
+            .with_eviction_listener(move |k, v, cause| {
+                listened.lock().unwrap().push((k, v, cause));
+            });
+
+        cache
+            .set_with_ttl(1, 10, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache.set(2, 20).await.unwrap();
+        cache.set(3, 30).await.unwrap();
+        cache
+            .set_with_ttl(4, 40, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache.delete(&4).await.unwrap();
+        cache
+            .set_with_ttl(5, 50, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&5).await.unwrap().is_none());
+
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![
+                (1, 10, EvictionCause::Capacity),
+                (2, 20, EvictionCause::Capacity),
+                (5, 50, EvictionCause::Expired),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_entries_evicted_first() {
+        let clock = ManualClock::default();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let listened = evicted.clone();
+        let cache = InMemCache::with_capacity(2, Policy::Fifo)
+            .with_clock(clock.clone())
+            .with_eviction_listener(move |k, v, cause| {
+                listened.lock().unwrap().push((k, v, cause));
+            });
+
+        cache.set(1, 10).await.unwrap();
+        cache
+            .set_with_ttl(2, 20, Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        // The oldest entry is live, so the expired one makes room.
+        cache.set(3, 30).await.unwrap();
+        assert_eq!(cache.get(&1).await.unwrap(), Some(10));
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![(2, 20, EvictionCause::Expired)]
+        );
+
+        cache.set(4, 40).await.unwrap();
+        assert!(cache.get(&1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn deletes_of_missing_keys_not_counted() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        cache.set(1, 1).await.unwrap();
+        cache
+            .set_with_ttl(2, 2, Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        cache.delete(&1).await.unwrap();
+        cache.delete(&1).await.unwrap();
+        cache.delete(&2).await.unwrap();
+        cache.delete_many(&[1, 2, 3]).await.unwrap();
+        assert_eq!(cache.stats().await.deletes, 1);
+    }
+
+    #[tokio::test]
+    async fn batch_operations() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        cache
+            .set_many(vec![(1, "one"), (2, "two"), (3, "three")])
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl(4, "four", Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        let values = cache.get_many(&[1, 2, 4, 5]).await.unwrap();
+        assert_eq!(values, vec![Some("one"), Some("two"), None, None]);
+
+        cache.delete_many(&[1, 3]).await.unwrap();
+        let values = cache.get_many(&[1, 2, 3]).await.unwrap();
+        assert_eq!(values, vec![None, Some("two"), None]);
+    }
+
+    #[tokio::test]
+    async fn bounded_set_many() {
+        let cache = InMemCache::with_capacity(2, Policy::Fifo);
+
+        cache.set_many(vec![(1, 1), (2, 2), (3, 3)]).await.unwrap();
+
+        assert_eq!(
+            cache.get_many(&[1, 2, 3]).await.unwrap(),
+            vec![None, Some(2), Some(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::with_capacity(2, Policy::Lru).with_clock(clock.clone());
+
+        cache.set(1, 1).await.unwrap();
+        cache.set(2, 2).await.unwrap();
+        cache.get(&1).await.unwrap();
+        cache.set(3, 3).await.unwrap();
+        cache.get(&2).await.unwrap();
+        cache.get_many(&[1, 3]).await.unwrap();
+        cache.delete(&1).await.unwrap();
+        cache
+            .set_with_ttl(4, 4, Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        cache.get(&4).await.unwrap();
+
+        assert_eq!(
+            cache.stats().await,
+            CacheStats {
+                hits: 3,
+                misses: 2,
+                sets: 4,
+                deletes: 1,
+                evictions: 2,
+                size: 1,
+                weighted_size: 0,
+            }
+        );
+        assert_eq!(cache.len().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn observer() {
+        #[derive(Default)]
+        struct Evictions(Mutex<Vec<(i32, EvictionCause)>>);
+
+        impl Observer<i32> for Evictions {
+            fn on_evict(&self, k: &i32, cause: EvictionCause) {
+                self.0.lock().unwrap().push((*k, cause));
+            }
+        }
+
+        let evictions = Arc::new(Evictions::default());
+        let cache = InMemCache::with_capacity(1, Policy::Lru).with_observer(evictions.clone());
+
+        cache.set(1, 1).await.unwrap();
+        cache.set(2, 2).await.unwrap();
+
+        assert_eq!(
+            *evictions.0.lock().unwrap(),
+            vec![(1, EvictionCause::Capacity)]
+        );
+        assert_eq!(cache.stats().await.evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn tags() {
+        let cache = InMemCache::new();
+
+        cache
+            .set_tagged("user:1", 1, &["user#1"], None)
+            .await
+            .unwrap();
+        cache
+            .set_tagged("user:1:posts", 2, &["user#1", "posts"], None)
+            .await
+            .unwrap();
+        cache
+            .set_tagged("user:2", 3, &["user#2"], None)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.invalidate_tag("user#1").await.unwrap(), 2);
+        assert!(cache.get(&"user:1").await.unwrap().is_none());
+        assert!(cache.get(&"user:1:posts").await.unwrap().is_none());
+        assert_eq!(cache.get(&"user:2").await.unwrap(), Some(3));
+
+        // The other tags of the deleted entries are cleaned up too.
+        assert_eq!(cache.invalidate_tag("posts").await.unwrap(), 0);
+        assert!(cache.store.read().await.tags.len() == 1);
+    }
+
+    #[tokio::test]
+    async fn tags_of_replaced_and_evicted_entries() {
+        let cache = InMemCache::with_capacity(2, Policy::Fifo);
+
+        cache.set_tagged(1, 1, &["a"], None).await.unwrap();
+        cache.set(1, 10).await.unwrap();
+        assert_eq!(cache.invalidate_tag("a").await.unwrap(), 0);
+        assert_eq!(cache.get(&1).await.unwrap(), Some(10));
+
+        cache.set_tagged(2, 2, &["b"], None).await.unwrap();
+        cache.set_tagged(3, 3, &["b"], None).await.unwrap();
+        assert!(cache.get(&1).await.unwrap().is_none());
+        assert_eq!(cache.invalidate_tag("b").await.unwrap(), 2);
+        assert!(cache.store.read().await.tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compare_and_set() {
+        let cache = InMemCache::new();
+
+        let v1 = cache.compare_and_set("key", None, 1).await.unwrap();
+        let (value, version) = cache.get_versioned(&"key").await.unwrap().unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(version, v1);
+
+        // Another worker updates the entry first.
+        let v2 = cache.compare_and_set("key", Some(&v1), 2).await.unwrap();
+        assert!(v2.value() > v1.value());
+
+        match cache.compare_and_set("key", Some(&v1), 3).await {
+            Err(Error::Conflict { expected, actual }) => {
+                assert_eq!(expected, Some(v1.value()));
+                assert_eq!(actual, Some(v2.value()));
+            }
+            _ => panic!("expected a conflict"),
+        }
+        assert!(matches!(
+            cache.compare_and_set("key", None, 3).await,
+            Err(Error::Conflict { .. })
+        ));
+        assert_eq!(cache.get(&"key").await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn versions_are_never_reused() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        let v1 = cache.compare_and_set(1, None, "a").await.unwrap();
+        cache.delete(&1).await.unwrap();
+        cache.set(1, "b").await.unwrap();
+
+        assert!(matches!(
+            cache.compare_and_set(1, Some(&v1), "c").await,
+            Err(Error::Conflict { .. })
+        ));
+
+        // Expired entries count as missing.
+        cache
+            .set_with_ttl(2, "a", Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get_versioned(&2).await.unwrap().is_none());
+        assert!(cache.compare_and_set(2, None, "b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_compare_and_set() {
+        let cache = InMemCache::new();
+        cache.set("counter", 0).await.unwrap();
+
+        let mut tasks = Vec::new();
+        for _ in 0..10 {
+            let cache = cache.clone();
+
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    let (value, version) = cache.get_versioned(&"counter").await.unwrap().unwrap();
+                    tokio::task::yield_now().await;
+
+                    match cache
+                        .compare_and_set("counter", Some(&version), value + 1)
+                        .await
+                    {
+                        Ok(_) => break,
+                        Err(Error::Conflict { .. }) => continue,
+                        Err(err) => panic!("{}", err),
+                    }
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(cache.get(&"counter").await.unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        let clock = ManualClock::default();
+        let cache: InMemCache<&str, i64> = InMemCache::new().with_clock(clock.clone());
+
+        let ttl = Some(Duration::from_secs(60));
+        assert_eq!(cache.incr_by(&"hits", 5, ttl).await.unwrap(), 5);
+        assert_eq!(cache.incr_by(&"hits", 2, None).await.unwrap(), 7);
+        assert_eq!(cache.decr_by(&"hits", 10, None).await.unwrap(), -3);
+
+        // The TTL of the first increment is kept.
+        clock.advance(Duration::from_secs(60));
+        assert!(cache.get(&"hits").await.unwrap().is_none());
+        assert_eq!(cache.incr_by(&"hits", 1, None).await.unwrap(), 1);
+
+        cache.set("max", i64::MAX).await.unwrap();
+        assert!(matches!(
+            cache.incr_by(&"max", 1, None).await,
+            Err(Error::InvalidCounter)
+        ));
+    }
+
+    #[tokio::test]
+    async fn text_counters() {
+        let cache = InMemCache::new();
+
+        cache.set(1, "not a number".to_string()).await.unwrap();
+        assert!(matches!(
+            cache.incr_by(&1, 1, None).await,
+            Err(Error::InvalidCounter)
+        ));
+
+        assert_eq!(cache.incr_by(&2, 10, None).await.unwrap(), 10);
+        assert_eq!(cache.get(&2).await.unwrap(), Some("10".to_string()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_counters() {
+        let cache: InMemCache<&str, i64> = InMemCache::new();
+
+        let mut tasks = Vec::new();
+        for _ in 0..16 {
+            let cache = cache.clone();
+
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..100 {
+                    cache.incr_by(&"counter", 1, None).await.unwrap();
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(cache.get(&"counter").await.unwrap(), Some(1600));
+    }
+
+    #[tokio::test]
+    async fn scan_and_delete_matching() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        cache.set("user.1".to_string(), 1).await.unwrap();
+        cache.set("user.2".to_string(), 2).await.unwrap();
+        cache.set("user.1.posts".to_string(), 3).await.unwrap();
+        cache.set("post.1".to_string(), 4).await.unwrap();
+        cache
+            .set_with_ttl("user.3".to_string(), 5, Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        let mut keys: Vec<String> = cache.scan("user.*").try_collect().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["user.1", "user.2"]);
+
+        let keys: Vec<String> = cache.scan("*.1.*").try_collect().await.unwrap();
+        assert_eq!(keys, vec!["user.1.posts"]);
+
+        assert_eq!(cache.delete_matching("user.*").await.unwrap(), 2);
+        assert!(cache.get(&"user.1".to_string()).await.unwrap().is_none());
+        assert_eq!(cache.len().await.unwrap(), 2);
+        assert_eq!(cache.stats().await.deletes, 2);
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        let mut watcher = cache.watch(&"config.a".to_string());
+        let prefix_watcher = cache.watch_prefix("config.");
+
+        cache.set("config.a".to_string(), 1).await.unwrap();
+        cache.set("other".to_string(), 2).await.unwrap();
+        cache
+            .set_with_ttl("config.b".to_string(), 3, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache.delete(&"config.a".to_string()).await.unwrap();
+        cache.delete(&"config.a".to_string()).await.unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&"config.b".to_string()).await.unwrap().is_none());
+
+        assert_eq!(
+            watcher.next().await.unwrap().unwrap(),
+            Change::Set {
+                key: "config.a".to_string(),
+                value: 1
+            }
+        );
+        assert_eq!(
+            watcher.next().await.unwrap().unwrap(),
+            Change::Deleted {
+                key: "config.a".to_string()
+            }
+        );
+
+        let changes: Vec<Change<String, i32>> = prefix_watcher.take(4).try_collect().await.unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                Change::Set {
+                    key: "config.a".to_string(),
+                    value: 1
+                },
+                Change::Set {
+                    key: "config.b".to_string(),
+                    value: 3
+                },
+                Change::Deleted {
+                    key: "config.a".to_string()
+                },
+                Change::Expired {
+                    key: "config.b".to_string()
+                },
+            ]
+        );
+
+        // Streams end when the cache is dropped.
+        drop(cache);
+        assert!(watcher.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn watch_lagging() {
+        let cache = InMemCache::new();
+        let mut watcher = cache.watch(&1);
+
+        for i in 0..WATCH_CAPACITY + 10 {
+            cache.set(1, i).await.unwrap();
+        }
+
+        assert!(matches!(watcher.next().await, Some(Err(Error::Lagged(10)))));
+        assert_eq!(
+            watcher.next().await.unwrap().unwrap(),
+            Change::Set { key: 1, value: 10 }
+        );
+    }
+
+    #[tokio::test]
+    async fn atomic_operations() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+
+        let ttl = Some(Duration::from_secs(10));
+        assert!(cache.set_if_absent("lock", "a", ttl).await.unwrap());
+        assert!(!cache.set_if_absent("lock", "b", ttl).await.unwrap());
+
+        assert!(!cache.delete_if_equals(&"lock", &"b").await.unwrap());
+        assert!(!cache
+            .expire_if_equals(&"lock", &"b", Duration::from_secs(20))
+            .await
+            .unwrap());
+        assert!(cache
+            .expire_if_equals(&"lock", &"a", Duration::from_secs(20))
+            .await
+            .unwrap());
+
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(cache.get(&"lock").await.unwrap(), Some("a"));
+        assert!(cache.delete_if_equals(&"lock", &"a").await.unwrap());
+        assert!(cache.get(&"lock").await.unwrap().is_none());
+
+        // Expired entries are absent.
+        assert!(cache.set_if_absent("lock", "c", ttl).await.unwrap());
+        clock.advance(Duration::from_secs(10));
+        assert!(!cache.delete_if_equals(&"lock", &"c").await.unwrap());
+        assert!(cache.set_if_absent("lock", "d", None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bounded_by_weight() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let cache = InMemCache::with_max_weight(10, Policy::Lru, |_k, v: &String| v.len() as u64)
+            .with_eviction_listener({
+                let evicted = evicted.clone();
+                move |k, _v, _cause| evicted.lock().unwrap().push(k)
+            });
+
+        cache.set(1, "aaaa".to_string()).await.unwrap();
+        cache.set(2, "bbbb".to_string()).await.unwrap();
+        cache.get(&1).await.unwrap();
+        assert_eq!(cache.stats().await.weighted_size, 8);
+
+        // 2 is the least recently used, and evicting it is enough.
+        cache.set(3, "ccc".to_string()).await.unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![2]);
+        assert_eq!(cache.stats().await.weighted_size, 7);
+
+        // Replacing an entry only counts its new weight.
+        cache.set(1, "aaaaaaa".to_string()).await.unwrap();
+        assert_eq!(cache.stats().await.weighted_size, 10);
+        assert_eq!(cache.len().await.unwrap(), 2);
+
+        cache.set(4, "dddddddd".to_string()).await.unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![2, 3, 1]);
+        assert_eq!(cache.stats().await.weighted_size, 8);
+
+        // Too heavy to ever fit.
+        cache.set(5, "e".repeat(11)).await.unwrap();
+        assert!(cache.get(&5).await.unwrap().is_none());
+        assert_eq!(*evicted.lock().unwrap(), vec![2, 3, 1, 4, 5]);
+        assert_eq!(cache.stats().await.weighted_size, 0);
+
+        cache.set(6, "ff".to_string()).await.unwrap();
+        cache.delete(&6).await.unwrap();
+        assert_eq!(cache.stats().await.weighted_size, 0);
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_bounded_by_weight() {
+        let cache = InMemCache::with_max_weight(10, Policy::Lru, |_k, v: &String| v.len() as u64);
+
+        // Rejected instead of being evicted right away.
+        let v1 = cache
+            .compare_and_set(1, None, "a".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            cache.compare_and_set(1, Some(&v1), "a".repeat(11)).await,
+            Err(Error::Capacity(..))
+        ));
+        assert_eq!(cache.get(&1).await.unwrap(), Some("a".to_string()));
+        assert!(matches!(
+            cache.set_if_absent(2, "b".repeat(11), None).await,
+            Err(Error::Capacity(..))
+        ));
+        assert!(cache.get(&2).await.unwrap().is_none());
+
+        // Counters growing heavier evict other entries.
+        cache.set(3, "ccccccc".to_string()).await.unwrap();
+        cache.incr_by(&4, 99, None).await.unwrap();
+        assert_eq!(cache.stats().await.weighted_size, 10);
+        assert_eq!(cache.incr_by(&4, 1, None).await.unwrap(), 100);
+        assert!(cache.stats().await.weighted_size <= 10);
+        assert!(cache.get(&1).await.unwrap().is_none());
+        assert_eq!(cache.get(&4).await.unwrap(), Some("100".to_string()));
+
+        cache.set(5, "9".repeat(10)).await.unwrap();
+        assert!(matches!(
+            cache.incr_by(&5, 1, None).await,
+            Err(Error::Capacity(..))
+        ));
+        assert_eq!(cache.get(&5).await.unwrap(), Some("9".repeat(10)));
+    }
+
+    #[tokio::test]
+    async fn weight_of_expired_entries() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::with_max_weight(10, Policy::Fifo, |_k, v: &Vec<u8>| v.len() as u64)
+            .with_clock(clock.clone());
+
+        cache
+            .set_with_ttl(1, vec![0; 6], Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache.set(2, vec![0; 2]).await.unwrap();
+        clock.advance(Duration::from_secs(1));
+
+        // Expired entries are not counted, but use the budget until removed.
+        assert_eq!(cache.stats().await.weighted_size, 2);
+        cache.purge_expired().await;
+        cache.set(3, vec![0; 8]).await.unwrap();
+        assert_eq!(cache.stats().await.weighted_size, 10);
+        assert_eq!(cache.len().await.unwrap(), 2);
+    }
 }
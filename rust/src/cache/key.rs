@@ -0,0 +1,60 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cache::Error;
+
+// Keys are serialized as JSON, except strings which are kept as they are so
+// they stay readable in remote backends.
+pub(crate) fn encode_key<K>(k: &K) -> Result<String, Error>
+where
+    K: Serialize,
+{
+    match serde_json::to_value(k).map_err(Error::SerializingKey)? {
+        Value::String(s) => Ok(s),
+        value => Ok(value.to_string()),
+    }
+}
+
+// Reverses `encode_key`. The key is first read as a string, then as JSON.
+pub(crate) fn decode_key<K>(key: &str) -> Result<K, Error>
+where
+    K: DeserializeOwned,
+{
+    if let Ok(k) = serde_json::from_value(Value::String(key.to_string())) {
+        return Ok(k);
+    }
+
+    serde_json::from_str(key).map_err(Error::DeserializingKey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Composite {
+        id: u32,
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_key(&"user:1").unwrap(), "user:1");
+        assert_eq!(encode_key(&42).unwrap(), "42");
+        assert_eq!(encode_key(&Composite { id: 1 }).unwrap(), r#"{"id":1}"#);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(decode_key::<String>("user:1").unwrap(), "user:1");
+        assert_eq!(decode_key::<String>("42").unwrap(), "42");
+        assert_eq!(decode_key::<i32>("42").unwrap(), 42);
+        assert_eq!(
+            decode_key::<Composite>(r#"{"id":1}"#).unwrap(),
+            Composite { id: 1 }
+        );
+        assert!(decode_key::<i32>("user:1").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+use crate::cache::{Cache, Error, InMemCache, Policy};
+
+type LoadError = Arc<dyn StdError + Sync + Send>;
+type Load<V> = Arc<OnceCell<Result<Option<V>, LoadError>>>;
+
+// LoadingCache wraps a cache to read through it: on a miss the value is
+// loaded and stored. Concurrent misses for the same key share a single call
+// to the loader.
+#[derive(Clone)]
+pub struct LoadingCache<K, V, C> {
+    cache: C,
+    in_flight: Arc<Mutex<HashMap<K, Load<V>>>>,
+    misses: Option<InMemCache<K, ()>>,
+}
+
+impl<K, V, C> LoadingCache<K, V, C>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+    C: Cache<K, V> + Sync,
+{
+    pub fn new(cache: C) -> LoadingCache<K, V, C> {
+        LoadingCache {
+            cache,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            misses: None,
+        }
+    }
+
+    // Remembers for `ttl` the keys the loader could not find, so they are not
+    // loaded again on every call. At most `capacity` of them are kept, the
+    // least recently used being forgotten first.
+    pub fn with_negative_caching(mut self, ttl: Duration, capacity: usize) -> LoadingCache<K, V, C>
+    where
+        K: 'static,
+    {
+        self.misses = Some(InMemCache::with_capacity(capacity, Policy::Lru).with_default_ttl(ttl));
+        self
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.cache
+    }
+
+    // Returns the cached value or loads it. The loader returns `None` when the
+    // value does not exist, and its failures are returned as
+    // `Error::Loading`. Failing to store a loaded value does not fail the
+    // load.
+    pub async fn get_or_load<F, Fut, E>(&self, k: K, loader: F) -> Result<Option<V>, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>, E>>,
+        E: StdError + Sync + Send + 'static,
+    {
+        if let Some(v) = self.cache.get(&k).await? {
+            return Ok(Some(v));
+        }
+
+        if let Some(misses) = &self.misses {
+            if misses.get(&k).await?.is_some() {
+                return Ok(None);
+            }
+        }
+
+        let load = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(k.clone())
+            .or_insert_with(|| Arc::new(OnceCell::new()))
+            .clone();
+
+        let res = load
+            .get_or_init(|| async {
+                let res = loader().await.map_err(|err| Arc::new(err) as LoadError);
+
+                match &res {
+                    Ok(Some(v)) => {
+                        let _ = self.cache.set(k.clone(), v.clone()).await;
+                    }
+                    Ok(None) => {
+                        if let Some(misses) = &self.misses {
+                            let _ = misses.set(k.clone(), ()).await;
+                        }
+                    }
+                    Err(_) => {}
+                }
+
+                res
+            })
+            .await;
+
+        // Once loaded, later misses must load again.
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if matches!(in_flight.get(&k), Some(current) if Arc::ptr_eq(current, &load)) {
+                in_flight.remove(&k);
+            }
+        }
+
+        match res {
+            Ok(v) => Ok(v.clone()),
+            Err(err) => Err(Error::Loading(err.clone())),
+        }
+    }
+
+    async fn forget_miss(&self, k: &K) -> Result<(), Error> {
+        match &self.misses {
+            Some(misses) => misses.delete(k).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V, C> Cache<K, V> for LoadingCache<K, V, C>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+    C: Cache<K, V> + Sync + Send,
+{
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        self.cache.get(k).await
+    }
+
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        self.forget_miss(&k).await?;
+        self.cache.set(k, v).await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        self.forget_miss(&k).await?;
+        self.cache.set_with_ttl(k, v, ttl).await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.cache.delete(k).await
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        self.cache.len().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    #[error("database is down")]
+    struct DatabaseError;
+
+    #[tokio::test(start_paused = true)]
+    async fn single_flight() {
+        let cache = LoadingCache::new(InMemCache::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..10 {
+            let cache = cache.clone();
+            let calls = calls.clone();
+
+            tasks.push(tokio::spawn(async move {
+                cache
+                    .get_or_load("key".to_string(), || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        Ok::<_, DatabaseError>(Some(42))
+                    })
+                    .await
+            }));
+        }
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), Some(42));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get(&"key".to_string()).await.unwrap(), Some(42));
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn loader_errors() {
+        let cache: LoadingCache<_, i32, _> = LoadingCache::new(InMemCache::new());
+
+        let res = cache.get_or_load(1, || async { Err(DatabaseError) }).await;
+        match res {
+            Err(Error::Loading(err)) => assert!(err.downcast_ref::<DatabaseError>().is_some()),
+            _ => panic!("expected a loading error"),
+        }
+
+        // Failures are not cached.
+        let res = cache
+            .get_or_load(1, || async { Ok::<_, DatabaseError>(Some(1)) })
+            .await;
+        assert_eq!(res.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn negative_caching() {
+        let cache =
+            LoadingCache::new(InMemCache::new()).with_negative_caching(Duration::from_secs(60), 2);
+        let calls = AtomicUsize::new(0);
+
+        for _ in 0..3 {
+            let res = cache
+                .get_or_load(1, || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, DatabaseError>(None)
+                })
+                .await;
+            assert!(res.unwrap().is_none());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Setting the key forgets the miss.
+        cache.set(1, "one").await.unwrap();
+        cache.delete(&1).await.unwrap();
+
+        let res = cache
+            .get_or_load(1, || async { Ok::<_, DatabaseError>(Some("loaded")) })
+            .await;
+        assert_eq!(res.unwrap(), Some("loaded"));
+
+        // Only the last misses are remembered.
+        for k in 2..5 {
+            cache
+                .get_or_load(k, || async { Ok::<_, DatabaseError>(None) })
+                .await
+                .unwrap();
+        }
+        let res = cache
+            .get_or_load(2, || async { Ok::<_, DatabaseError>(Some("found")) })
+            .await;
+        assert_eq!(res.unwrap(), Some("found"));
+        let res = cache
+            .get_or_load(4, || async { Ok::<_, DatabaseError>(Some("found")) })
+            .await;
+        assert_eq!(res.unwrap(), None);
+    }
+}
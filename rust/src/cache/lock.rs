@@ -0,0 +1,252 @@
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::cache::{AtomicCache, CounterCache, Error};
+
+// Interval between attempts of `acquire` while the lock is held.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// Lock gives mutual exclusion to the holders of a lease, across every process
+// sharing the cache.
+//
+// A lease expires after its TTL unless renewed, so a crashed holder cannot
+// keep the lock forever. Because of that a holder can lose the lock without
+// noticing, for instance while paused. Every lease has a fencing token, which
+// increases with every acquisition, so resources can reject writes carrying a
+// token older than one already seen.
+//
+// Tokens are kept in a counter without TTL, which the cache must never drop:
+// with a default TTL or a capacity limit, an expired or evicted counter starts
+// again from 1 and tokens go backwards. Use a cache without either, or a
+// dedicated one.
+#[derive(Clone)]
+pub struct Lock<C> {
+    cache: C,
+    key: String,
+    token_key: String,
+    ttl: Duration,
+}
+
+impl<C> Lock<C>
+where
+    C: AtomicCache<String, Vec<u8>> + CounterCache<String> + Clone + Sync + Send,
+{
+    pub fn new<N: AsRef<str>>(cache: C, name: N, ttl: Duration) -> Lock<C> {
+        Lock {
+            cache,
+            // Locks and tokens have their own namespaces, so no lock name
+            // gives the key of another lock's token.
+            key: format!("lock:{}", name.as_ref()),
+            token_key: format!("lock-token:{}", name.as_ref()),
+            ttl,
+        }
+    }
+
+    // Returns the lease, or `None` if the lock is held.
+    pub async fn try_acquire(&self) -> Result<Option<Lease<C>>, Error> {
+        let owner = Uuid::new_v4().to_string().into_bytes();
+
+        let acquired = self
+            .cache
+            .set_if_absent(self.key.clone(), owner.clone(), Some(self.ttl))
+            .await?;
+        if !acquired {
+            return Ok(None);
+        }
+
+        // The token is taken while holding the lock, so every holder gets a
+        // greater one than the previous holders.
+        let token = match self.cache.incr_by(&self.token_key, 1, None).await {
+            Ok(token) => token,
+            Err(err) => {
+                let _ = self.cache.delete_if_equals(&self.key, &owner).await;
+                return Err(err);
+            }
+        };
+
+        Ok(Some(Lease {
+            lock: self.clone(),
+            owner,
+            token,
+        }))
+    }
+
+    // Waits up to `timeout` for the lock. Returns `None` if it is still held.
+    pub async fn acquire(&self, timeout: Duration) -> Result<Option<Lease<C>>, Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            if let Some(lease) = self.try_acquire().await? {
+                return Ok(Some(lease));
+            }
+
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            tokio::time::sleep(RETRY_INTERVAL.min(deadline - now)).await;
+        }
+    }
+}
+
+// Lease on a lock. Dropping it does not release the lock, which is then kept
+// until the lease expires.
+pub struct Lease<C> {
+    lock: Lock<C>,
+    owner: Vec<u8>,
+    token: i64,
+}
+
+impl<C> Lease<C>
+where
+    C: AtomicCache<String, Vec<u8>> + CounterCache<String> + Clone + Sync + Send,
+{
+    pub fn token(&self) -> i64 {
+        self.token
+    }
+
+    // Extends the lease for another TTL. Returns false if it had already
+    // expired, in which case the lock may be held by someone else.
+    pub async fn renew(&self) -> Result<bool, Error> {
+        self.lock
+            .cache
+            .expire_if_equals(&self.lock.key, &self.owner, self.lock.ttl)
+            .await
+    }
+
+    // Releases the lock if this lease still holds it. Returns false if it had
+    // already expired.
+    pub async fn release(self) -> Result<bool, Error> {
+        self.lock
+            .cache
+            .delete_if_equals(&self.lock.key, &self.owner)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::cache::resp_server::RespServer;
+    use crate::cache::{InMemCache, ManualClock, RedisCache};
+
+    #[tokio::test]
+    async fn mutual_exclusion() {
+        let cache = InMemCache::new();
+        let lock = Lock::new(cache.clone(), "job", Duration::from_secs(10));
+        let other = Lock::new(cache, "job", Duration::from_secs(10));
+
+        let lease = lock.try_acquire().await.unwrap().unwrap();
+        assert!(other.try_acquire().await.unwrap().is_none());
+
+        assert!(lease.release().await.unwrap());
+
+        let lease = other.try_acquire().await.unwrap().unwrap();
+        assert!(lock.try_acquire().await.unwrap().is_none());
+        assert_eq!(lease.token(), 2);
+    }
+
+    #[tokio::test]
+    async fn names_do_not_collide_with_tokens() {
+        let cache = InMemCache::new();
+        let lock = Lock::new(cache.clone(), "job", Duration::from_secs(10));
+        let token_named = Lock::new(cache, "job.token", Duration::from_secs(10));
+
+        let lease = lock.try_acquire().await.unwrap().unwrap();
+        let other = token_named.try_acquire().await.unwrap().unwrap();
+        assert_eq!(other.token(), 1);
+
+        assert!(lease.release().await.unwrap());
+        assert_eq!(lock.try_acquire().await.unwrap().unwrap().token(), 2);
+    }
+
+    #[tokio::test]
+    async fn lease_expiration_and_renewal() {
+        let clock = ManualClock::default();
+        let cache: InMemCache<String, Vec<u8>> = InMemCache::new().with_clock(clock.clone());
+        let lock = Lock::new(cache, "job", Duration::from_secs(10));
+
+        let lease = lock.try_acquire().await.unwrap().unwrap();
+
+        clock.advance(Duration::from_secs(8));
+        assert!(lease.renew().await.unwrap());
+        clock.advance(Duration::from_secs(8));
+        assert!(lock.try_acquire().await.unwrap().is_none());
+
+        // Once expired, the lock goes to someone else and the old lease can
+        // neither renew nor release it.
+        clock.advance(Duration::from_secs(2));
+        let new_lease = lock.try_acquire().await.unwrap().unwrap();
+        assert!(new_lease.token() > lease.token());
+
+        assert!(!lease.renew().await.unwrap());
+        assert!(!lease.release().await.unwrap());
+        assert!(lock.try_acquire().await.unwrap().is_none());
+
+        assert!(new_lease.release().await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_waits() {
+        let lock = Lock::new(InMemCache::new(), "job", Duration::from_secs(10));
+        let lease = lock.try_acquire().await.unwrap().unwrap();
+
+        assert!(lock
+            .acquire(Duration::from_millis(200))
+            .await
+            .unwrap()
+            .is_none());
+
+        let releaser = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            lease.release().await.unwrap();
+        });
+
+        let lease = lock.acquire(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(lease.token(), 2);
+        releaser.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn across_nodes() {
+        let server = RespServer::start().await;
+        let holders = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let cache = RedisCache::connect(server.addr()).await.unwrap();
+            let lock = Lock::new(cache, "job", Duration::from_secs(10));
+            let holders = holders.clone();
+
+            tasks.push(tokio::spawn(async move {
+                let mut tokens = Vec::new();
+                while tokens.len() < 5 {
+                    if let Some(lease) = lock.try_acquire().await.unwrap() {
+                        assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                        tokens.push(lease.token());
+                        tokio::task::yield_now().await;
+                        holders.fetch_sub(1, Ordering::SeqCst);
+
+                        assert!(lease.release().await.unwrap());
+                    }
+                    tokio::task::yield_now().await;
+                }
+
+                tokens
+            }));
+        }
+
+        let mut tokens = Vec::new();
+        for task in tasks {
+            tokens.extend(task.await.unwrap());
+        }
+
+        tokens.sort_unstable();
+        assert_eq!(tokens, (1..=20).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,199 @@
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::cache::key::encode_key;
+use crate::cache::Cache;
+
+// Memoized caches the results of an async function in any cache, keyed by the
+// name of the function and its arguments, serialized together as a pair.
+//
+// Only successful results are cached: errors are returned as they are and the
+// function is called again next time. The cache is an optimization, so its
+// failures are never returned: reading failures count as misses and writing
+// failures are ignored.
+pub struct Memoized<C, F> {
+    name: String,
+    cache: C,
+    f: F,
+    ttl: Option<Duration>,
+}
+
+impl<C, F> Memoized<C, F> {
+    // `name` identifies the function, so functions memoized with different
+    // names can share a cache.
+    pub fn new<N: Into<String>>(name: N, cache: C, f: F) -> Memoized<C, F> {
+        Memoized {
+            name: name.into(),
+            cache,
+            f,
+            ttl: None,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Memoized<C, F> {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.cache
+    }
+
+    // Returns the cached result for `args`, or calls the function.
+    pub async fn call<A, V, E, Fut>(&self, args: A) -> Result<V, E>
+    where
+        A: Serialize,
+        V: Clone,
+        F: Fn(A) -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+        C: Cache<String, V>,
+    {
+        let key = match encode_key(&(&self.name, &args)) {
+            Ok(key) => key,
+            Err(_) => return (self.f)(args).await,
+        };
+
+        if let Ok(Some(v)) = self.cache.get(&key).await {
+            return Ok(v);
+        }
+
+        let v = (self.f)(args).await?;
+
+        let _ = match self.ttl {
+            Some(ttl) => self.cache.set_with_ttl(key, v.clone(), ttl).await,
+            None => self.cache.set(key, v.clone()).await,
+        };
+
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::cache::{Error, InMemCache, ManualClock};
+
+    fn counted(
+        calls: &Arc<AtomicUsize>,
+    ) -> impl Fn((i32, i32)) -> futures::future::Ready<Result<i32, String>> {
+        let calls = calls.clone();
+
+        move |(a, b)| {
+            calls.fetch_add(1, Ordering::SeqCst);
+
+            futures::future::ready(if b == 0 {
+                Err("division by zero".to_string())
+            } else {
+                Ok(a / b)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_results() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let div = Memoized::new("div", InMemCache::new(), counted(&calls));
+
+        assert_eq!(div.call((6, 2)).await, Ok(3));
+        assert_eq!(div.call((6, 2)).await, Ok(3));
+        assert_eq!(div.call((6, 3)).await, Ok(2));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        assert_eq!(
+            div.inner()
+                .get(&r#"["div",[6,2]]"#.to_string())
+                .await
+                .unwrap(),
+            Some(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn names_and_arguments_kept_apart() {
+        let cache = InMemCache::new();
+        let first = Memoized::new("user:name", cache.clone(), |id: String| {
+            futures::future::ready(Ok::<_, String>(format!("first {}", id)))
+        });
+        let second = Memoized::new("user", cache, |id: String| {
+            futures::future::ready(Ok::<_, String>(format!("second {}", id)))
+        });
+
+        assert_eq!(first.call("1".to_string()).await.unwrap(), "first 1");
+        assert_eq!(
+            second.call("name:1".to_string()).await.unwrap(),
+            "second name:1"
+        );
+    }
+
+    #[tokio::test]
+    async fn never_caches_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let div = Memoized::new("div", InMemCache::new(), counted(&calls));
+
+        assert!(div.call((1, 0)).await.is_err());
+        assert!(div.call((1, 0)).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(div.inner().is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let clock = ManualClock::default();
+        let div = Memoized::new(
+            "div",
+            InMemCache::new().with_clock(clock.clone()),
+            counted(&calls),
+        )
+        .with_ttl(Duration::from_secs(10));
+
+        div.call((4, 2)).await.unwrap();
+        div.call((4, 2)).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(10));
+        div.call((4, 2)).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    struct BrokenCache;
+
+    #[async_trait]
+    impl Cache<String, i32> for BrokenCache {
+        async fn get(&self, _k: &String) -> Result<Option<i32>, Error> {
+            Err(Error::Internal)
+        }
+
+        async fn set(&self, _k: String, _v: i32) -> Result<(), Error> {
+            Err(Error::Internal)
+        }
+
+        async fn set_with_ttl(&self, _k: String, _v: i32, _ttl: Duration) -> Result<(), Error> {
+            Err(Error::Internal)
+        }
+
+        async fn delete(&self, _k: &String) -> Result<(), Error> {
+            Err(Error::Internal)
+        }
+
+        async fn len(&self) -> Result<usize, Error> {
+            Err(Error::Internal)
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_failures_are_misses() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let div = Memoized::new("div", BrokenCache, counted(&calls));
+
+        assert_eq!(div.call((6, 2)).await, Ok(3));
+        assert_eq!(div.call((6, 2)).await, Ok(3));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,7 +1,45 @@
+#[allow(clippy::module_inception)]
 mod cache;
+mod clock;
+mod codec;
+mod codec_cache;
+mod counter;
 mod errors;
+mod eviction;
 mod inmem_cache;
+mod key;
+mod loading_cache;
+mod lock;
+mod memoize;
+mod pattern;
+mod persistent_cache;
+mod rate_limiter;
+mod redis_cache;
+mod refreshing_cache;
+mod resp;
+#[cfg(test)]
+mod resp_server;
+mod sharded_cache;
+mod snapshot;
+mod stats;
+mod tiered_cache;
 
 pub use cache::*;
+pub use clock::*;
+pub use codec::*;
+pub use codec_cache::*;
+pub use counter::*;
 pub use errors::*;
+pub use eviction::*;
 pub use inmem_cache::*;
+pub use loading_cache::*;
+pub use lock::*;
+pub use memoize::*;
+pub use persistent_cache::*;
+pub use rate_limiter::*;
+pub use redis_cache::*;
+pub use refreshing_cache::*;
+pub use sharded_cache::*;
+pub use snapshot::*;
+pub use stats::*;
+pub use tiered_cache::*;
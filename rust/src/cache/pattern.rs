@@ -0,0 +1,87 @@
+// Keys are matched against patterns segment by segment, segments being
+// separated by dots like in event subjects. A `*` matches any characters
+// within a segment, so `user.*` matches `user.1` but not `user.1.posts`, and
+// `user:*` matches `user:1`.
+//
+// Unlike subjects of event subscriptions (see `LocalEventBus`), matching is
+// case sensitive, as keys are in every backend, and a `*` may be only part of
+// a segment.
+pub(crate) fn key_matches(pattern: &str, key: &str) -> bool {
+    if pattern == key {
+        return true;
+    }
+
+    let pattern_parts: Vec<&str> = pattern.split('.').collect();
+    let key_parts: Vec<&str> = key.split('.').collect();
+
+    if pattern_parts.len() != key_parts.len() {
+        return false;
+    }
+
+    pattern_parts
+        .iter()
+        .zip(key_parts.iter())
+        .all(|(pattern_part, key_part)| segment_matches(pattern_part, key_part))
+}
+
+fn segment_matches(pattern: &str, segment: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let segment = segment.as_bytes();
+
+    let (mut p, mut s) = (0, 0);
+    // Last `*` seen and the position of the segment it was tried at, to
+    // backtrack when the rest does not match.
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < segment.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, s));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == segment[s] {
+            p += 1;
+            s += 1;
+        } else if let Some((star_p, star_s)) = star {
+            p = star_p + 1;
+            s = star_s + 1;
+            star = Some((star_p, star_s + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        assert!(key_matches("user.1", "user.1"));
+        assert!(key_matches("user.*", "user.1"));
+        assert!(key_matches("*.1", "user.1"));
+        assert!(key_matches("user:*", "user:1"));
+        assert!(key_matches("*", "anything"));
+        assert!(key_matches("*", ""));
+        assert!(key_matches("u*r.*1*", "user.a1b"));
+        assert!(key_matches("a*b*c", "aXbYbZc"));
+
+        assert!(!key_matches("user.*", "user.1.posts"));
+        assert!(!key_matches("user.*", "user"));
+        assert!(!key_matches("*", "user.1"));
+        assert!(!key_matches("user:*", "post:1"));
+        assert!(!key_matches("a*b*c", "aXbYbZ"));
+        assert!(!key_matches("User.*", "user.1"));
+    }
+
+    #[test]
+    fn differs_from_subjects() {
+        // Both match by whole segments.
+        assert!(key_matches("user.*", "user.created"));
+
+        // Subjects ignore case and only have whole segment wildcards.
+        assert!(!key_matches("USER.*", "user.created"));
+        assert!(key_matches("user.cre*", "user.created"));
+    }
+}
@@ -0,0 +1,623 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::{BoxStream, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::cache::{BincodeCodec, Cache, Change, Codec, Error, InMemCache, ScanCache, WatchCache};
+
+const SNAPSHOT: &str = "snapshot";
+const SNAPSHOT_TMP: &str = "snapshot.tmp";
+const LOG: &str = "log";
+
+// Every record is framed as its length and CRC32, both u32 little endian,
+// followed by the encoded record.
+const HEADER: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+enum Record<K, V> {
+    Set {
+        key: K,
+        value: V,
+        expires_at: Option<DateTime<Utc>>,
+    },
+    Delete {
+        key: K,
+    },
+}
+
+struct Log {
+    file: File,
+    size: u64,
+}
+
+// PersistentCache keeps an `InMemCache` on disk so it survives restarts.
+//
+// Every write is appended to a log before being applied in memory. The log is
+// compacted into a snapshot of the live entries, either explicitly or once it
+// grows past a maximum size. On open, the snapshot and then the log are
+// replayed. A record torn by a crash, and everything after it, is discarded.
+//
+// Evictions and expirations are not logged: they are applied again when
+// replaying. Tags are not persisted.
+#[derive(Clone)]
+pub struct PersistentCache<K, V> {
+    cache: InMemCache<K, V>,
+    dir: PathBuf,
+    log: Arc<Mutex<Log>>,
+    max_log_size: Option<u64>,
+    sync_writes: bool,
+}
+
+impl<K, V> PersistentCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned,
+    V: Clone + Sync + Send + Serialize + DeserializeOwned,
+{
+    // Opens the cache stored in `dir`, creating it if needed, and loads it
+    // into `cache`.
+    pub async fn open<P: AsRef<Path>>(
+        dir: P,
+        cache: InMemCache<K, V>,
+    ) -> Result<PersistentCache<K, V>, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).await.map_err(Error::Storage)?;
+
+        let now = cache.now();
+
+        let snapshot = read(&dir.join(SNAPSHOT)).await?;
+        let (records, _) = decode(&snapshot);
+        for record in records {
+            apply(&cache, record, now).await;
+        }
+
+        let log = read(&dir.join(LOG)).await?;
+        let (records, size) = decode(&log);
+        for record in records {
+            apply(&cache, record, now).await;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG))
+            .await
+            .map_err(Error::Storage)?;
+
+        // Drops the torn tail, if any, so new records are not appended after it.
+        if size < log.len() {
+            file.set_len(size as u64).await.map_err(Error::Storage)?;
+        }
+
+        Ok(PersistentCache {
+            cache,
+            dir,
+            log: Arc::new(Mutex::new(Log {
+                file,
+                size: size as u64,
+            })),
+            max_log_size: None,
+            sync_writes: false,
+        })
+    }
+
+    // Compacts the log into a snapshot once it is larger than `size` bytes.
+    pub fn with_max_log_size(mut self, size: u64) -> PersistentCache<K, V> {
+        self.max_log_size = Some(size);
+        self
+    }
+
+    // Syncs the log to disk on every write, so acknowledged writes also
+    // survive a power loss. Otherwise they only survive a crash of the process.
+    pub fn with_sync_writes(mut self) -> PersistentCache<K, V> {
+        self.sync_writes = true;
+        self
+    }
+
+    pub fn inner(&self) -> &InMemCache<K, V> {
+        &self.cache
+    }
+
+    // Writes a snapshot of the live entries and empties the log.
+    pub async fn compact(&self) -> Result<(), Error> {
+        let mut log = self.log.lock().await;
+
+        self.compact_log(&mut log).await
+    }
+
+    // Flushes the log to disk. Writes only reach the OS until then.
+    pub async fn sync(&self) -> Result<(), Error> {
+        let log = self.log.lock().await;
+
+        log.file.sync_data().await.map_err(Error::Storage)
+    }
+
+    async fn compact_log(&self, log: &mut Log) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        for (key, value, expires_at) in self.cache.entries().await {
+            frame(
+                &mut buf,
+                &Record::Set {
+                    key,
+                    value,
+                    expires_at,
+                },
+            )?;
+        }
+
+        // The snapshot is replaced atomically, so a crash leaves either the
+        // old or the new one.
+        let tmp = self.dir.join(SNAPSHOT_TMP);
+        let mut file = File::create(&tmp).await.map_err(Error::Storage)?;
+        file.write_all(&buf).await.map_err(Error::Storage)?;
+        file.flush().await.map_err(Error::Storage)?;
+        file.sync_all().await.map_err(Error::Storage)?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT))
+            .await
+            .map_err(Error::Storage)?;
+        sync_dir(&self.dir).await?;
+
+        // A crash before truncating replays the log over the new snapshot,
+        // which ends up in the same state.
+        log.file.set_len(0).await.map_err(Error::Storage)?;
+        log.size = 0;
+
+        Ok(())
+    }
+
+    async fn write(&self, records: Vec<Record<K, V>>) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        for record in records.iter() {
+            frame(&mut buf, record)?;
+        }
+
+        // The lock is held while applying, so memory and log see writes in the
+        // same order.
+        let mut log = self.log.lock().await;
+
+        // Writes to a tokio file complete in the background, so errors only
+        // show up once flushed.
+        if let Err(err) = append(&mut log.file, &buf, self.sync_writes).await {
+            // Drops what was partially written, if anything.
+            let size = log.size;
+            let _ = log.file.set_len(size).await;
+            return Err(Error::Storage(err));
+        }
+        log.size += buf.len() as u64;
+
+        let now = self.cache.now();
+        for record in records {
+            apply(&self.cache, record, now).await;
+        }
+
+        match self.max_log_size {
+            Some(max) if log.size > max => self.compact_log(&mut log).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+async fn append(file: &mut File, buf: &[u8], sync: bool) -> std::io::Result<()> {
+    file.write_all(buf).await?;
+    file.flush().await?;
+
+    if sync {
+        file.sync_data().await?;
+    }
+
+    Ok(())
+}
+
+// Makes a rename in `dir` durable.
+async fn sync_dir(dir: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    File::open(dir)
+        .await
+        .map_err(Error::Storage)?
+        .sync_all()
+        .await
+        .map_err(Error::Storage)?;
+
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
+
+async fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    match fs::read(path).await {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(Error::Storage(err)),
+    }
+}
+
+fn frame<K, V>(buf: &mut Vec<u8>, record: &Record<K, V>) -> Result<(), Error>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    let data = BincodeCodec.encode(record)?;
+
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+    buf.extend_from_slice(&data);
+
+    Ok(())
+}
+
+// Decodes records until the end of the data or the first invalid one, and
+// returns them with the length of the valid prefix.
+fn decode<K, V>(data: &[u8]) -> (Vec<Record<K, V>>, usize)
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while data.len() - offset >= HEADER {
+        let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+
+        let start = offset + HEADER;
+        if data.len() - start < len {
+            break;
+        }
+
+        let payload = &data[start..start + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+
+        match BincodeCodec.decode(payload) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+
+        offset = start + len;
+    }
+
+    (records, offset)
+}
+
+async fn apply<K, V>(cache: &InMemCache<K, V>, record: Record<K, V>, now: DateTime<Utc>)
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    match record {
+        Record::Set {
+            key,
+            value,
+            expires_at,
+        } => match expires_at {
+            Some(expires_at) if expires_at <= now => {
+                let _ = cache.delete(&key).await;
+            }
+            _ => cache.set_expiring(key, value, expires_at).await,
+        },
+        Record::Delete { key } => {
+            let _ = cache.delete(&key).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> Cache<K, V> for PersistentCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned,
+    V: Clone + Sync + Send + Serialize + DeserializeOwned,
+{
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        self.cache.get(k).await
+    }
+
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        let expires_at = self.cache.expires_at(None);
+
+        self.write(vec![Record::Set {
+            key: k,
+            value: v,
+            expires_at,
+        }])
+        .await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        let expires_at = self.cache.expires_at(Some(ttl));
+
+        self.write(vec![Record::Set {
+            key: k,
+            value: v,
+            expires_at,
+        }])
+        .await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.write(vec![Record::Delete { key: k.clone() }]).await
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        self.cache.len().await
+    }
+
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error> {
+        self.cache.get_many(ks).await
+    }
+
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error> {
+        let expires_at = self.cache.expires_at(None);
+
+        self.write(
+            items
+                .into_iter()
+                .map(|(key, value)| Record::Set {
+                    key,
+                    value,
+                    expires_at,
+                })
+                .collect(),
+        )
+        .await
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        self.write(
+            ks.iter()
+                .map(|k| Record::Delete { key: k.clone() })
+                .collect(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<K, V> ScanCache<K> for PersistentCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned,
+    V: Clone + Sync + Send + Serialize + DeserializeOwned,
+{
+    fn scan(&self, pattern: &str) -> BoxStream<'_, Result<K, Error>> {
+        self.cache.scan(pattern)
+    }
+
+    // The matching keys are logged as deleted like with `delete_many`.
+    async fn delete_matching(&self, pattern: &str) -> Result<usize, Error> {
+        let keys: Vec<K> = self.cache.scan(pattern).try_collect().await?;
+        self.delete_many(&keys).await?;
+
+        Ok(keys.len())
+    }
+}
+
+impl<K, V> WatchCache<K, V> for PersistentCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + 'static,
+    V: Clone + Sync + Send + 'static,
+{
+    fn watch(&self, k: &K) -> BoxStream<'static, Result<Change<K, V>, Error>> {
+        self.cache.watch(k)
+    }
+
+    fn watch_prefix(&self, prefix: &str) -> BoxStream<'static, Result<Change<K, V>, Error>> {
+        self.cache.watch_prefix(prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    use crate::cache::ManualClock;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("core-lib-cache-{}", Uuid::new_v4()))
+    }
+
+    async fn open(dir: &Path) -> PersistentCache<String, i64> {
+        PersistentCache::open(dir, InMemCache::new()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn restores_after_restart() {
+        let dir = temp_dir();
+
+        let cache = open(&dir).await.with_sync_writes();
+        cache.set("a".to_string(), 1).await.unwrap();
+        cache.set("b".to_string(), 2).await.unwrap();
+        cache.set("a".to_string(), 3).await.unwrap();
+        cache.delete(&"b".to_string()).await.unwrap();
+        cache
+            .set_many(vec![("c".to_string(), 4), ("d".to_string(), 5)])
+            .await
+            .unwrap();
+        cache.delete_many(&["d".to_string()]).await.unwrap();
+        drop(cache);
+
+        let cache = open(&dir).await;
+        assert_eq!(cache.len().await.unwrap(), 2);
+        assert_eq!(cache.get(&"a".to_string()).await.unwrap(), Some(3));
+        assert_eq!(cache.get(&"c".to_string()).await.unwrap(), Some(4));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_matching_is_persisted() {
+        let dir = temp_dir();
+
+        let cache = open(&dir).await;
+        cache.set("user.1".to_string(), 1).await.unwrap();
+        cache.set("user.2".to_string(), 2).await.unwrap();
+        cache.set("post.1".to_string(), 3).await.unwrap();
+        assert_eq!(cache.delete_matching("user.*").await.unwrap(), 2);
+        drop(cache);
+
+        let cache = open(&dir).await;
+        let keys: Vec<String> = cache.scan("*.*").try_collect().await.unwrap();
+        assert_eq!(keys, vec!["post.1"]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_expirations() {
+        let dir = temp_dir();
+        let clock = ManualClock::default();
+
+        let cache = PersistentCache::open(&dir, InMemCache::new().with_clock(clock.clone()))
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl(1, "short".to_string(), Duration::from_secs(10))
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl(2, "long".to_string(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        drop(cache);
+
+        clock.advance(Duration::from_secs(30));
+
+        let cache: PersistentCache<i32, String> =
+            PersistentCache::open(&dir, InMemCache::new().with_clock(clock.clone()))
+                .await
+                .unwrap();
+        assert!(cache.get(&1).await.unwrap().is_none());
+        assert_eq!(cache.get(&2).await.unwrap(), Some("long".to_string()));
+
+        clock.advance(Duration::from_secs(30));
+        assert!(cache.get(&2).await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compaction() {
+        let dir = temp_dir();
+
+        let cache = open(&dir).await;
+        for i in 0..100 {
+            cache.set("counter".to_string(), i).await.unwrap();
+        }
+        cache.set("deleted".to_string(), 0).await.unwrap();
+        cache.delete(&"deleted".to_string()).await.unwrap();
+
+        cache.compact().await.unwrap();
+        assert_eq!(fs::metadata(dir.join(LOG)).await.unwrap().len(), 0);
+        assert!(!dir.join(SNAPSHOT_TMP).exists());
+
+        cache.set("after".to_string(), 1).await.unwrap();
+        drop(cache);
+
+        let cache = open(&dir).await;
+        assert_eq!(cache.len().await.unwrap(), 2);
+        assert_eq!(cache.get(&"counter".to_string()).await.unwrap(), Some(99));
+        assert_eq!(cache.get(&"after".to_string()).await.unwrap(), Some(1));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compacts_when_log_is_too_large() {
+        let dir = temp_dir();
+
+        let cache = open(&dir).await.with_max_log_size(1024);
+        for i in 0..1000 {
+            cache.set(format!("key-{}", i % 10), i).await.unwrap();
+        }
+
+        assert!(fs::metadata(dir.join(LOG)).await.unwrap().len() <= 1024);
+        drop(cache);
+
+        let cache = open(&dir).await;
+        assert_eq!(cache.len().await.unwrap(), 10);
+        assert_eq!(cache.get(&"key-9".to_string()).await.unwrap(), Some(999));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovers_from_torn_write() {
+        let dir = temp_dir();
+
+        let cache = open(&dir).await;
+        cache.set("a".to_string(), 1).await.unwrap();
+        cache.set("b".to_string(), 2).await.unwrap();
+        drop(cache);
+
+        let size = fs::metadata(dir.join(LOG)).await.unwrap().len();
+
+        // A crash in the middle of appending a record.
+        let mut record = Vec::new();
+        frame(
+            &mut record,
+            &Record::Set {
+                key: "c".to_string(),
+                value: 3i64,
+                expires_at: None,
+            },
+        )
+        .unwrap();
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG))
+            .await
+            .unwrap();
+        file.write_all(&record[..record.len() - 3]).await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        let cache = open(&dir).await;
+        assert_eq!(cache.len().await.unwrap(), 2);
+        assert!(cache.get(&"c".to_string()).await.unwrap().is_none());
+        assert_eq!(fs::metadata(dir.join(LOG)).await.unwrap().len(), size);
+
+        // New records are not lost behind the torn one.
+        cache.set("d".to_string(), 4).await.unwrap();
+        drop(cache);
+
+        let cache = open(&dir).await;
+        assert_eq!(cache.len().await.unwrap(), 3);
+        assert_eq!(cache.get(&"d".to_string()).await.unwrap(), Some(4));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovers_from_corrupted_record() {
+        let dir = temp_dir();
+
+        let cache = open(&dir).await;
+        cache.set("a".to_string(), 1).await.unwrap();
+        cache.set("b".to_string(), 2).await.unwrap();
+        cache.set("c".to_string(), 3).await.unwrap();
+        drop(cache);
+
+        // Corrupts the second record, which also discards the third one.
+        let mut data = fs::read(dir.join(LOG)).await.unwrap();
+        let len = data.len() / 3;
+        data[len + HEADER] ^= 0xff;
+        fs::write(dir.join(LOG), &data).await.unwrap();
+
+        let cache = open(&dir).await;
+        assert_eq!(cache.len().await.unwrap(), 1);
+        assert_eq!(cache.get(&"a".to_string()).await.unwrap(), Some(1));
+        assert_eq!(fs::metadata(dir.join(LOG)).await.unwrap().len(), len as u64);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
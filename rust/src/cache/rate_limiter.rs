@@ -0,0 +1,571 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::{Clock, Codec, Error, JsonCodec, SystemClock, VersionedCache};
+use crate::errors::{self, Define, Metadata};
+
+// Attempts of a check before giving up when other checks keep updating the
+// same key.
+const MAX_ATTEMPTS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    TokenBucket { capacity: u64, refill_every: i64 },
+    SlidingWindow { limit: u64, window: i64 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    tokens: u64,
+    refilled_at: i64,
+}
+
+// Requests allowed in the current fixed window, starting at `started_at`, and
+// in the one before it.
+#[derive(Serialize, Deserialize)]
+struct Window {
+    started_at: i64,
+    current: u64,
+    previous: u64,
+}
+
+impl Window {
+    // Moves to the window holding `now`.
+    fn roll(&mut self, now: i64, window: i64) {
+        let elapsed = (now - self.started_at).max(0) / window;
+        if elapsed > 0 {
+            self.previous = if elapsed == 1 { self.current } else { 0 };
+            self.current = 0;
+            self.started_at += elapsed * window;
+        }
+    }
+
+    // Requests in the sliding window ending at `now`, times `window`: the
+    // previous window counts for the part of it still in the sliding window.
+    fn weighted(&self, now: i64, window: i64) -> u128 {
+        let overlap = (window - (now - self.started_at)).max(0) as u128;
+
+        self.previous as u128 * overlap + self.current as u128 * window as u128
+    }
+
+    // Time until one more request is allowed.
+    fn retry_after(&self, now: i64, window: i64, limit: u64) -> i64 {
+        let elapsed = now - self.started_at;
+        let wait = |count: u64, room: u64| {
+            // Time into a window when `count` requests of the previous one
+            // leave room for `room` more.
+            match count {
+                0 => 0,
+                count => window - (room as i128 * window as i128 / count as i128) as i64,
+            }
+        };
+
+        if self.current >= limit {
+            window - elapsed + wait(self.current, limit.saturating_sub(1))
+        } else {
+            wait(self.previous, limit - self.current - 1) - elapsed
+        }
+    }
+}
+
+// RateLimiter limits how often something identified by a key can happen,
+// keeping its state in a cache so every handle to the cache shares the limits.
+//
+// State is updated with `compare_and_set`, so concurrent checks never let more
+// requests through than the limit. It expires once it no longer matters: when
+// the bucket would be full again or every request left the window. Any cache
+// with versions can be shared between processes, `RedisCache` included.
+pub struct RateLimiter<C> {
+    cache: C,
+    name: String,
+    algorithm: Algorithm,
+    clock: Arc<dyn Clock>,
+}
+
+impl<C> RateLimiter<C>
+where
+    C: VersionedCache<String, Vec<u8>> + Sync + Send,
+{
+    // Bucket of `capacity` tokens, initially full, refilled with one token
+    // every `refill_every`. Every allowed request takes a token, so bursts of
+    // up to `capacity` are allowed.
+    pub fn token_bucket<N: Into<String>>(
+        cache: C,
+        name: N,
+        capacity: u64,
+        refill_every: Duration,
+    ) -> RateLimiter<C> {
+        RateLimiter::new(
+            cache,
+            name,
+            Algorithm::TokenBucket {
+                capacity,
+                refill_every: (refill_every.as_millis() as i64).max(1),
+            },
+        )
+    }
+
+    // Allows `limit` requests in any `window`, approximately: only the number
+    // of requests in the current and the previous fixed windows are kept, and
+    // those of the previous one are assumed evenly spread. Windows follow the
+    // first request of the key.
+    pub fn sliding_window<N: Into<String>>(
+        cache: C,
+        name: N,
+        limit: u64,
+        window: Duration,
+    ) -> RateLimiter<C> {
+        RateLimiter::new(
+            cache,
+            name,
+            Algorithm::SlidingWindow {
+                limit,
+                window: (window.as_millis() as i64).max(1),
+            },
+        )
+    }
+
+    fn new<N: Into<String>>(cache: C, name: N, algorithm: Algorithm) -> RateLimiter<C> {
+        RateLimiter {
+            cache,
+            name: name.into(),
+            algorithm,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock<K>(mut self, clock: K) -> RateLimiter<C>
+    where
+        K: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Counts a request for `key` if it is allowed. Fails with
+    // `Error::Conflict` if other checks of the same key kept updating it
+    // after a few attempts, in which case nothing was counted.
+    pub async fn check(&self, key: &str) -> Result<Decision, Error> {
+        let state_key = format!("rate.{}.{}", self.name, key);
+
+        match self.algorithm {
+            Algorithm::TokenBucket {
+                capacity,
+                refill_every,
+            } => {
+                let ttl = refill_every.saturating_mul(capacity.max(1) as i64);
+                self.update(&state_key, ttl, |bucket: Option<Bucket>, now| {
+                    let mut bucket = bucket.unwrap_or(Bucket {
+                        tokens: capacity,
+                        refilled_at: now,
+                    });
+
+                    let refills = (now - bucket.refilled_at).max(0) / refill_every;
+                    bucket.tokens = bucket.tokens.saturating_add(refills as u64);
+                    bucket.refilled_at += refills * refill_every;
+                    if bucket.tokens >= capacity {
+                        bucket.tokens = capacity;
+                        bucket.refilled_at = now;
+                    }
+
+                    if bucket.tokens == 0 {
+                        let retry_after = bucket.refilled_at + refill_every - now;
+                        return (None, self.denied(key, capacity, retry_after));
+                    }
+
+                    bucket.tokens -= 1;
+                    let remaining = bucket.tokens;
+
+                    (Some(bucket), Decision::Allowed { remaining })
+                })
+                .await
+            }
+            Algorithm::SlidingWindow { limit, window } => {
+                // The previous window still counts during the current one.
+                let ttl = window.saturating_mul(2);
+                self.update(&state_key, ttl, |state: Option<Window>, now| {
+                    let mut state = state.unwrap_or(Window {
+                        started_at: now,
+                        current: 0,
+                        previous: 0,
+                    });
+                    state.roll(now, window);
+
+                    let capacity = limit as u128 * window as u128;
+                    let weighted = state.weighted(now, window) + window as u128;
+                    if weighted > capacity {
+                        let retry_after = state.retry_after(now, window, limit);
+                        return (None, self.denied(key, limit, retry_after));
+                    }
+
+                    state.current += 1;
+                    let remaining = ((capacity - weighted) / window as u128) as u64;
+
+                    (Some(state), Decision::Allowed { remaining })
+                })
+                .await
+            }
+        }
+    }
+
+    // Reads the state, decides and writes the new state, if any, expiring
+    // after `ttl` milliseconds, unless the state was changed meanwhile, in
+    // which case it starts over.
+    async fn update<S, F>(&self, key: &str, ttl: i64, decide: F) -> Result<Decision, Error>
+    where
+        S: Serialize + DeserializeOwned,
+        F: Fn(Option<S>, i64) -> (Option<S>, Decision),
+    {
+        let key = key.to_string();
+        let ttl = Duration::from_millis(ttl as u64);
+        let mut res = Err(Error::Internal);
+
+        for _ in 0..MAX_ATTEMPTS {
+            let (state, version) = match self.cache.get_versioned(&key).await? {
+                Some((data, version)) => (Some(JsonCodec.decode(&data)?), Some(version)),
+                None => (None, None),
+            };
+
+            let now = self.clock.now().timestamp_millis();
+            let (state, decision) = decide(state, now);
+
+            let state = match state {
+                Some(state) => JsonCodec.encode(&state)?,
+                None => return Ok(decision),
+            };
+
+            res = self
+                .cache
+                .compare_and_set_with_ttl(key.clone(), version.as_ref(), state, ttl)
+                .await
+                .map(|_| decision);
+            if !matches!(res, Err(Error::Conflict { .. })) {
+                return res;
+            }
+        }
+
+        res
+    }
+
+    fn denied(&self, key: &str, limit: u64, retry_after: i64) -> Decision {
+        Decision::Denied(Denied {
+            name: self.name.clone(),
+            key: key.to_string(),
+            limit,
+            retry_after: Duration::from_millis(retry_after.max(0) as u64),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allowed { remaining: u64 },
+    Denied(Denied),
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allowed { .. })
+    }
+
+    // Lets a deny be returned with `?`, converted into an `errors::Error`.
+    pub fn into_result(self) -> Result<u64, Denied> {
+        match self {
+            Decision::Allowed { remaining } => Ok(remaining),
+            Decision::Denied(denied) => Err(denied),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denied {
+    name: String,
+    key: String,
+    limit: u64,
+    retry_after: Duration,
+}
+
+impl Denied {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    // Time until a request can be allowed again.
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+}
+
+// Code of the errors converted from a `Denied`.
+pub struct RateLimited;
+
+impl Define for RateLimited {
+    fn define(&self) -> &str {
+        "rate_limited"
+    }
+}
+
+impl From<Denied> for errors::Error {
+    fn from(denied: Denied) -> Self {
+        errors::Error::new(
+            RateLimited,
+            format!("rate limit of {} exceeded for {}", denied.name, denied.key),
+            Metadata::with("limiter", &denied.name)
+                .and("key", &denied.key)
+                .and("limit", denied.limit)
+                .and("retry_after_ms", denied.retry_after.as_millis() as u64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::cache::resp_server::RespServer;
+    use crate::cache::{Cache, InMemCache, ManualClock, RedisCache, ShardedInMemCache};
+
+    #[tokio::test]
+    async fn token_bucket() {
+        let clock = ManualClock::default();
+        let limiter =
+            RateLimiter::token_bucket(InMemCache::new(), "api", 3, Duration::from_secs(10))
+                .with_clock(clock.clone());
+
+        for remaining in (0..3).rev() {
+            assert_eq!(
+                limiter.check("user-1").await.unwrap(),
+                Decision::Allowed { remaining }
+            );
+        }
+
+        let denied = limiter
+            .check("user-1")
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(denied.limit(), 3);
+        assert_eq!(denied.retry_after(), Duration::from_secs(10));
+
+        // Other keys have their own bucket.
+        assert!(limiter.check("user-2").await.unwrap().is_allowed());
+
+        clock.advance(Duration::from_secs(4));
+        let denied = limiter
+            .check("user-1")
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_secs(6));
+
+        clock.advance(Duration::from_secs(16));
+        assert_eq!(
+            limiter.check("user-1").await.unwrap(),
+            Decision::Allowed { remaining: 1 }
+        );
+
+        // The bucket never holds more than its capacity.
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(
+            limiter.check("user-1").await.unwrap(),
+            Decision::Allowed { remaining: 2 }
+        );
+    }
+
+    #[tokio::test]
+    async fn sliding_window() {
+        let clock = ManualClock::default();
+        let limiter =
+            RateLimiter::sliding_window(InMemCache::new(), "login", 2, Duration::from_secs(60))
+                .with_clock(clock.clone());
+
+        assert!(limiter.check("user-1").await.unwrap().is_allowed());
+        clock.advance(Duration::from_secs(20));
+        assert!(limiter.check("user-1").await.unwrap().is_allowed());
+        clock.advance(Duration::from_secs(20));
+
+        // Once the window is over, its requests are assumed evenly spread,
+        // so one of them leaves the sliding window after half of the next one.
+        let denied = limiter
+            .check("user-1")
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_secs(50));
+
+        clock.advance(Duration::from_secs(20));
+        let denied = limiter
+            .check("user-1")
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_secs(30));
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            limiter.check("user-1").await.unwrap(),
+            Decision::Allowed { remaining: 0 }
+        );
+        let denied = limiter
+            .check("user-1")
+            .await
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_secs(30));
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.check("user-1").await.unwrap().is_allowed());
+
+        // Windows long over are forgotten.
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(
+            limiter.check("user-1").await.unwrap(),
+            Decision::Allowed { remaining: 1 }
+        );
+    }
+
+    #[tokio::test]
+    async fn state_expires() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+        let bucket = RateLimiter::token_bucket(cache.clone(), "api", 3, Duration::from_secs(10))
+            .with_clock(clock.clone());
+        let window =
+            RateLimiter::sliding_window(cache.clone(), "login", 2, Duration::from_secs(60))
+                .with_clock(clock.clone());
+
+        bucket.check("user-1").await.unwrap();
+        window.check("user-1").await.unwrap();
+        assert_eq!(cache.len().await.unwrap(), 2);
+
+        // The bucket is full again after 30 seconds, and the window no longer
+        // counts once the next one is over, after two minutes.
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(cache.len().await.unwrap(), 2);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.len().await.unwrap(), 1);
+        clock.advance(Duration::from_secs(89));
+        assert_eq!(cache.len().await.unwrap(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.is_empty().await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_checks() {
+        let limiter = Arc::new(RateLimiter::sliding_window(
+            ShardedInMemCache::new(4),
+            "api",
+            50,
+            Duration::from_secs(3600),
+        ));
+
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let limiter = limiter.clone();
+
+            tasks.push(tokio::spawn(async move {
+                let mut allowed = 0;
+                for _ in 0..20 {
+                    loop {
+                        match limiter.check("shared").await {
+                            Ok(decision) if decision.is_allowed() => allowed += 1,
+                            Ok(_) => {}
+                            // Gave up because of contention.
+                            Err(Error::Conflict { .. }) => continue,
+                            Err(err) => panic!("{}", err),
+                        }
+                        break;
+                    }
+                }
+
+                allowed
+            }));
+        }
+
+        let mut allowed = 0;
+        for task in tasks {
+            allowed += task.await.unwrap();
+        }
+
+        assert_eq!(allowed, 50);
+    }
+
+    // Limiters of separate processes share their limits through Redis.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn shared_through_redis() {
+        let server = RespServer::start().await;
+
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let cache = RedisCache::connect(server.addr()).await.unwrap();
+            let window =
+                RateLimiter::sliding_window(cache.clone(), "window", 20, Duration::from_secs(3600));
+            let bucket = RateLimiter::token_bucket(cache, "bucket", 10, Duration::from_secs(3600));
+
+            tasks.push(tokio::spawn(async move {
+                let mut allowed = (0, 0);
+                for _ in 0..10 {
+                    for (limiter, allowed) in [(&window, &mut allowed.0), (&bucket, &mut allowed.1)]
+                    {
+                        loop {
+                            match limiter.check("shared").await {
+                                Ok(decision) if decision.is_allowed() => *allowed += 1,
+                                Ok(_) => {}
+                                Err(Error::Conflict { .. }) => continue,
+                                Err(err) => panic!("{}", err),
+                            }
+                            break;
+                        }
+                    }
+                }
+
+                allowed
+            }));
+        }
+
+        let mut allowed = (0, 0);
+        for task in tasks {
+            let (window, bucket) = task.await.unwrap();
+            allowed = (allowed.0 + window, allowed.1 + bucket);
+        }
+
+        assert_eq!(allowed, (20, 10));
+    }
+
+    #[tokio::test]
+    async fn denied_into_error() {
+        let limiter =
+            RateLimiter::token_bucket(InMemCache::new(), "api", 1, Duration::from_secs(1));
+
+        let check = || async {
+            limiter.check("user-1").await.unwrap().into_result()?;
+            Ok::<(), errors::Error>(())
+        };
+
+        assert!(check().await.is_ok());
+
+        let err = check().await.unwrap_err();
+        assert_eq!(err.code(), "rate_limited");
+        assert_eq!(err.code(), RateLimited.define());
+        assert_eq!(err.metadata().values()["limiter"], json!("api"));
+        assert_eq!(err.metadata().values()["key"], json!("user-1"));
+        assert_eq!(err.metadata().values()["limit"], json!(1));
+        assert!(err.metadata().values()["retry_after_ms"].as_u64().unwrap() <= 1000);
+    }
+}
@@ -0,0 +1,1543 @@
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::cache::key::{decode_key, encode_key};
+use crate::cache::pattern::key_matches;
+use crate::cache::resp::{read_value, Command, Value};
+use crate::cache::{
+    AtomicCache, Cache, CounterCache, Error, ScanCache, TaggedCache, VersionedCache,
+};
+use crate::models::Version;
+
+struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    async fn open(addr: &str) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(addr).await.map_err(Error::Connection)?;
+        stream.set_nodelay(true).map_err(Error::Connection)?;
+
+        Ok(Connection {
+            stream: BufStream::new(stream),
+        })
+    }
+
+    // Commands are pipelined: all of them are written before reading the
+    // replies.
+    async fn execute(&mut self, commands: &[Command]) -> Result<Vec<Value>, Error> {
+        let mut buf = Vec::new();
+        for command in commands {
+            command.encode(&mut buf);
+        }
+
+        self.stream
+            .write_all(&buf)
+            .await
+            .map_err(Error::Connection)?;
+        self.stream.flush().await.map_err(Error::Connection)?;
+
+        let mut values = Vec::with_capacity(commands.len());
+        for _ in commands {
+            values.push(read_value(&mut self.stream).await?);
+        }
+
+        Ok(values)
+    }
+}
+
+// Connection in use by a call. After a failure, or if the call is cancelled
+// midway, the state of the stream is unknown, so it is dropped.
+struct Checkout<'a> {
+    connection: MutexGuard<'a, Option<Connection>>,
+    healthy: bool,
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        if !self.healthy {
+            *self.connection = None;
+        }
+    }
+}
+
+// RedisCache stores raw bytes in any server speaking RESP. Keys are
+// serialized with serde. A broken connection is dropped and re-established on
+// the next call.
+#[derive(Clone)]
+pub struct RedisCache {
+    addr: String,
+    connection: Arc<Mutex<Option<Connection>>>,
+    timeout: Option<Duration>,
+    // SHA1 digests of the scripts loaded in the server.
+    scripts: Arc<std::sync::Mutex<HashMap<&'static str, String>>>,
+}
+
+impl RedisCache {
+    pub async fn connect<A: Into<String>>(addr: A) -> Result<RedisCache, Error> {
+        let addr = addr.into();
+        let connection = Connection::open(&addr).await?;
+
+        Ok(RedisCache {
+            addr,
+            connection: Arc::new(Mutex::new(Some(connection))),
+            timeout: None,
+            scripts: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        })
+    }
+
+    // Fails calls taking longer than `timeout`, reconnecting included, with
+    // `Error::Timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> RedisCache {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Counts the keys of the selected database, except those the cache keeps
+    // for itself, such as tags. Keys are scanned, so it is not atomic.
+    pub async fn len(&self) -> Result<usize, Error> {
+        let mut cursor = "0".to_string();
+        let mut count = 0;
+
+        loop {
+            let (next, keys) = self.scan_keys(&cursor, "*").await?;
+            count += keys
+                .iter()
+                .filter(|key| !key.starts_with(INTERNAL_PREFIX.as_bytes()))
+                .count();
+
+            match next {
+                Some(next) => cursor = next,
+                None => return Ok(count),
+            }
+        }
+    }
+
+    pub async fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len().await? == 0)
+    }
+
+    // Keys are popped from the tag in chunks, so entries tagged while
+    // invalidating are either deleted now or kept for the next invalidation.
+    // Entries overwritten since they were tagged are left alone.
+    pub async fn invalidate_tag(&self, tag: &str) -> Result<usize, Error> {
+        let mut count = 0;
+
+        loop {
+            let reply = self
+                .eval(INVALIDATE_TAG, |command| {
+                    command
+                        .arg("1")
+                        .arg(tag_key(tag))
+                        .arg(TAG_CHUNK.to_string())
+                        .arg(entry_tags_key(""))
+                        .arg(version_key(""))
+                })
+                .await?;
+
+            match reply {
+                Value::Array(Some(reply)) => match reply.as_slice() {
+                    [Value::Integer(0), _] => break,
+                    [Value::Integer(_), Value::Integer(deleted)] => count += *deleted as usize,
+                    _ => return Err(Error::Protocol(format!("unexpected reply {:?}", reply))),
+                },
+                value => return Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Deletes the keys matching `pattern` page by page, without decoding
+    // them. See `ScanCache`.
+    pub async fn delete_matching(&self, pattern: &str) -> Result<usize, Error> {
+        let mut cursor = "0".to_string();
+        let mut count = 0;
+
+        loop {
+            let (next, keys) = self.scan_page(&cursor, pattern).await?;
+
+            if !keys.is_empty() {
+                count += self.delete_entries(keys).await?;
+            }
+
+            match next {
+                Some(next) => cursor = next,
+                None => return Ok(count),
+            }
+        }
+    }
+
+    // Runs one SCAN iteration and returns the next cursor, if any, and the
+    // matching keys. Redis only filters with its own glob syntax, so keys are
+    // matched again against the segments of the pattern.
+    async fn scan_page(
+        &self,
+        cursor: &str,
+        pattern: &str,
+    ) -> Result<(Option<String>, Vec<String>), Error> {
+        let (next, keys) = self.scan_keys(cursor, &redis_glob(pattern)).await?;
+
+        let mut matching = Vec::new();
+        for key in keys {
+            // Keys not written by this cache may not be UTF-8.
+            if let Ok(key) = String::from_utf8(key) {
+                if !key.starts_with(INTERNAL_PREFIX) && key_matches(pattern, &key) {
+                    matching.push(key);
+                }
+            }
+        }
+
+        Ok((next, matching))
+    }
+
+    // Runs one SCAN iteration with a Redis glob and returns the next cursor,
+    // if any, and the raw keys.
+    async fn scan_keys(
+        &self,
+        cursor: &str,
+        glob: &str,
+    ) -> Result<(Option<String>, Vec<Vec<u8>>), Error> {
+        let command = Command::new("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(glob)
+            .arg("COUNT")
+            .arg(SCAN_COUNT.to_string());
+
+        let (next, keys) = match self.execute_one(command).await? {
+            Value::Array(Some(reply)) => match <[Value; 2]>::try_from(reply) {
+                Ok([Value::Bulk(Some(next)), Value::Array(Some(keys))]) => (next, keys),
+                Ok(reply) => return Err(Error::Protocol(format!("unexpected reply {:?}", reply))),
+                Err(reply) => return Err(Error::Protocol(format!("unexpected reply {:?}", reply))),
+            },
+            value => return Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        };
+
+        let next = String::from_utf8_lossy(&next).to_string();
+        let next = if next == "0" { None } else { Some(next) };
+
+        let keys = keys
+            .into_iter()
+            .map(|key| match key {
+                Value::Bulk(Some(key)) => Ok(key),
+                value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok((next, keys))
+    }
+
+    // Writes entries and detaches them from the tags of the entries they
+    // replace.
+    async fn set_entries(
+        &self,
+        entries: Vec<(String, Vec<u8>)>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let reply = self
+            .eval(SET_ENTRIES, |mut command| {
+                command = command.arg((entries.len() * 3).to_string());
+                for (key, _) in entries.iter() {
+                    command = command
+                        .arg(key)
+                        .arg(entry_tags_key(key))
+                        .arg(version_key(key));
+                }
+
+                command = command.arg(ttl.map_or("0".to_string(), ttl_millis));
+                for (_, value) in entries.iter() {
+                    command = command.arg(value);
+                }
+
+                command
+            })
+            .await?;
+
+        expect_ok(reply)
+    }
+
+    // Deletes entries with their tags and returns how many existed.
+    async fn delete_entries(&self, keys: Vec<String>) -> Result<usize, Error> {
+        let reply = self
+            .eval(DELETE_ENTRIES, |mut command| {
+                command = command.arg((keys.len() * 3).to_string());
+                for key in keys.iter() {
+                    command = command
+                        .arg(key)
+                        .arg(entry_tags_key(key))
+                        .arg(version_key(key));
+                }
+
+                command
+            })
+            .await?;
+
+        match reply {
+            Value::Integer(deleted) => Ok(deleted as usize),
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    pub(crate) async fn execute(&self, commands: &[Command]) -> Result<Vec<Value>, Error> {
+        let mut checkout = Checkout {
+            connection: self.connection.lock().await,
+            healthy: false,
+        };
+        let connection: &mut Option<Connection> = &mut checkout.connection;
+
+        let run = async {
+            match connection.as_mut() {
+                Some(connection) => connection.execute(commands).await,
+                None => {
+                    let mut new_connection = Connection::open(&self.addr).await?;
+                    let res = new_connection.execute(commands).await;
+                    *connection = Some(new_connection);
+                    res
+                }
+            }
+        };
+
+        let res = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or_else(|elapsed| Err(Error::Timeout(timeout, elapsed))),
+            None => run.await,
+        };
+
+        checkout.healthy = res.is_ok();
+
+        res
+    }
+
+    pub(crate) async fn execute_one(&self, command: Command) -> Result<Value, Error> {
+        let mut values = self.execute(&[command]).await?;
+
+        values
+            .pop()
+            .ok_or_else(|| Error::Protocol("missing reply".to_string()))?
+            .into_result()
+    }
+
+    // Runs a script with EVALSHA, the arguments being added by `args`. Scripts
+    // are loaded once with SCRIPT LOAD, and again if the server lost them, for
+    // instance after a restart.
+    async fn eval<F>(&self, script: &'static str, args: F) -> Result<Value, Error>
+    where
+        F: Fn(Command) -> Command,
+    {
+        let loaded = self.scripts.lock().unwrap().get(script).cloned();
+        let sha = match loaded {
+            Some(sha) => sha,
+            None => self.load_script(script).await?,
+        };
+
+        match self
+            .execute_one(args(Command::new("EVALSHA").arg(sha)))
+            .await
+        {
+            Err(Error::Command(msg)) if msg.starts_with("NOSCRIPT") => {
+                let sha = self.load_script(script).await?;
+                self.execute_one(args(Command::new("EVALSHA").arg(sha)))
+                    .await
+            }
+            res => res,
+        }
+    }
+
+    async fn load_script(&self, script: &'static str) -> Result<String, Error> {
+        let command = Command::new("SCRIPT").arg("LOAD").arg(script);
+
+        match self.execute_one(command).await? {
+            Value::Bulk(Some(sha)) => {
+                let sha = String::from_utf8_lossy(&sha).into_owned();
+                self.scripts.lock().unwrap().insert(script, sha.clone());
+                Ok(sha)
+            }
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+}
+
+fn expect_ok(value: Value) -> Result<(), Error> {
+    match value {
+        Value::Simple(s) if s == "OK" => Ok(()),
+        value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+    }
+}
+
+// Prefix of the keys used internally, such as tags.
+const INTERNAL_PREFIX: &str = "__core:";
+
+const TAG_CHUNK: usize = 100;
+
+const SCAN_COUNT: usize = 100;
+
+// Scripts run atomically by the server. Writes other than
+// `compare_and_set` delete the version of the entries they change, so the
+// next `get_versioned` gives them a new one. KEYS[2] of the conditional
+// writes is the version of the entry.
+pub(crate) const DELETE_IF_EQUALS: &str = "
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('DEL', KEYS[2])
+        return redis.call('DEL', KEYS[1])
+    end
+    return 0";
+pub(crate) const EXPIRE_IF_EQUALS: &str = "
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('PEXPIRE', KEYS[2], ARGV[2])
+        return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    end
+    return 0";
+// ARGV are the value and the TTL in milliseconds, or 0. Returns whether the
+// value was stored.
+pub(crate) const SET_IF_ABSENT: &str = "
+    local stored
+    if ARGV[2] == '0' then
+        stored = redis.call('SET', KEYS[1], ARGV[1], 'NX')
+    else
+        stored = redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2])
+    end
+    if not stored then
+        return 0
+    end
+    redis.call('DEL', KEYS[2])
+    return 1";
+// ARGV are the delta and the TTL in milliseconds set if the counter is
+// created, or 0.
+pub(crate) const INCR_BY: &str = "
+    local created = redis.call('EXISTS', KEYS[1]) == 0
+    local n = redis.call('INCRBY', KEYS[1], ARGV[1])
+    if created and ARGV[2] ~= '0' then
+        redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    end
+    redis.call('DEL', KEYS[2])
+    return n";
+// Versions are taken from the counter in KEYS[3], and expire with their
+// entry. Returns the value and its version.
+pub(crate) const GET_VERSIONED: &str = "
+    local value = redis.call('GET', KEYS[1])
+    if not value then
+        return false
+    end
+    local version = redis.call('GET', KEYS[2])
+    if not version then
+        version = redis.call('INCR', KEYS[3])
+        local ttl = redis.call('PTTL', KEYS[1])
+        if ttl > 0 then
+            redis.call('SET', KEYS[2], version, 'PX', ttl)
+        else
+            redis.call('SET', KEYS[2], version)
+        end
+    end
+    return {value, tonumber(version)}";
+// KEYS are the entry, its version, the counter of versions and the set of
+// its tags. ARGV are the expected version, or 0 if the entry must not exist,
+// the value and the TTL in milliseconds, or 0. Returns whether the value was
+// stored, and the new version or else the current one, or 0.
+pub(crate) const COMPARE_AND_SET: &str = "
+    local exists = redis.call('EXISTS', KEYS[1]) == 1
+    local current = exists and tonumber(redis.call('GET', KEYS[2])) or 0
+    if exists ~= (ARGV[1] ~= '0') or (exists and current ~= tonumber(ARGV[1])) then
+        return {0, current}
+    end
+    local version = redis.call('INCR', KEYS[3])
+    if ARGV[3] == '0' then
+        redis.call('SET', KEYS[1], ARGV[2])
+        redis.call('SET', KEYS[2], version)
+    else
+        redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+        redis.call('SET', KEYS[2], version, 'PX', ARGV[3])
+    end
+    redis.call('DEL', KEYS[4])
+    return {1, version}";
+// Tagging scripts. Keys come in triples of an entry, the set of its tags and
+// its version. ARGV[1] of SET_ENTRIES is the TTL in milliseconds, or 0, and
+// the values follow.
+pub(crate) const SET_ENTRIES: &str = "
+    for i = 1, #KEYS, 3 do
+        local value = ARGV[(i + 5) / 3]
+        if ARGV[1] == '0' then
+            redis.call('SET', KEYS[i], value)
+        else
+            redis.call('SET', KEYS[i], value, 'PX', ARGV[1])
+        end
+        redis.call('DEL', KEYS[i + 1], KEYS[i + 2])
+    end
+    return redis.status_reply('OK')";
+// KEYS are the entry, the set of its tags, its version and the sets of the
+// new tags. ARGV are the value and the TTL in milliseconds, or 0.
+pub(crate) const SET_TAGGED: &str = "
+    for _, tag in ipairs(redis.call('SMEMBERS', KEYS[2])) do
+        redis.call('SREM', tag, KEYS[1])
+    end
+    redis.call('DEL', KEYS[2], KEYS[3])
+    local ttl = tonumber(ARGV[2])
+    if ttl == 0 then
+        redis.call('SET', KEYS[1], ARGV[1])
+    else
+        redis.call('SET', KEYS[1], ARGV[1], 'PX', ttl)
+    end
+    for i = 4, #KEYS do
+        local current = redis.call('PTTL', KEYS[i])
+        redis.call('SADD', KEYS[i], KEYS[1])
+        redis.call('SADD', KEYS[2], KEYS[i])
+        if ttl == 0 then
+            redis.call('PERSIST', KEYS[i])
+        elseif current == -2 or (current >= 0 and current < ttl) then
+            redis.call('PEXPIRE', KEYS[i], ttl)
+        end
+    end
+    if ttl > 0 and #KEYS > 3 then
+        redis.call('PEXPIRE', KEYS[2], ttl)
+    end
+    return redis.status_reply('OK')";
+pub(crate) const DELETE_ENTRIES: &str = "
+    local deleted = 0
+    for i = 1, #KEYS, 3 do
+        deleted = deleted + redis.call('DEL', KEYS[i])
+        redis.call('DEL', KEYS[i + 1], KEYS[i + 2])
+    end
+    return deleted";
+// KEYS[1] is the set of the tag. ARGV are how many keys to pop and the
+// prefixes of the sets of tags and of the versions of the entries. Returns
+// how many keys were popped and how many entries deleted.
+pub(crate) const INVALIDATE_TAG: &str = "
+    local keys = redis.call('SPOP', KEYS[1], ARGV[1])
+    local deleted = 0
+    for _, key in ipairs(keys) do
+        local tags = ARGV[2] .. key
+        if redis.call('SISMEMBER', tags, KEYS[1]) == 1 then
+            deleted = deleted + redis.call('DEL', key)
+            redis.call('DEL', tags, ARGV[3] .. key)
+        end
+    end
+    return {#keys, deleted}";
+
+// Escapes everything but `*`, which means any characters in both syntaxes.
+fn redis_glob(pattern: &str) -> String {
+    let mut glob = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if matches!(c, '?' | '[' | ']' | '\\') {
+            glob.push('\\');
+        }
+        glob.push(c);
+    }
+
+    glob
+}
+
+// Set of the keys of the entries carrying a tag. It expires with the last of
+// them.
+fn tag_key(tag: &str) -> String {
+    format!("{}tag:{}", INTERNAL_PREFIX, tag)
+}
+
+// Version of an entry, which expires with it. It is only set once asked for
+// by `get_versioned`, and deleted by writes other than `compare_and_set`.
+fn version_key(key: &str) -> String {
+    format!("{}version:{}", INTERNAL_PREFIX, key)
+}
+
+// Counter the versions are taken from.
+fn versions_key() -> String {
+    format!("{}versions", INTERNAL_PREFIX)
+}
+
+// Set of the tags of an entry, which expires with it. A key is only deleted
+// through a tag still in this set, so tags are dropped when the entry is
+// replaced.
+fn entry_tags_key(key: &str) -> String {
+    format!("{}entry-tags:{}", INTERNAL_PREFIX, key)
+}
+
+fn ttl_millis(ttl: Duration) -> String {
+    // Redis rejects a zero expiration.
+    ttl.as_millis().max(1).to_string()
+}
+
+#[async_trait]
+impl<K> Cache<K, Vec<u8>> for RedisCache
+where
+    K: Serialize + Sync + Send + 'static,
+{
+    async fn get(&self, k: &K) -> Result<Option<Vec<u8>>, Error> {
+        let key = encode_key(k)?;
+
+        match self.execute_one(Command::new("GET").arg(key)).await? {
+            Value::Bulk(data) => Ok(data),
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    async fn set(&self, k: K, v: Vec<u8>) -> Result<(), Error> {
+        self.set_entries(vec![(encode_key(&k)?, v)], None).await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: Vec<u8>, ttl: Duration) -> Result<(), Error> {
+        self.set_entries(vec![(encode_key(&k)?, v)], Some(ttl))
+            .await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.delete_entries(vec![encode_key(k)?]).await?;
+
+        Ok(())
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        RedisCache::len(self).await
+    }
+
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        if ks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut command = Command::new("MGET");
+        for k in ks {
+            command = command.arg(encode_key(k)?);
+        }
+
+        match self.execute_one(command).await? {
+            Value::Array(Some(values)) if values.len() == ks.len() => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Bulk(data) => Ok(data),
+                    value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+                })
+                .collect(),
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    async fn set_many(&self, items: Vec<(K, Vec<u8>)>) -> Result<(), Error> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let entries = items
+            .into_iter()
+            .map(|(k, v)| Ok((encode_key(&k)?, v)))
+            .collect::<Result<_, Error>>()?;
+
+        self.set_entries(entries, None).await
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        if ks.is_empty() {
+            return Ok(());
+        }
+
+        let keys = ks.iter().map(encode_key).collect::<Result<_, Error>>()?;
+        self.delete_entries(keys).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K> TaggedCache<K, Vec<u8>> for RedisCache
+where
+    K: Serialize + Sync + Send + 'static,
+{
+    // Each tag is a set holding the keys of its entries. The entry and its
+    // tags are written by a single script.
+    async fn set_tagged(
+        &self,
+        k: K,
+        v: Vec<u8>,
+        tags: &[&str],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let key = encode_key(&k)?;
+
+        let reply = self
+            .eval(SET_TAGGED, |mut command| {
+                command = command
+                    .arg((tags.len() + 3).to_string())
+                    .arg(&key)
+                    .arg(entry_tags_key(&key))
+                    .arg(version_key(&key));
+                for tag in tags {
+                    command = command.arg(tag_key(tag));
+                }
+
+                command.arg(&v).arg(ttl.map_or("0".to_string(), ttl_millis))
+            })
+            .await?;
+
+        expect_ok(reply)
+    }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize, Error> {
+        RedisCache::invalidate_tag(self, tag).await
+    }
+}
+
+#[async_trait]
+impl<K> CounterCache<K> for RedisCache
+where
+    K: Serialize + Sync + Send + 'static,
+{
+    // A script increments the counter and sets the TTL if it was just
+    // created, so it cannot expire in between and be recreated without one.
+    // INCRBY keeps the expiration of the key.
+    async fn incr_by(&self, k: &K, delta: i64, ttl: Option<Duration>) -> Result<i64, Error> {
+        let key = encode_key(k)?;
+
+        let reply = self
+            .eval(INCR_BY, |command| {
+                command
+                    .arg("2")
+                    .arg(&key)
+                    .arg(version_key(&key))
+                    .arg(delta.to_string())
+                    .arg(ttl.map_or("0".to_string(), ttl_millis))
+            })
+            .await;
+
+        match reply {
+            Ok(Value::Integer(n)) => Ok(n),
+            Ok(value) => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+            Err(Error::Command(msg)) => Err(counter_error(msg)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn counter_error(msg: String) -> Error {
+    if msg.contains("not an integer") || msg.contains("overflow") {
+        return Error::InvalidCounter;
+    }
+
+    Error::Command(msg)
+}
+
+#[async_trait]
+impl<K> VersionedCache<K, Vec<u8>> for RedisCache
+where
+    K: Serialize + Sync + Send + 'static,
+{
+    async fn get_versioned(&self, k: &K) -> Result<Option<(Vec<u8>, Version)>, Error> {
+        let key = encode_key(k)?;
+        let reply = self
+            .eval(GET_VERSIONED, |command| {
+                command
+                    .arg("3")
+                    .arg(&key)
+                    .arg(version_key(&key))
+                    .arg(versions_key())
+            })
+            .await?;
+
+        match reply {
+            Value::Bulk(None) => Ok(None),
+            Value::Array(Some(reply)) => match <[Value; 2]>::try_from(reply) {
+                Ok([Value::Bulk(Some(data)), Value::Integer(version)]) => {
+                    Ok(Some((data, parse_version(version)?)))
+                }
+                Ok(reply) => Err(Error::Protocol(format!("unexpected reply {:?}", reply))),
+                Err(reply) => Err(Error::Protocol(format!("unexpected reply {:?}", reply))),
+            },
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    async fn compare_and_set(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: Vec<u8>,
+    ) -> Result<Version, Error> {
+        self.compare_and_set_entry(encode_key(&k)?, expected, v, None)
+            .await
+    }
+
+    async fn compare_and_set_with_ttl(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<Version, Error> {
+        self.compare_and_set_entry(encode_key(&k)?, expected, v, Some(ttl))
+            .await
+    }
+}
+
+impl RedisCache {
+    // Like a plain write, the entry is detached from its tags.
+    async fn compare_and_set_entry(
+        &self,
+        key: String,
+        expected: Option<&Version>,
+        v: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<Version, Error> {
+        let expected = expected.map(Version::value);
+        let reply = self
+            .eval(COMPARE_AND_SET, |command| {
+                command
+                    .arg("4")
+                    .arg(&key)
+                    .arg(version_key(&key))
+                    .arg(versions_key())
+                    .arg(entry_tags_key(&key))
+                    .arg(expected.unwrap_or(0).to_string())
+                    .arg(&v)
+                    .arg(ttl.map_or("0".to_string(), ttl_millis))
+            })
+            .await?;
+
+        match reply {
+            Value::Array(Some(reply)) => match reply.as_slice() {
+                [Value::Integer(1), Value::Integer(version)] => parse_version(*version),
+                [Value::Integer(0), Value::Integer(actual)] => Err(Error::Conflict {
+                    expected,
+                    actual: Some(*actual).filter(|v| *v > 0),
+                }),
+                _ => Err(Error::Protocol(format!("unexpected reply {:?}", reply))),
+            },
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+}
+
+fn parse_version(version: i64) -> Result<Version, Error> {
+    Version::new(version).map_err(|_| Error::Protocol(format!("invalid version {}", version)))
+}
+
+#[async_trait]
+impl<K> AtomicCache<K, Vec<u8>> for RedisCache
+where
+    K: Serialize + Sync + Send + 'static,
+{
+    async fn set_if_absent(&self, k: K, v: Vec<u8>, ttl: Option<Duration>) -> Result<bool, Error> {
+        let key = encode_key(&k)?;
+        let reply = self
+            .eval(SET_IF_ABSENT, |command| {
+                command
+                    .arg("2")
+                    .arg(&key)
+                    .arg(version_key(&key))
+                    .arg(&v)
+                    .arg(ttl.map_or("0".to_string(), ttl_millis))
+            })
+            .await?;
+
+        match reply {
+            Value::Integer(stored) => Ok(stored == 1),
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    async fn delete_if_equals(&self, k: &K, v: &Vec<u8>) -> Result<bool, Error> {
+        let key = encode_key(k)?;
+        let reply = self
+            .eval(DELETE_IF_EQUALS, |command| {
+                command
+                    .arg("2")
+                    .arg(&key)
+                    .arg(version_key(&key))
+                    .arg(v.as_slice())
+            })
+            .await?;
+
+        match reply {
+            Value::Integer(deleted) => Ok(deleted == 1),
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+
+    async fn expire_if_equals(&self, k: &K, v: &Vec<u8>, ttl: Duration) -> Result<bool, Error> {
+        let key = encode_key(k)?;
+        let reply = self
+            .eval(EXPIRE_IF_EQUALS, |command| {
+                command
+                    .arg("2")
+                    .arg(&key)
+                    .arg(version_key(&key))
+                    .arg(v.as_slice())
+                    .arg(ttl_millis(ttl))
+            })
+            .await?;
+
+        match reply {
+            Value::Integer(updated) => Ok(updated == 1),
+            value => Err(Error::Protocol(format!("unexpected reply {:?}", value))),
+        }
+    }
+}
+
+#[async_trait]
+impl<K> ScanCache<K> for RedisCache
+where
+    K: DeserializeOwned + Send + 'static,
+{
+    // Keys are fetched with SCAN, a page at a time. As with SCAN, keys set or
+    // deleted while scanning may or may not be returned.
+    fn scan(&self, pattern: &str) -> BoxStream<'_, Result<K, Error>> {
+        let pattern = pattern.to_string();
+        let pages = stream::try_unfold(Some("0".to_string()), move |cursor| {
+            let pattern = pattern.clone();
+
+            async move {
+                match cursor {
+                    Some(cursor) => {
+                        let (next, keys) = self.scan_page(&cursor, &pattern).await?;
+                        Ok(Some((keys, next)))
+                    }
+                    None => Ok(None),
+                }
+            }
+        });
+
+        pages
+            .map_ok(|keys| stream::iter(keys.into_iter().map(|key| decode_key(&key))))
+            .try_flatten()
+            .boxed()
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<usize, Error> {
+        RedisCache::delete_matching(self, pattern).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cache::resp_server::RespServer;
+
+    #[tokio::test]
+    async fn set_get_and_delete() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        let res = cache.get(&"key_1").await;
+        assert!(res.unwrap().is_none());
+
+        cache.set("key_1", b"value".to_vec()).await.unwrap();
+        assert_eq!(cache.get(&"key_1").await.unwrap().unwrap(), b"value");
+
+        cache.delete(&"key_1").await.unwrap();
+        assert!(cache.get(&"key_1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_operations() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        cache
+            .set_many(vec![("a", b"1".to_vec()), ("b", b"2".to_vec())])
+            .await
+            .unwrap();
+
+        let values = cache.get_many(&["a", "b", "c"]).await.unwrap();
+        assert_eq!(values, vec![Some(b"1".to_vec()), Some(b"2".to_vec()), None]);
+
+        cache.delete_many(&["a", "c"]).await.unwrap();
+        let values = cache.get_many(&["a", "b"]).await.unwrap();
+        assert_eq!(values, vec![None, Some(b"2".to_vec())]);
+
+        assert!(cache.get_many(&[] as &[&str]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tags() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        for i in 0..250 {
+            let tags: &[&str] = if i % 2 == 0 {
+                &["even", "all"]
+            } else {
+                &["all"]
+            };
+            cache
+                .set_tagged(i, b"value".to_vec(), tags, Some(Duration::from_secs(60)))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(cache.invalidate_tag("even").await.unwrap(), 125);
+        assert!(cache.get(&0).await.unwrap().is_none());
+        assert!(cache.get(&1).await.unwrap().is_some());
+
+        // Keys already deleted through another tag are not counted.
+        assert_eq!(cache.invalidate_tag("all").await.unwrap(), 125);
+        assert_eq!(cache.invalidate_tag("unknown").await.unwrap(), 0);
+        assert!(cache.is_empty().await.unwrap());
+        assert_eq!(server.size(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tags_of_replaced_and_expired_entries() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+        let ttl = Some(Duration::from_secs(60));
+
+        // Replaced entries lose their tags, like in memory.
+        cache
+            .set_tagged("a", b"1".to_vec(), &["t"], ttl)
+            .await
+            .unwrap();
+        cache.set("a", b"2".to_vec()).await.unwrap();
+        cache
+            .set_tagged("b", b"1".to_vec(), &["t"], ttl)
+            .await
+            .unwrap();
+        cache
+            .set_tagged("b", b"2".to_vec(), &["u"], ttl)
+            .await
+            .unwrap();
+        assert_eq!(cache.invalidate_tag("t").await.unwrap(), 0);
+        assert_eq!(cache.get(&"a").await.unwrap(), Some(b"2".to_vec()));
+        assert_eq!(cache.invalidate_tag("u").await.unwrap(), 1);
+
+        // Deleted and set again without tags.
+        cache
+            .set_tagged("c", b"1".to_vec(), &["t"], ttl)
+            .await
+            .unwrap();
+        cache.delete(&"c").await.unwrap();
+        cache.set("c", b"2".to_vec()).await.unwrap();
+        assert_eq!(cache.invalidate_tag("t").await.unwrap(), 0);
+        cache.delete_many(&["a", "c"]).await.unwrap();
+        assert_eq!(server.size(), 0);
+
+        // Tags expire with the last of their entries.
+        cache
+            .set_tagged("d", b"1".to_vec(), &["t"], Some(Duration::from_secs(10)))
+            .await
+            .unwrap();
+        cache
+            .set_tagged("e", b"1".to_vec(), &["t"], ttl)
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(server.size() > 0);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(server.size(), 0);
+
+        // Unless one of them does not expire.
+        cache
+            .set_tagged("f", b"1".to_vec(), &["t"], ttl)
+            .await
+            .unwrap();
+        cache
+            .set_tagged("g", b"1".to_vec(), &["t"], None)
+            .await
+            .unwrap();
+        // Tag sets are not counted as entries.
+        assert_eq!(cache.len().await.unwrap(), 2);
+        assert!(server.size() > 2);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(cache.invalidate_tag("t").await.unwrap(), 1);
+        assert_eq!(server.size(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counters() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        let ttl = Some(Duration::from_secs(10));
+        assert_eq!(cache.incr_by(&"hits", 3, ttl).await.unwrap(), 3);
+        assert_eq!(cache.incr_by(&"hits", 3, ttl).await.unwrap(), 6);
+        assert_eq!(cache.decr_by(&"hits", 1, None).await.unwrap(), 5);
+        assert_eq!(cache.get(&"hits").await.unwrap().unwrap(), b"5");
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(cache.incr_by(&"hits", 1, None).await.unwrap(), 1);
+
+        // Recreated after expiring, with the TTL again.
+        assert_eq!(cache.incr_by(&"visits", 1, ttl).await.unwrap(), 1);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(cache.incr_by(&"visits", 1, ttl).await.unwrap(), 1);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(cache.get(&"visits").await.unwrap().is_none());
+
+        cache.set("text", b"abc".to_vec()).await.unwrap();
+        assert!(matches!(
+            cache.incr_by(&"text", 1, None).await,
+            Err(Error::InvalidCounter)
+        ));
+        assert!(matches!(
+            cache.incr_by(&"text", 1, ttl).await,
+            Err(Error::InvalidCounter)
+        ));
+
+        server.set_out_of_memory(true);
+        assert!(matches!(
+            cache.incr_by(&"hits", 1, None).await,
+            Err(Error::Capacity(..))
+        ));
+        assert!(matches!(
+            cache.incr_by(&"hits", 1, ttl).await,
+            Err(Error::Capacity(..))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn atomic_operations() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        let ttl = Some(Duration::from_secs(10));
+        assert!(cache
+            .set_if_absent("lock", b"a".to_vec(), ttl)
+            .await
+            .unwrap());
+        assert!(!cache
+            .set_if_absent("lock", b"b".to_vec(), ttl)
+            .await
+            .unwrap());
+
+        assert!(!cache
+            .delete_if_equals(&"lock", &b"b".to_vec())
+            .await
+            .unwrap());
+        assert!(!cache
+            .expire_if_equals(&"lock", &b"b".to_vec(), Duration::from_secs(20))
+            .await
+            .unwrap());
+        assert!(cache
+            .expire_if_equals(&"lock", &b"a".to_vec(), Duration::from_secs(20))
+            .await
+            .unwrap());
+
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(cache.get(&"lock").await.unwrap().unwrap(), b"a");
+        assert!(cache
+            .delete_if_equals(&"lock", &b"a".to_vec())
+            .await
+            .unwrap());
+        assert!(cache.get(&"lock").await.unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn versions() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        assert!(cache.get_versioned(&"key").await.unwrap().is_none());
+        let v1 = cache
+            .compare_and_set(&"key", None, b"1".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(
+            cache.compare_and_set(&"key", None, b"2".to_vec()).await,
+            Err(Error::Conflict {
+                expected: None,
+                actual: Some(_)
+            })
+        ));
+
+        let (value, version) = cache.get_versioned(&"key").await.unwrap().unwrap();
+        assert_eq!((value.as_slice(), &version), (&b"1"[..], &v1));
+        let v2 = cache
+            .compare_and_set(&"key", Some(&v1), b"2".to_vec())
+            .await
+            .unwrap();
+        assert!(v2.value() > v1.value());
+        match cache
+            .compare_and_set(&"key", Some(&v1), b"3".to_vec())
+            .await
+        {
+            Err(Error::Conflict { expected, actual }) => {
+                assert_eq!((expected, actual), (Some(v1.value()), Some(v2.value())));
+            }
+            res => panic!("expected a conflict, got {:?}", res),
+        }
+        // Versions are internal keys.
+        assert_eq!(cache.len().await.unwrap(), 1);
+
+        // Every other write changes the version.
+        let stale = |version: Version| {
+            let cache = cache.clone();
+            async move {
+                cache
+                    .compare_and_set(&"key", Some(&version), b"stale".to_vec())
+                    .await
+                    .is_err()
+            }
+        };
+        let version = |cache: RedisCache| async move {
+            cache.get_versioned(&"key").await.unwrap().unwrap().1
+        };
+
+        let v = version(cache.clone()).await;
+        cache.set("key", b"4".to_vec()).await.unwrap();
+        assert!(stale(v).await);
+        let v = version(cache.clone()).await;
+        cache
+            .set_tagged("key", b"5".to_vec(), &["tag"], None)
+            .await
+            .unwrap();
+        assert!(stale(v).await);
+        let v = version(cache.clone()).await;
+        cache.incr_by(&"key", 1, None).await.unwrap();
+        assert!(stale(v).await);
+
+        // But its TTL.
+        let v = version(cache.clone()).await;
+        assert!(cache
+            .expire_if_equals(&"key", &b"6".to_vec(), Duration::from_secs(60))
+            .await
+            .unwrap());
+        assert_eq!(version(cache.clone()).await, v);
+
+        // Versions expire with their entry.
+        let (_, version) = cache.get_versioned(&"key").await.unwrap().unwrap();
+        cache
+            .compare_and_set_with_ttl(
+                &"key",
+                Some(&version),
+                b"8".to_vec(),
+                Duration::from_secs(10),
+            )
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(cache.get_versioned(&"key").await.unwrap().is_none());
+        assert!(cache
+            .set_if_absent(&"key", b"9".to_vec(), None)
+            .await
+            .unwrap());
+        assert!(cache
+            .compare_and_set(&"key", Some(&version), b"10".to_vec())
+            .await
+            .is_err());
+
+        // Deleted entries lose their version.
+        let (_, version) = cache.get_versioned(&"key").await.unwrap().unwrap();
+        cache.delete(&"key").await.unwrap();
+        cache.set("key", b"11".to_vec()).await.unwrap();
+        assert!(cache
+            .compare_and_set(&"key", Some(&version), b"12".to_vec())
+            .await
+            .is_err());
+        cache.delete(&"key").await.unwrap();
+        cache.invalidate_tag("tag").await.unwrap();
+        // Only the counter of versions is left.
+        assert_eq!(server.size(), 1);
+    }
+
+    #[tokio::test]
+    async fn scan_and_delete_matching() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        for i in 0..150 {
+            cache
+                .set_tagged(format!("user.{}", i), b"user".to_vec(), &["users"], None)
+                .await
+                .unwrap();
+            cache
+                .set(format!("user.{}.posts", i), b"posts".to_vec())
+                .await
+                .unwrap();
+        }
+        cache
+            .set("user?.[1]".to_string(), b"odd".to_vec())
+            .await
+            .unwrap();
+
+        let keys: Vec<String> = ScanCache::<String>::scan(&cache, "user.*")
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(keys.len(), 150);
+        assert!(keys.iter().all(|key| !key.ends_with(".posts")));
+
+        // Tag keys are internal, so they are never listed.
+        let keys: Vec<String> = ScanCache::<String>::scan(&cache, "*")
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(keys.is_empty());
+
+        let keys: Vec<String> = ScanCache::<String>::scan(&cache, "user?.[*]")
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(keys, vec!["user?.[1]"]);
+
+        assert_eq!(cache.delete_matching("user.*.posts").await.unwrap(), 150);
+        assert!(cache
+            .get(&"user.0.posts".to_string())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(cache.get(&"user.0".to_string()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn scan_decodes_keys() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        cache.set(1, b"one".to_vec()).await.unwrap();
+        cache.set(2, b"two".to_vec()).await.unwrap();
+
+        let mut keys: Vec<i32> = ScanCache::<i32>::scan(&cache, "*")
+            .try_collect()
+            .await
+            .unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn serialized_keys() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        cache.set(1, b"one".to_vec()).await.unwrap();
+        cache.set((1, "a"), b"tuple".to_vec()).await.unwrap();
+
+        assert_eq!(server.get(b"1").await.unwrap(), b"one");
+        assert_eq!(server.get(br#"[1,"a"]"#).await.unwrap(), b"tuple");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        cache
+            .set_with_ttl("key", b"value".to_vec(), Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(cache.get(&"key").await.unwrap().is_some());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(cache.get(&"key").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn connection_errors() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+        cache.set("key", b"value".to_vec()).await.unwrap();
+
+        // The server drops the connection, the next call fails and the
+        // following one reconnects.
+        server.disconnect_all();
+        assert!(matches!(cache.get(&"key").await, Err(Error::Connection(_))));
+        assert!(cache.get(&"key").await.unwrap().is_some());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        assert!(matches!(
+            RedisCache::connect(addr).await,
+            Err(Error::Connection(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scripts() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        for i in 0..3 {
+            cache.set("key", vec![i]).await.unwrap();
+        }
+        cache.clone().delete(&"key").await.unwrap();
+        assert_eq!(server.calls("SCRIPT"), 2);
+        assert_eq!(server.calls("EVALSHA"), 4);
+
+        // Loaded again once the server lost them.
+        server.flush_scripts();
+        cache.set("key", b"value".to_vec()).await.unwrap();
+        assert_eq!(server.calls("SCRIPT"), 3);
+        assert_eq!(server.get(b"key").await.unwrap(), b"value");
+    }
+
+    // The fake server only recognizes the scripts, so they are also run
+    // against a real server when REDIS_ADDR is set, for instance to
+    // 127.0.0.1:6379. Keys are prefixed to leave other data alone.
+    #[tokio::test]
+    async fn scripts_on_redis() {
+        let Ok(addr) = std::env::var("REDIS_ADDR") else {
+            return;
+        };
+        let cache = RedisCache::connect(addr).await.unwrap();
+        let prefix = uuid::Uuid::new_v4().to_string();
+        let key = |name: &str| format!("{}:{}", prefix, name);
+        let ttl = Duration::from_secs(60);
+
+        // SET_ENTRIES, DELETE_ENTRIES
+        cache
+            .set_many(vec![(key("a"), b"1".to_vec()), (key("b"), b"2".to_vec())])
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl(key("c"), b"3".to_vec(), ttl)
+            .await
+            .unwrap();
+        assert_eq!(cache.get(&key("c")).await.unwrap().unwrap(), b"3");
+        cache.delete_many(&[key("a"), key("c")]).await.unwrap();
+        assert_eq!(
+            cache
+                .get_many(&[key("a"), key("b"), key("c")])
+                .await
+                .unwrap(),
+            vec![None, Some(b"2".to_vec()), None]
+        );
+
+        // SET_TAGGED, INVALIDATE_TAG
+        let tag = key("tag");
+        cache
+            .set_tagged(key("t1"), b"1".to_vec(), &[&tag], Some(ttl))
+            .await
+            .unwrap();
+        cache
+            .set_tagged(key("t2"), b"2".to_vec(), &[&tag], None)
+            .await
+            .unwrap();
+        cache.set(key("t2"), b"replaced".to_vec()).await.unwrap();
+        assert_eq!(cache.invalidate_tag(&tag).await.unwrap(), 1);
+        assert!(cache.get(&key("t1")).await.unwrap().is_none());
+        assert!(cache.get(&key("t2")).await.unwrap().is_some());
+
+        // INCR_BY
+        assert_eq!(cache.incr_by(&key("n"), 2, Some(ttl)).await.unwrap(), 2);
+        assert_eq!(cache.incr_by(&key("n"), 2, Some(ttl)).await.unwrap(), 4);
+        assert_eq!(cache.incr_by(&key("b"), 1, Some(ttl)).await.unwrap(), 3);
+        assert!(matches!(
+            cache.incr_by(&key("t2"), 1, Some(ttl)).await,
+            Err(Error::InvalidCounter)
+        ));
+
+        // SET_IF_ABSENT, DELETE_IF_EQUALS, EXPIRE_IF_EQUALS
+        assert!(cache
+            .set_if_absent(key("lock"), b"me".to_vec(), Some(ttl))
+            .await
+            .unwrap());
+        assert!(!cache
+            .set_if_absent(key("lock"), b"other".to_vec(), None)
+            .await
+            .unwrap());
+        assert!(!cache
+            .expire_if_equals(&key("lock"), &b"other".to_vec(), ttl)
+            .await
+            .unwrap());
+        assert!(cache
+            .expire_if_equals(&key("lock"), &b"me".to_vec(), ttl)
+            .await
+            .unwrap());
+        assert!(!cache
+            .delete_if_equals(&key("lock"), &b"other".to_vec())
+            .await
+            .unwrap());
+        assert!(cache
+            .delete_if_equals(&key("lock"), &b"me".to_vec())
+            .await
+            .unwrap());
+
+        // GET_VERSIONED, COMPARE_AND_SET
+        let v1 = cache
+            .compare_and_set_with_ttl(key("v"), None, b"1".to_vec(), ttl)
+            .await
+            .unwrap();
+        let (value, version) = cache.get_versioned(&key("v")).await.unwrap().unwrap();
+        assert_eq!((value, &version), (b"1".to_vec(), &v1));
+        let v2 = cache
+            .compare_and_set(key("v"), Some(&v1), b"2".to_vec())
+            .await
+            .unwrap();
+        assert!(v2.value() > v1.value());
+        assert!(matches!(
+            cache
+                .compare_and_set(key("v"), Some(&v1), b"3".to_vec())
+                .await,
+            Err(Error::Conflict { .. })
+        ));
+        cache.set(key("v"), b"4".to_vec()).await.unwrap();
+        assert!(matches!(
+            cache
+                .compare_and_set(key("v"), Some(&v2), b"5".to_vec())
+                .await,
+            Err(Error::Conflict { .. })
+        ));
+
+        cache
+            .delete_matching(&format!("{}:*", prefix))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_errors() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr()).await.unwrap();
+
+        let res = cache.execute_one(Command::new("UNKNOWN")).await;
+        assert!(matches!(res, Err(Error::Command(_))));
+
+        // The connection is still usable.
+        cache.set("key", b"value".to_vec()).await.unwrap();
+
+        // Empty commands are rejected too.
+        let stream = TcpStream::connect(server.addr()).await.unwrap();
+        let mut stream = BufStream::new(stream);
+        stream.write_all(b"*0\r\n").await.unwrap();
+        stream.flush().await.unwrap();
+        assert!(matches!(
+            read_value(&mut stream).await.unwrap(),
+            Value::Error(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let server = RespServer::start().await;
+        let cache = RedisCache::connect(server.addr())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50));
+        cache.set("key", b"value".to_vec()).await.unwrap();
+
+        // A server accepting connections but never replying.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepting = tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let cache = RedisCache::connect(addr)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            cache.get(&"key").await,
+            Err(Error::Timeout(timeout, _)) if timeout == Duration::from_millis(50)
+        ));
+        assert!(cache.connection.lock().await.is_none());
+
+        // Calls cancelled by the caller drop the connection too, as a reply
+        // could still be on its way.
+        let cache = RedisCache::connect(cache.addr.clone()).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(50), cache.get(&"key")).await;
+        assert!(res.is_err());
+        assert!(cache.connection.lock().await.is_none());
+
+        accepting.abort();
+    }
+}
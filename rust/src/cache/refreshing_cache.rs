@@ -0,0 +1,536 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::cache::{
+    expiration, Cache, Clock, Error, InMemCache, LoadingCache, Policy, VersionedCache,
+};
+
+// Loader loads the values of a `RefreshingCache`. It returns `None` when the
+// value does not exist.
+#[async_trait]
+pub trait Loader<K, V>: Sync + Send {
+    type Error: StdError + Sync + Send + 'static;
+
+    async fn load(&self, k: &K) -> Result<Option<V>, Self::Error>;
+}
+
+#[async_trait]
+impl<K, V, E, F, Fut> Loader<K, V> for F
+where
+    K: Clone + Sync + Send + 'static,
+    E: StdError + Sync + Send + 'static,
+    F: Fn(K) -> Fut + Sync + Send,
+    Fut: Future<Output = Result<Option<V>, E>> + Send,
+{
+    type Error = E;
+
+    async fn load(&self, k: &K) -> Result<Option<V>, E> {
+        self(k.clone()).await
+    }
+}
+
+#[derive(Clone)]
+struct Stamped<V> {
+    value: V,
+    fresh_until: Option<DateTime<Utc>>,
+}
+
+type Stamps<K, V> = InMemCache<K, Stamped<V>>;
+
+// RefreshingCache reads through a registered loader and keeps hot entries
+// fresh without making readers wait.
+//
+// An entry is fresh for its TTL. Once stale it is still served for a grace
+// period while a background task reloads it, only one per key at a time.
+// With refresh-ahead, the reload starts a bit before the entry becomes stale.
+// Entries not read during the grace period expire and are loaded again by the
+// next read. Expired entries are only removed from memory when read, by the
+// reaper or, if the cache is bounded, when evicted to make room.
+pub struct RefreshingCache<K, V, L> {
+    cache: LoadingCache<K, Stamped<V>, Stamps<K, V>>,
+    loader: Arc<L>,
+    ttl: Duration,
+    grace: Duration,
+    refresh_ahead: Duration,
+    refreshing: Arc<Mutex<HashSet<K>>>,
+}
+
+impl<K, V, L> RefreshingCache<K, V, L>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + 'static,
+    V: Clone + Sync + Send + 'static,
+    L: Loader<K, V> + 'static,
+{
+    // Entries are fresh for `ttl`, then served stale for `grace`.
+    pub fn new(loader: L, ttl: Duration, grace: Duration) -> RefreshingCache<K, V, L> {
+        RefreshingCache::with_cache(loader, ttl, grace, InMemCache::new())
+    }
+
+    // Like `new`, holding at most `capacity` entries, evicted by `policy`.
+    pub fn with_capacity(
+        loader: L,
+        ttl: Duration,
+        grace: Duration,
+        capacity: usize,
+        policy: Policy<K>,
+    ) -> RefreshingCache<K, V, L> {
+        RefreshingCache::with_cache(
+            loader,
+            ttl,
+            grace,
+            InMemCache::with_capacity(capacity, policy),
+        )
+    }
+
+    fn with_cache(
+        loader: L,
+        ttl: Duration,
+        grace: Duration,
+        cache: Stamps<K, V>,
+    ) -> RefreshingCache<K, V, L> {
+        RefreshingCache {
+            cache: LoadingCache::new(cache.with_default_ttl(ttl + grace)),
+            loader: Arc::new(loader),
+            ttl,
+            grace,
+            refresh_ahead: Duration::ZERO,
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    // Reloads entries read less than `before` ahead of becoming stale.
+    pub fn with_refresh_ahead(mut self, before: Duration) -> RefreshingCache<K, V, L> {
+        self.refresh_ahead = before;
+        self
+    }
+
+    pub fn with_clock<C>(mut self, clock: C) -> RefreshingCache<K, V, L>
+    where
+        C: Clock + 'static,
+    {
+        self.cache = LoadingCache::new(self.cache.inner().clone().with_clock(clock));
+        self
+    }
+
+    // Spawns a task that removes expired entries every `interval`, stopping
+    // once the cache is dropped.
+    pub fn start_reaper(&self, interval: Duration) -> JoinHandle<()> {
+        self.cache.inner().start_reaper(interval)
+    }
+
+    fn stamp(&self, value: V, ttl: Duration) -> Stamped<V> {
+        Stamped {
+            value,
+            fresh_until: expiration(self.cache.inner().now(), ttl),
+        }
+    }
+
+    fn needs_refresh(&self, stamped: &Stamped<V>) -> bool {
+        match stamped.fresh_until {
+            Some(fresh_until) => {
+                let now = self.cache.inner().now();
+                expiration(now, self.refresh_ahead).is_none_or(|at| at >= fresh_until)
+            }
+            None => false,
+        }
+    }
+
+    // Reloads the entry in a background task, unless one is already running.
+    fn refresh(&self, k: &K) {
+        if !self.refreshing.lock().unwrap().insert(k.clone()) {
+            return;
+        }
+
+        let cache = self.clone();
+        let k = k.clone();
+
+        tokio::spawn(async move {
+            // Released even if the loader panics.
+            let _refreshing = Refreshing {
+                keys: cache.refreshing.clone(),
+                key: k.clone(),
+            };
+
+            cache.reload(&k).await;
+        });
+    }
+
+    // A failed reload leaves the stale entry in place until its grace
+    // period ends, and is retried by the next read. The result is dropped if
+    // the entry was set or deleted while loading.
+    async fn reload(&self, k: &K) {
+        let inner = self.cache.inner();
+        let version = match inner.get_versioned(k).await {
+            Ok(versioned) => versioned.map(|(_, version)| version),
+            Err(_) => return,
+        };
+
+        match (self.loader.load(k).await, version) {
+            (Ok(Some(v)), version) => {
+                let stamped = self.stamp(v, self.ttl);
+                let _ = inner
+                    .compare_and_set(k.clone(), version.as_ref(), stamped)
+                    .await;
+            }
+            (Ok(None), Some(version)) => {
+                inner.delete_if_version(k, &version).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Key being refreshed, removed from the set once dropped.
+struct Refreshing<K: Eq + Hash> {
+    keys: Arc<Mutex<HashSet<K>>>,
+    key: K,
+}
+
+impl<K: Eq + Hash> Drop for Refreshing<K> {
+    fn drop(&mut self) {
+        self.keys.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl<K, V, L> Clone for RefreshingCache<K, V, L>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        RefreshingCache {
+            cache: self.cache.clone(),
+            loader: self.loader.clone(),
+            ttl: self.ttl,
+            grace: self.grace,
+            refresh_ahead: self.refresh_ahead,
+            refreshing: self.refreshing.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V, L> Cache<K, V> for RefreshingCache<K, V, L>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + 'static,
+    V: Clone + Sync + Send + 'static,
+    L: Loader<K, V> + 'static,
+{
+    // Loads the value on a miss, or returns the cached one, refreshing it in
+    // the background if needed.
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        let stamped = self
+            .cache
+            .get_or_load(k.clone(), || async {
+                let v = self.loader.load(k).await?;
+                Ok::<_, L::Error>(v.map(|v| self.stamp(v, self.ttl)))
+            })
+            .await?;
+
+        match stamped {
+            Some(stamped) => {
+                if self.needs_refresh(&stamped) {
+                    self.refresh(k);
+                }
+
+                Ok(Some(stamped.value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        let stamped = self.stamp(v, self.ttl);
+
+        self.cache.set(k, stamped).await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        let stamped = self.stamp(v, ttl);
+
+        self.cache.set_with_ttl(k, stamped, ttl + self.grace).await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.cache.delete(k).await
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        self.cache.len().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Error as IoError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::cache::ManualClock;
+
+    #[derive(Default)]
+    struct Source {
+        value: Mutex<Option<String>>,
+        failing: Mutex<bool>,
+        panicking: Mutex<bool>,
+        // Held by tests to keep loads waiting.
+        paused: tokio::sync::Mutex<()>,
+        calls: AtomicUsize,
+    }
+
+    impl Source {
+        fn set(&self, value: &str) {
+            *self.value.lock().unwrap() = Some(value.to_string());
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl Loader<&'static str, String> for Arc<Source> {
+        type Error = IoError;
+
+        async fn load(&self, _k: &&'static str) -> Result<Option<String>, IoError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            drop(self.paused.lock().await);
+
+            if *self.panicking.lock().unwrap() {
+                panic!("loader panicked");
+            }
+            if *self.failing.lock().unwrap() {
+                return Err(IoError::other("source is down"));
+            }
+
+            Ok(self.value.lock().unwrap().clone())
+        }
+    }
+
+    fn refreshing(
+        source: &Arc<Source>,
+        clock: &ManualClock,
+    ) -> RefreshingCache<&'static str, String, Arc<Source>> {
+        RefreshingCache::new(
+            source.clone(),
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+        )
+        .with_clock(clock.clone())
+    }
+
+    // Lets background refreshes run.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn loads_on_miss() {
+        let source = Arc::new(Source::default());
+        let cache = refreshing(&source, &ManualClock::default());
+
+        assert!(cache.get(&"missing").await.unwrap().is_none());
+
+        source.set("v1");
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        assert_eq!(source.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_while_revalidate() {
+        let source = Arc::new(Source::default());
+        let clock = ManualClock::default();
+        let cache = refreshing(&source, &clock);
+
+        source.set("v1");
+        cache.get(&"key").await.unwrap();
+        source.set("v2");
+
+        // Stale values are served while a single refresh runs.
+        clock.advance(Duration::from_secs(12));
+        for _ in 0..5 {
+            assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        }
+
+        settle().await;
+        assert_eq!(source.calls(), 2);
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v2");
+
+        // After the grace period, readers wait for the load.
+        source.set("v3");
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v3");
+        assert_eq!(source.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn refresh_ahead() {
+        let source = Arc::new(Source::default());
+        let clock = ManualClock::default();
+        let cache = refreshing(&source, &clock).with_refresh_ahead(Duration::from_secs(3));
+
+        source.set("v1");
+        cache.get(&"key").await.unwrap();
+        source.set("v2");
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        settle().await;
+        assert_eq!(source.calls(), 1);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        settle().await;
+        assert_eq!(source.calls(), 2);
+
+        // Refreshed before becoming stale.
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v2");
+    }
+
+    #[tokio::test]
+    async fn failed_refresh() {
+        let source = Arc::new(Source::default());
+        let clock = ManualClock::default();
+        let cache = refreshing(&source, &clock);
+
+        source.set("v1");
+        cache.get(&"key").await.unwrap();
+        *source.failing.lock().unwrap() = true;
+
+        clock.advance(Duration::from_secs(12));
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        settle().await;
+
+        // Retried on the next read, still serving the stale value.
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        settle().await;
+        assert_eq!(source.calls(), 3);
+
+        clock.advance(Duration::from_secs(3));
+        assert!(matches!(cache.get(&"key").await, Err(Error::Loading(_))));
+    }
+
+    #[tokio::test]
+    async fn writes_during_refresh() {
+        let source = Arc::new(Source::default());
+        let clock = ManualClock::default();
+        let cache = refreshing(&source, &clock);
+
+        source.set("v1");
+        cache.get(&"key").await.unwrap();
+        source.set("v2");
+
+        // Set while reloading: the reloaded value is dropped.
+        let paused = source.paused.lock().await;
+        clock.advance(Duration::from_secs(12));
+        cache.get(&"key").await.unwrap();
+        settle().await;
+        cache.set("key", "mine".to_string()).await.unwrap();
+        drop(paused);
+        settle().await;
+        assert_eq!(source.calls(), 2);
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "mine");
+
+        // Deleted while reloading: not brought back.
+        let paused = source.paused.lock().await;
+        clock.advance(Duration::from_secs(12));
+        cache.get(&"key").await.unwrap();
+        settle().await;
+        cache.delete(&"key").await.unwrap();
+        drop(paused);
+        settle().await;
+        assert_eq!(source.calls(), 3);
+        assert!(cache.cache.inner().get(&"key").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn panicking_refresh() {
+        let source = Arc::new(Source::default());
+        let clock = ManualClock::default();
+        let cache = refreshing(&source, &clock);
+
+        source.set("v1");
+        cache.get(&"key").await.unwrap();
+        source.set("v2");
+        *source.panicking.lock().unwrap() = true;
+
+        clock.advance(Duration::from_secs(12));
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        settle().await;
+
+        // The key is not left marked as refreshing.
+        *source.panicking.lock().unwrap() = false;
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v1");
+        settle().await;
+        assert_eq!(source.calls(), 3);
+        assert_eq!(cache.get(&"key").await.unwrap().unwrap(), "v2");
+    }
+
+    #[tokio::test]
+    async fn deleted_from_source() {
+        let source = Arc::new(Source::default());
+        let clock = ManualClock::default();
+        let cache = refreshing(&source, &clock);
+
+        source.set("v1");
+        cache.get(&"key").await.unwrap();
+        *source.value.lock().unwrap() = None;
+
+        clock.advance(Duration::from_secs(12));
+        cache.get(&"key").await.unwrap();
+        settle().await;
+        assert!(cache.get(&"key").await.unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bounded_and_reaped() {
+        let clock = ManualClock::default();
+        let cache = RefreshingCache::with_capacity(
+            |k: i32| async move { Ok::<_, IoError>(Some(k * 2)) },
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            2,
+            Policy::Lru,
+        )
+        .with_clock(clock.clone());
+
+        for k in 0..3 {
+            cache.get(&k).await.unwrap();
+        }
+        assert_eq!(cache.len().await.unwrap(), 2);
+        assert_eq!(cache.cache.inner().stats().await.evictions, 1);
+
+        // Entries not read after their grace period are removed.
+        let _reaper = cache.start_reaper(Duration::from_secs(30));
+        clock.advance(Duration::from_secs(15));
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(cache.cache.inner().stats().await.evictions, 3);
+    }
+
+    #[tokio::test]
+    async fn closure_loader() {
+        let cache = RefreshingCache::new(
+            |k: i32| async move { Ok::<_, IoError>(Some(k * 2)) },
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(cache.get(&21).await.unwrap(), Some(42));
+
+        cache.set(1, 100).await.unwrap();
+        assert_eq!(cache.get(&1).await.unwrap(), Some(100));
+    }
+}
@@ -0,0 +1,247 @@
+use futures::future::BoxFuture;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::cache::{Error, ServerError};
+
+// Longest bulk string and array accepted, as in Redis.
+const MAX_LEN: i64 = 512 * 1024 * 1024;
+
+// Values preallocated for an array, which may announce more than it holds.
+const MAX_PREALLOCATED: usize = 1024;
+
+// Value of the REdis Serialization Protocol (RESP2).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Value>>),
+}
+
+impl Value {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => {
+                buf.push(b'+');
+                buf.extend_from_slice(s.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Error(s) => {
+                buf.push(b'-');
+                buf.extend_from_slice(s.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Integer(i) => {
+                buf.extend_from_slice(format!(":{}\r\n", i).as_bytes());
+            }
+            Value::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+            Value::Bulk(Some(data)) => {
+                buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Array(None) => buf.extend_from_slice(b"*-1\r\n"),
+            Value::Array(Some(values)) => {
+                buf.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(buf);
+                }
+            }
+        }
+    }
+
+    // Turns error replies into errors.
+    pub(crate) fn into_result(self) -> Result<Value, Error> {
+        match self {
+            // Sent when writing past the memory limit of the server.
+            Value::Error(msg) if msg.starts_with("OOM ") => Err(Error::Capacity(
+                "server is out of memory".to_string(),
+                Some(ServerError(msg)),
+            )),
+            Value::Error(msg) => Err(Error::Command(msg)),
+            value => Ok(value),
+        }
+    }
+}
+
+// Command is sent as an array of bulk strings.
+#[derive(Debug, Clone)]
+pub(crate) struct Command {
+    args: Vec<Vec<u8>>,
+}
+
+impl Command {
+    pub(crate) fn new(name: &str) -> Command {
+        Command {
+            args: vec![name.as_bytes().to_vec()],
+        }
+    }
+
+    pub(crate) fn arg<A: AsRef<[u8]>>(mut self, arg: A) -> Command {
+        self.args.push(arg.as_ref().to_vec());
+        self
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let args = self.args.iter().cloned().map(Some).map(Value::Bulk);
+        Value::Array(Some(args.collect())).encode(buf);
+    }
+}
+
+pub(crate) fn read_value<R>(r: &mut R) -> BoxFuture<'_, Result<Value, Error>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    Box::pin(async move {
+        let line = read_line(r).await?;
+        let (kind, rest) = match line.split_first() {
+            Some((kind, rest)) => (*kind, String::from_utf8_lossy(rest).into_owned()),
+            None => return Err(Error::Protocol("empty line".to_string())),
+        };
+
+        match kind {
+            b'+' => Ok(Value::Simple(rest)),
+            b'-' => Ok(Value::Error(rest)),
+            b':' => Ok(Value::Integer(parse_int(&rest)?)),
+            b'$' => {
+                let len = parse_len(&rest)?;
+                if len < 0 {
+                    return Ok(Value::Bulk(None));
+                }
+
+                let mut data = vec![0; len as usize + 2];
+                r.read_exact(&mut data).await.map_err(Error::Connection)?;
+                if !data.ends_with(b"\r\n") {
+                    return Err(Error::Protocol("unterminated bulk string".to_string()));
+                }
+                data.truncate(len as usize);
+
+                Ok(Value::Bulk(Some(data)))
+            }
+            b'*' => {
+                let len = parse_len(&rest)?;
+                if len < 0 {
+                    return Ok(Value::Array(None));
+                }
+
+                let mut values = Vec::with_capacity((len as usize).min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    values.push(read_value(r).await?);
+                }
+
+                Ok(Value::Array(Some(values)))
+            }
+            kind => Err(Error::Protocol(format!(
+                "unknown value type {:?}",
+                kind as char
+            ))),
+        }
+    })
+}
+
+async fn read_line<R>(r: &mut R) -> Result<Vec<u8>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    r.read_until(b'\n', &mut line)
+        .await
+        .map_err(Error::Connection)?;
+
+    if line.is_empty() {
+        return Err(Error::Connection(IoError::new(
+            IoErrorKind::UnexpectedEof,
+            "connection closed",
+        )));
+    }
+
+    if !line.ends_with(b"\r\n") {
+        return Err(Error::Protocol("unterminated line".to_string()));
+    }
+    line.truncate(line.len() - 2);
+
+    Ok(line)
+}
+
+fn parse_int(s: &str) -> Result<i64, Error> {
+    s.parse()
+        .map_err(|_| Error::Protocol(format!("invalid integer {:?}", s)))
+}
+
+fn parse_len(s: &str) -> Result<i64, Error> {
+    match parse_int(s)? {
+        len if len > MAX_LEN => Err(Error::Protocol(format!("length {} too large", len))),
+        len => Ok(len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn encode_and_read() {
+        let value = Value::Array(Some(vec![
+            Value::Simple("OK".to_string()),
+            Value::Error("ERR wrong".to_string()),
+            Value::Integer(-42),
+            Value::Bulk(Some(b"hello\r\nworld".to_vec())),
+            Value::Bulk(None),
+            Value::Array(None),
+            Value::Array(Some(Vec::new())),
+        ]));
+
+        let mut buf = Vec::new();
+        value.encode(&mut buf);
+
+        let mut reader = BufReader::new(buf.as_slice());
+        assert_eq!(read_value(&mut reader).await.unwrap(), value);
+    }
+
+    #[tokio::test]
+    async fn command() {
+        let mut buf = Vec::new();
+        Command::new("GET").arg("key").encode(&mut buf);
+
+        assert_eq!(buf, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+    }
+
+    #[test]
+    fn error_replies() {
+        assert!(matches!(
+            Value::Error("ERR unknown command".to_string()).into_result(),
+            Err(Error::Command(_))
+        ));
+        assert!(matches!(
+            Value::Error("OOM command not allowed when used memory > 'maxmemory'".to_string())
+                .into_result(),
+            Err(Error::Capacity(_, Some(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn too_large() {
+        for data in [&b"$536870913\r\n"[..], &b"*536870913\r\n"[..]] {
+            let mut reader = BufReader::new(data);
+
+            assert!(matches!(
+                read_value(&mut reader).await,
+                Err(Error::Protocol(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_connection() {
+        let mut reader = BufReader::new(&b"$5\r\nhel"[..]);
+
+        assert!(matches!(
+            read_value(&mut reader).await,
+            Err(Error::Connection(_))
+        ));
+    }
+}
@@ -0,0 +1,631 @@
+// In-process stand-in for a Redis server, used by the tests of the RESP
+// backend. It implements only the commands the backend sends.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWriteExt, BufStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+use crate::cache::redis_cache::{
+    COMPARE_AND_SET, DELETE_ENTRIES, DELETE_IF_EQUALS, EXPIRE_IF_EQUALS, GET_VERSIONED, INCR_BY,
+    INVALIDATE_TAG, SET_ENTRIES, SET_IF_ABSENT, SET_TAGGED,
+};
+use crate::cache::resp::{read_value, Value};
+
+enum Stored {
+    String(Vec<u8>),
+    Set(HashSet<Vec<u8>>),
+}
+
+struct Item {
+    value: Stored,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    data: Mutex<HashMap<Vec<u8>, Item>>,
+    // Loaded scripts by digest.
+    scripts: Mutex<HashMap<String, Vec<u8>>>,
+    // Number of commands received by name.
+    calls: Mutex<HashMap<String, usize>>,
+    // Writes are rejected like by a server past its memory limit.
+    out_of_memory: AtomicBool,
+}
+
+pub(crate) struct RespServer {
+    addr: String,
+    state: Arc<State>,
+    disconnect: watch::Sender<u64>,
+    listener: JoinHandle<()>,
+}
+
+impl RespServer {
+    pub(crate) async fn start() -> RespServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(State::default());
+        let (disconnect, disconnected) = watch::channel(0);
+
+        let listener = {
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, state.clone(), disconnected.clone()));
+                }
+            })
+        };
+
+        RespServer {
+            addr,
+            state,
+            disconnect,
+            listener,
+        }
+    }
+
+    pub(crate) fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub(crate) async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut data = self.state.data.lock().unwrap();
+        string(&mut data, key).ok().flatten()
+    }
+
+    // Number of live keys, internal ones included.
+    pub(crate) fn size(&self) -> usize {
+        let mut data = self.state.data.lock().unwrap();
+        let keys: Vec<Vec<u8>> = data.keys().cloned().collect();
+
+        keys.iter()
+            .filter(|key| live(&mut data, key).is_some())
+            .count()
+    }
+
+    pub(crate) fn set_out_of_memory(&self, out_of_memory: bool) {
+        self.state
+            .out_of_memory
+            .store(out_of_memory, Ordering::SeqCst);
+    }
+
+    pub(crate) fn calls(&self, name: &str) -> usize {
+        let calls = self.state.calls.lock().unwrap();
+        calls.get(name).copied().unwrap_or(0)
+    }
+
+    // Forgets the loaded scripts, like SCRIPT FLUSH or a restart.
+    pub(crate) fn flush_scripts(&self) {
+        self.state.scripts.lock().unwrap().clear();
+    }
+
+    // Closes every open connection.
+    pub(crate) fn disconnect_all(&self) {
+        self.disconnect.send_modify(|generation| *generation += 1);
+    }
+}
+
+impl Drop for RespServer {
+    fn drop(&mut self) {
+        self.listener.abort();
+        self.disconnect_all();
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<State>, mut disconnect: watch::Receiver<u64>) {
+    let _ = stream.set_nodelay(true);
+    let mut stream = BufStream::new(stream);
+    disconnect.borrow_and_update();
+
+    loop {
+        let value = tokio::select! {
+            biased;
+            _ = disconnect.changed() => return,
+            value = read_value(&mut stream) => value,
+        };
+
+        let args = match value {
+            Ok(Value::Array(Some(values))) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Bulk(Some(arg)) => arg,
+                    _ => Vec::new(),
+                })
+                .collect::<Vec<_>>(),
+            _ => return,
+        };
+
+        let mut buf = Vec::new();
+        execute(&state, args).encode(&mut buf);
+
+        if stream.write_all(&buf).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn live<'a>(data: &'a mut HashMap<Vec<u8>, Item>, key: &[u8]) -> Option<&'a mut Item> {
+    if matches!(data.get(key), Some(item) if item.expires_at.is_some_and(|at| at <= Instant::now()))
+    {
+        data.remove(key);
+    }
+
+    data.get_mut(key)
+}
+
+fn string(data: &mut HashMap<Vec<u8>, Item>, key: &[u8]) -> Result<Option<Vec<u8>>, Value> {
+    match live(data, key).map(|item| &item.value) {
+        Some(Stored::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+fn ok() -> Value {
+    Value::Simple("OK".to_string())
+}
+
+fn err(msg: &str) -> Value {
+    Value::Error(format!("ERR {}", msg))
+}
+
+fn wrong_type() -> Value {
+    Value::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+}
+
+fn parse<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    String::from_utf8_lossy(arg).parse().ok()
+}
+
+fn execute(state: &State, args: Vec<Vec<u8>>) -> Value {
+    let mut data = state.data.lock().unwrap();
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (String::from_utf8_lossy(name).to_uppercase(), args),
+        None => return err("empty command"),
+    };
+    *state.calls.lock().unwrap().entry(name.clone()).or_default() += 1;
+
+    if state.out_of_memory.load(Ordering::SeqCst)
+        && matches!(name.as_str(), "SET" | "INCRBY" | "PEXPIRE" | "EVALSHA")
+    {
+        return Value::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string());
+    }
+
+    let res = match (name.as_str(), args) {
+        ("PING", []) => Ok(Value::Simple("PONG".to_string())),
+        ("GET", [key]) => string(&mut data, key).map(Value::Bulk),
+        ("SET", [key, value, options @ ..]) => set(&mut data, key, value, options),
+        ("INCRBY", [key, delta]) => incr_by(&mut data, key, delta),
+        ("PEXPIRE", [key, ms]) => pexpire(&mut data, key, ms),
+        ("SCRIPT", [subcommand, script]) if subcommand.eq_ignore_ascii_case(b"LOAD") => {
+            // Not SHA1, but just as stable.
+            let mut hasher = DefaultHasher::new();
+            script.hash(&mut hasher);
+            let sha = format!("{:040x}", hasher.finish());
+
+            state
+                .scripts
+                .lock()
+                .unwrap()
+                .insert(sha.clone(), script.clone());
+            Ok(Value::Bulk(Some(sha.into_bytes())))
+        }
+        ("EVALSHA", [sha, numkeys, args @ ..]) => {
+            let script = state
+                .scripts
+                .lock()
+                .unwrap()
+                .get(String::from_utf8_lossy(sha).as_ref())
+                .cloned();
+
+            match (script, parse::<usize>(numkeys)) {
+                (None, _) => Err(Value::Error(
+                    "NOSCRIPT No matching script. Please use EVAL.".to_string(),
+                )),
+                (Some(script), Some(numkeys)) if numkeys <= args.len() => {
+                    let (keys, argv) = args.split_at(numkeys);
+                    eval(&mut data, &script, keys, argv)
+                }
+                _ => Err(err("invalid number of keys")),
+            }
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(&mut data, cursor, options),
+        ("MGET", keys) if !keys.is_empty() => Ok(Value::Array(Some(
+            keys.iter()
+                .map(|key| Value::Bulk(string(&mut data, key).ok().flatten()))
+                .collect(),
+        ))),
+        _ => Err(err("unknown command or wrong number of arguments")),
+    };
+
+    res.unwrap_or_else(|err| err)
+}
+
+// Only the scripts used by `RedisCache` are known.
+fn eval(
+    data: &mut HashMap<Vec<u8>, Item>,
+    script: &[u8],
+    keys: &[Vec<u8>],
+    argv: &[Vec<u8>],
+) -> Result<Value, Value> {
+    match (keys, argv) {
+        ([key, version], [value]) if script == DELETE_IF_EQUALS.as_bytes() => {
+            match string(data, key)? {
+                Some(current) if current == *value => {
+                    data.remove(key);
+                    data.remove(version);
+                    Ok(Value::Integer(1))
+                }
+                _ => Ok(Value::Integer(0)),
+            }
+        }
+        ([key, version], [value, ms]) if script == EXPIRE_IF_EQUALS.as_bytes() => {
+            match string(data, key)? {
+                Some(current) if current == *value => {
+                    pexpire(data, version, ms)?;
+                    pexpire(data, key, ms)
+                }
+                _ => Ok(Value::Integer(0)),
+            }
+        }
+        ([key, version], [value, ms]) if script == SET_IF_ABSENT.as_bytes() => {
+            let options = match ms.as_slice() {
+                b"0" => vec![b"NX".to_vec()],
+                _ => vec![b"NX".to_vec(), b"PX".to_vec(), ms.clone()],
+            };
+
+            match set(data, key, value, &options)? {
+                Value::Bulk(None) => Ok(Value::Integer(0)),
+                _ => {
+                    data.remove(version);
+                    Ok(Value::Integer(1))
+                }
+            }
+        }
+        ([key, version], [delta, ms]) if script == INCR_BY.as_bytes() => {
+            let created = live(data, key).is_none();
+            let n = incr_by(data, key, delta)?;
+            if created && ms.as_slice() != b"0" {
+                pexpire(data, key, ms)?;
+            }
+            data.remove(version);
+
+            Ok(n)
+        }
+        ([key, version, versions], []) if script == GET_VERSIONED.as_bytes() => {
+            let value = match string(data, key)? {
+                Some(value) => value,
+                None => return Ok(Value::Bulk(None)),
+            };
+
+            let current = match string(data, version)? {
+                Some(current) => parse::<i64>(&current),
+                None => {
+                    let expires_at = live(data, key).and_then(|item| item.expires_at);
+                    let next = next_version(data, versions)?;
+                    store(data, version, next.to_string().as_bytes(), expires_at);
+                    Some(next)
+                }
+            };
+
+            Ok(Value::Array(Some(vec![
+                Value::Bulk(Some(value)),
+                Value::Integer(current.ok_or_else(|| err("invalid version"))?),
+            ])))
+        }
+        ([key, version, versions, entry_tags], [expected, value, ttl])
+            if script == COMPARE_AND_SET.as_bytes() =>
+        {
+            let exists = string(data, key)?.is_some();
+            let current = match string(data, version)? {
+                Some(current) if exists => parse::<i64>(&current).unwrap_or(0),
+                _ => 0,
+            };
+            let expected: i64 = parse(expected).ok_or_else(|| err("invalid version"))?;
+            if exists != (expected != 0) || (exists && current != expected) {
+                return Ok(Value::Array(Some(vec![
+                    Value::Integer(0),
+                    Value::Integer(current),
+                ])));
+            }
+
+            let next = next_version(data, versions)?;
+            let expires_at = expiry(ttl)?;
+            store(data, key, value, expires_at);
+            store(data, version, next.to_string().as_bytes(), expires_at);
+            data.remove(entry_tags);
+
+            Ok(Value::Array(Some(vec![
+                Value::Integer(1),
+                Value::Integer(next),
+            ])))
+        }
+        (keys, [ttl, values @ ..])
+            if script == SET_ENTRIES.as_bytes() && keys.len() == values.len() * 3 =>
+        {
+            let expires_at = expiry(ttl)?;
+            for (triple, value) in keys.chunks(3).zip(values) {
+                store(data, &triple[0], value, expires_at);
+                data.remove(&triple[1]);
+                data.remove(&triple[2]);
+            }
+
+            Ok(ok())
+        }
+        ([key, entry_tags, version, tags @ ..], [value, ttl])
+            if script == SET_TAGGED.as_bytes() =>
+        {
+            data.remove(version);
+            let expires_at = expiry(ttl)?;
+
+            for tag in members(data, entry_tags)? {
+                if let Some(Stored::Set(set)) = live(data, &tag).map(|item| &mut item.value) {
+                    set.remove(key);
+                    if set.is_empty() {
+                        data.remove(&tag);
+                    }
+                }
+            }
+            data.remove(entry_tags);
+
+            store(data, key, value, expires_at);
+            for tag in tags {
+                let current = live(data, tag).map(|item| item.expires_at);
+                add(data, tag, key)?;
+                add(data, entry_tags, tag)?;
+
+                let item = live(data, tag).unwrap();
+                item.expires_at = match (current, expires_at) {
+                    (_, None) => None,
+                    (None, expires_at) => expires_at,
+                    (Some(current), Some(expires_at)) => current.map(|at| at.max(expires_at)),
+                };
+            }
+            if let Some(item) = live(data, entry_tags) {
+                item.expires_at = expires_at;
+            }
+
+            Ok(ok())
+        }
+        (keys, []) if script == DELETE_ENTRIES.as_bytes() && keys.len() % 3 == 0 => {
+            let mut deleted = 0;
+            for triple in keys.chunks(3) {
+                if live(data, &triple[0]).is_some() {
+                    deleted += 1;
+                }
+                data.remove(&triple[0]);
+                data.remove(&triple[1]);
+                data.remove(&triple[2]);
+            }
+
+            Ok(Value::Integer(deleted))
+        }
+        ([tag], [count, prefix, version_prefix]) if script == INVALIDATE_TAG.as_bytes() => {
+            let count: usize = parse(count).ok_or_else(|| err("value is out of range"))?;
+            let popped: Vec<Vec<u8>> = members(data, tag)?.into_iter().take(count).collect();
+
+            let mut deleted = 0;
+            for key in popped.iter() {
+                if let Some(Stored::Set(set)) = live(data, tag).map(|item| &mut item.value) {
+                    set.remove(key);
+                    if set.is_empty() {
+                        data.remove(tag);
+                    }
+                }
+
+                let entry_tags = [prefix.as_slice(), key].concat();
+                if members(data, &entry_tags)?.contains(tag) {
+                    if live(data, key).is_some() {
+                        deleted += 1;
+                    }
+                    data.remove(key);
+                    data.remove(&entry_tags);
+                    data.remove(&[version_prefix.as_slice(), key].concat());
+                }
+            }
+
+            Ok(Value::Array(Some(vec![
+                Value::Integer(popped.len() as i64),
+                Value::Integer(deleted),
+            ])))
+        }
+        _ => Err(err("unknown script")),
+    }
+}
+
+fn next_version(data: &mut HashMap<Vec<u8>, Item>, versions: &[u8]) -> Result<i64, Value> {
+    match incr_by(data, versions, b"1")? {
+        Value::Integer(n) => Ok(n),
+        _ => Err(err("invalid version")),
+    }
+}
+
+fn expiry(ms: &[u8]) -> Result<Option<Instant>, Value> {
+    match parse::<u64>(ms) {
+        Some(0) => Ok(None),
+        Some(ms) => Ok(Some(Instant::now() + Duration::from_millis(ms))),
+        None => Err(err("invalid expire time")),
+    }
+}
+
+fn store(data: &mut HashMap<Vec<u8>, Item>, key: &[u8], value: &[u8], expires_at: Option<Instant>) {
+    data.insert(
+        key.to_vec(),
+        Item {
+            value: Stored::String(value.to_vec()),
+            expires_at,
+        },
+    );
+}
+
+fn members(data: &mut HashMap<Vec<u8>, Item>, key: &[u8]) -> Result<HashSet<Vec<u8>>, Value> {
+    match live(data, key).map(|item| &item.value) {
+        Some(Stored::Set(set)) => Ok(set.clone()),
+        Some(_) => Err(wrong_type()),
+        None => Ok(HashSet::new()),
+    }
+}
+
+fn add(data: &mut HashMap<Vec<u8>, Item>, key: &[u8], member: &[u8]) -> Result<(), Value> {
+    let item = match live(data, key) {
+        Some(item) => item,
+        None => data.entry(key.to_vec()).or_insert(Item {
+            value: Stored::Set(HashSet::new()),
+            expires_at: None,
+        }),
+    };
+
+    match &mut item.value {
+        Stored::Set(set) => {
+            set.insert(member.to_vec());
+            Ok(())
+        }
+        _ => Err(wrong_type()),
+    }
+}
+
+fn incr_by(data: &mut HashMap<Vec<u8>, Item>, key: &[u8], delta: &[u8]) -> Result<Value, Value> {
+    let delta: i64 = parse(delta).ok_or_else(|| err("value is not an integer or out of range"))?;
+    let current = match string(data, key)? {
+        Some(current) => parse::<i64>(&current),
+        None => Some(0),
+    };
+
+    match current.and_then(|current| current.checked_add(delta)) {
+        Some(n) => {
+            let expires_at = live(data, key).and_then(|item| item.expires_at);
+            data.insert(
+                key.to_vec(),
+                Item {
+                    value: Stored::String(n.to_string().into_bytes()),
+                    expires_at,
+                },
+            );
+            Ok(Value::Integer(n))
+        }
+        None => Err(err("value is not an integer or out of range")),
+    }
+}
+
+fn pexpire(data: &mut HashMap<Vec<u8>, Item>, key: &[u8], ms: &[u8]) -> Result<Value, Value> {
+    let ms: u64 = parse(ms).ok_or_else(|| err("value is not an integer or out of range"))?;
+
+    match live(data, key) {
+        Some(item) => {
+            item.expires_at = Some(Instant::now() + Duration::from_millis(ms));
+            Ok(Value::Integer(1))
+        }
+        None => Ok(Value::Integer(0)),
+    }
+}
+
+fn scan(
+    data: &mut HashMap<Vec<u8>, Item>,
+    cursor: &[u8],
+    options: &[Vec<u8>],
+) -> Result<Value, Value> {
+    let mut pattern = b"*".to_vec();
+    let mut count = 10;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (
+            String::from_utf8_lossy(option).to_uppercase().as_str(),
+            options.next(),
+        ) {
+            ("MATCH", Some(arg)) => pattern = arg.clone(),
+            ("COUNT", Some(arg)) => count = parse(arg).ok_or_else(|| err("syntax error"))?,
+            _ => return Err(err("syntax error")),
+        }
+    }
+
+    // The cursor is the hex encoded key to resume from, so like in Redis,
+    // deleting keys while scanning does not skip any other key.
+    let start = match cursor {
+        b"0" => Vec::new(),
+        cursor => from_hex(cursor).ok_or_else(|| err("invalid cursor"))?,
+    };
+
+    let mut keys: Vec<Vec<u8>> = data.keys().filter(|key| **key >= start).cloned().collect();
+    keys.retain(|key| live(data, key).is_some());
+    keys.sort();
+
+    let next = match keys.get(count) {
+        Some(key) => to_hex(key),
+        None => "0".to_string(),
+    };
+    let page = keys
+        .iter()
+        .take(count)
+        .filter(|key| glob_matches(&pattern, key))
+        .map(|key| Value::Bulk(Some(key.clone())))
+        .collect();
+
+    Ok(Value::Array(Some(vec![
+        Value::Bulk(Some(next.into_bytes())),
+        Value::Array(Some(page)),
+    ])))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// Redis glob patterns, without character classes.
+fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|i| glob_matches(rest, &key[i..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_matches(rest, &key[1..]),
+        Some((b'\\', [c, rest @ ..])) | Some((c, rest)) => {
+            key.first() == Some(c) && glob_matches(rest, &key[1..])
+        }
+    }
+}
+
+fn set(
+    data: &mut HashMap<Vec<u8>, Item>,
+    key: &[u8],
+    value: &[u8],
+    options: &[Vec<u8>],
+) -> Result<Value, Value> {
+    let mut expires_at = None;
+    let mut options = options.iter();
+
+    while let Some(option) = options.next() {
+        match String::from_utf8_lossy(option).to_uppercase().as_str() {
+            "NX" => {
+                if live(data, key).is_some() {
+                    return Ok(Value::Bulk(None));
+                }
+            }
+            "PX" => match options.next().and_then(|ms| parse::<u64>(ms)) {
+                Some(ms) if ms > 0 => expires_at = Some(Instant::now() + Duration::from_millis(ms)),
+                _ => return Err(err("invalid expire time")),
+            },
+            _ => return Err(err("syntax error")),
+        }
+    }
+
+    data.insert(
+        key.to_vec(),
+        Item {
+            value: Stored::String(value.to_vec()),
+            expires_at,
+        },
+    );
+
+    Ok(ok())
+}
@@ -0,0 +1,511 @@
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::cache::{
+    AtomicCache, Cache, CacheStats, Change, CounterCache, CounterValue, Error, InMemCache,
+    ScanCache, TaggedCache, VersionedCache, WatchCache,
+};
+use crate::models::Version;
+
+// ShardedInMemCache spreads keys over independently locked `InMemCache`
+// shards, so operations on different shards never wait for each other.
+#[derive(Clone)]
+pub struct ShardedInMemCache<K, V> {
+    shards: Arc<[InMemCache<K, V>]>,
+    hasher: RandomState,
+}
+
+impl<K, V> ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    pub fn new(shards: usize) -> ShardedInMemCache<K, V> {
+        ShardedInMemCache::with_shards(shards, InMemCache::new)
+    }
+
+    // Builds every shard with `build`. Limits such as the capacity apply to
+    // each shard, not to the whole cache.
+    pub fn with_shards<F>(shards: usize, build: F) -> ShardedInMemCache<K, V>
+    where
+        F: Fn() -> InMemCache<K, V>,
+    {
+        assert!(shards > 0, "number of shards must be greater than 0");
+
+        ShardedInMemCache {
+            shards: (0..shards).map(|_| build()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn shards(&self) -> &[InMemCache<K, V>] {
+        &self.shards
+    }
+
+    pub async fn all(&self) -> HashMap<K, V> {
+        let mut all = HashMap::new();
+        for shard in self.shards.iter() {
+            all.extend(shard.all().await);
+        }
+
+        all
+    }
+
+    pub async fn purge_expired(&self) -> usize {
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            count += shard.purge_expired().await;
+        }
+
+        count
+    }
+
+    pub fn start_reaper(&self, interval: Duration) -> Vec<JoinHandle<()>>
+    where
+        K: Sync + Send + 'static,
+        V: Sync + Send + 'static,
+    {
+        self.shards
+            .iter()
+            .map(|shard| shard.start_reaper(interval))
+            .collect()
+    }
+
+    // Sum of the stats of every shard.
+    pub async fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in self.shards.iter() {
+            let shard = shard.stats().await;
+
+            stats.hits += shard.hits;
+            stats.misses += shard.misses;
+            stats.sets += shard.sets;
+            stats.deletes += shard.deletes;
+            stats.evictions += shard.evictions;
+            stats.weighted_size += shard.weighted_size;
+            stats.size += shard.size;
+        }
+
+        stats
+    }
+
+    fn index(&self, k: &K) -> usize {
+        (self.hasher.hash_one(k) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, k: &K) -> &InMemCache<K, V> {
+        &self.shards[self.index(k)]
+    }
+
+    // Positions of the given keys grouped by shard.
+    fn group<'a, I>(&self, ks: I) -> HashMap<usize, Vec<usize>>
+    where
+        I: Iterator<Item = &'a K>,
+        K: 'a,
+    {
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, k) in ks.enumerate() {
+            groups.entry(self.index(k)).or_default().push(i);
+        }
+
+        groups
+    }
+}
+
+#[async_trait]
+impl<K, V> Cache<K, V> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        self.shard(k).get(k).await
+    }
+
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        self.shard(&k).set(k, v).await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        self.shard(&k).set_with_ttl(k, v, ttl).await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.shard(k).delete(k).await
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.len().await?;
+        }
+
+        Ok(len)
+    }
+
+    // Batch operations lock each shard involved once.
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error> {
+        let mut values = vec![None; ks.len()];
+
+        for (shard, positions) in self.group(ks.iter()) {
+            let shard_ks: Vec<K> = positions.iter().map(|i| ks[*i].clone()).collect();
+            let shard_values = self.shards[shard].get_many(&shard_ks).await?;
+
+            for (i, v) in positions.into_iter().zip(shard_values) {
+                values[i] = v;
+            }
+        }
+
+        Ok(values)
+    }
+
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error> {
+        let mut groups: HashMap<usize, Vec<(K, V)>> = HashMap::new();
+        for (k, v) in items {
+            groups.entry(self.index(&k)).or_default().push((k, v));
+        }
+
+        for (shard, items) in groups {
+            self.shards[shard].set_many(items).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        for (shard, positions) in self.group(ks.iter()) {
+            let shard_ks: Vec<K> = positions.iter().map(|i| ks[*i].clone()).collect();
+            self.shards[shard].delete_many(&shard_ks).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K, V> TaggedCache<K, V> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    async fn set_tagged(
+        &self,
+        k: K,
+        v: V,
+        tags: &[&str],
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.shard(&k).set_tagged(k, v, tags, ttl).await
+    }
+
+    async fn invalidate_tag(&self, tag: &str) -> Result<usize, Error> {
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            count += shard.invalidate_tag(tag).await?;
+        }
+
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl<K, V> VersionedCache<K, V> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    async fn get_versioned(&self, k: &K) -> Result<Option<(V, Version)>, Error> {
+        self.shard(k).get_versioned(k).await
+    }
+
+    async fn compare_and_set(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: V,
+    ) -> Result<Version, Error> {
+        self.shard(&k).compare_and_set(k, expected, v).await
+    }
+
+    async fn compare_and_set_with_ttl(
+        &self,
+        k: K,
+        expected: Option<&Version>,
+        v: V,
+        ttl: Duration,
+    ) -> Result<Version, Error> {
+        self.shard(&k)
+            .compare_and_set_with_ttl(k, expected, v, ttl)
+            .await
+    }
+}
+
+#[async_trait]
+impl<K, V> CounterCache<K> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: CounterValue + Clone + Sync + Send,
+{
+    async fn incr_by(&self, k: &K, delta: i64, ttl: Option<Duration>) -> Result<i64, Error> {
+        self.shard(k).incr_by(k, delta, ttl).await
+    }
+}
+
+#[async_trait]
+impl<K, V> AtomicCache<K, V> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + PartialEq + Sync + Send,
+{
+    async fn set_if_absent(&self, k: K, v: V, ttl: Option<Duration>) -> Result<bool, Error> {
+        self.shard(&k).set_if_absent(k, v, ttl).await
+    }
+
+    async fn delete_if_equals(&self, k: &K, v: &V) -> Result<bool, Error> {
+        self.shard(k).delete_if_equals(k, v).await
+    }
+
+    async fn expire_if_equals(&self, k: &K, v: &V, ttl: Duration) -> Result<bool, Error> {
+        self.shard(k).expire_if_equals(k, v, ttl).await
+    }
+}
+
+#[async_trait]
+impl<K, V> ScanCache<K> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize,
+    V: Clone + Sync + Send,
+{
+    // Shards are scanned one after the other.
+    fn scan(&self, pattern: &str) -> BoxStream<'_, Result<K, Error>> {
+        let pattern = pattern.to_string();
+
+        stream::iter(self.shards.iter())
+            .flat_map(move |shard| shard.scan(&pattern))
+            .boxed()
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<usize, Error> {
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            count += shard.delete_matching(pattern).await?;
+        }
+
+        Ok(count)
+    }
+}
+
+impl<K, V> WatchCache<K, V> for ShardedInMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + 'static,
+    V: Clone + Sync + Send + 'static,
+{
+    fn watch(&self, k: &K) -> BoxStream<'static, Result<Change<K, V>, Error>> {
+        self.shard(k).watch(k)
+    }
+
+    // Changes of different shards are interleaved in no particular order.
+    fn watch_prefix(&self, prefix: &str) -> BoxStream<'static, Result<Change<K, V>, Error>> {
+        stream::select_all(self.shards.iter().map(|shard| shard.watch_prefix(prefix))).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::TryStreamExt;
+
+    use crate::cache::{ManualClock, Policy};
+
+    #[tokio::test]
+    async fn set_get_and_delete() {
+        let cache = ShardedInMemCache::new(8);
+
+        for i in 0..100 {
+            cache.set(i, i * 2).await.unwrap();
+        }
+
+        assert_eq!(cache.get(&10).await.unwrap(), Some(20));
+        assert_eq!(cache.len().await.unwrap(), 100);
+        let mut sizes = 0;
+        for shard in cache.shards() {
+            sizes += shard.all().await.len();
+        }
+        assert_eq!(sizes, 100);
+
+        cache.delete(&10).await.unwrap();
+        assert!(cache.get(&10).await.unwrap().is_none());
+        assert_eq!(cache.all().await.len(), 99);
+    }
+
+    #[tokio::test]
+    async fn batch_operations() {
+        let cache = ShardedInMemCache::new(4);
+
+        cache
+            .set_many((0..20).map(|i| (i, i)).collect())
+            .await
+            .unwrap();
+
+        let ks: Vec<i32> = (0..25).collect();
+        let values = cache.get_many(&ks).await.unwrap();
+        for (k, v) in ks.iter().zip(values) {
+            assert_eq!(v, if *k < 20 { Some(*k) } else { None });
+        }
+
+        cache.delete_many(&[1, 2, 3]).await.unwrap();
+        assert_eq!(cache.len().await.unwrap(), 17);
+    }
+
+    #[tokio::test]
+    async fn tags() {
+        let cache = ShardedInMemCache::new(4);
+
+        for i in 0..20 {
+            let tag = if i % 2 == 0 { "even" } else { "odd" };
+            cache.set_tagged(i, i, &[tag], None).await.unwrap();
+        }
+
+        assert_eq!(cache.invalidate_tag("even").await.unwrap(), 10);
+        assert_eq!(cache.len().await.unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn configured_shards() {
+        let clock = ManualClock::default();
+        let cache = ShardedInMemCache::with_shards(4, || {
+            InMemCache::with_capacity(10, Policy::Lru)
+                .with_default_ttl(Duration::from_secs(1))
+                .with_clock(clock.clone())
+        });
+
+        for i in 0..100 {
+            cache.set(i, i).await.unwrap();
+        }
+        assert!(cache.len().await.unwrap() <= 40);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&99).await.unwrap().is_none());
+        cache.purge_expired().await;
+
+        // Every key was evicted once, either to make room or once expired,
+        // however keys are spread across the shards.
+        let stats = cache.stats().await;
+        assert_eq!(stats.sets, 100);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.size, 0);
+        assert_eq!(stats.evictions, 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn thread_safe_at_scale() {
+        const TASKS: usize = 64;
+        const KEYS: usize = 1000;
+
+        let cache = ShardedInMemCache::new(16);
+
+        let mut tasks = Vec::new();
+        for t in 0..TASKS {
+            let cache = cache.clone();
+
+            tasks.push(tokio::spawn(async move {
+                for k in 0..KEYS {
+                    cache.set((t, k), t * k).await.unwrap();
+                    assert_eq!(cache.get(&(t, k)).await.unwrap(), Some(t * k));
+                }
+
+                for k in (0..KEYS).step_by(2) {
+                    cache.delete(&(t, k)).await.unwrap();
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.sets as usize, TASKS * KEYS);
+        assert_eq!(stats.hits as usize, TASKS * KEYS);
+        assert_eq!(stats.deletes as usize, TASKS * KEYS / 2);
+
+        assert_eq!(cache.len().await.unwrap(), TASKS * KEYS / 2);
+        assert_eq!(cache.get(&(3, 7)).await.unwrap(), Some(21));
+        assert!(cache.get(&(3, 8)).await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_readers_and_writers() {
+        let cache = ShardedInMemCache::new(16);
+        cache
+            .set_many((0..1000).map(|k| (k, 0)).collect())
+            .await
+            .unwrap();
+
+        let mut tasks = Vec::new();
+        for t in 0..32 {
+            let cache = cache.clone();
+
+            tasks.push(tokio::spawn(async move {
+                for round in 0..20 {
+                    if t % 4 == 0 {
+                        let items = (0..1000).map(|k| (k, round)).collect();
+                        cache.set_many(items).await.unwrap();
+                    } else {
+                        let ks: Vec<i32> = (0..1000).collect();
+                        let values = cache.get_many(&ks).await.unwrap();
+                        assert!(values.iter().all(Option::is_some));
+                    }
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(cache.len().await.unwrap(), 1000);
+        assert!(cache.all().await.values().all(|v| *v == 19));
+    }
+
+    #[tokio::test]
+    async fn scan_and_delete_matching() {
+        let cache = ShardedInMemCache::new(4);
+
+        for i in 0..20 {
+            cache.set(format!("user.{}", i), i).await.unwrap();
+            cache.set(format!("post.{}", i), i).await.unwrap();
+        }
+
+        let keys: Vec<String> = cache.scan("user.*").try_collect().await.unwrap();
+        assert_eq!(keys.len(), 20);
+        assert!(keys.iter().all(|key| key.starts_with("user.")));
+
+        assert_eq!(cache.delete_matching("post.*").await.unwrap(), 20);
+        assert_eq!(cache.len().await.unwrap(), 20);
+    }
+
+    #[tokio::test]
+    async fn watch_prefix() {
+        let cache = ShardedInMemCache::new(4);
+        let watcher = cache.watch_prefix("user.");
+
+        for i in 0..10 {
+            cache.set(format!("user.{}", i), i).await.unwrap();
+            cache.set(format!("post.{}", i), i).await.unwrap();
+        }
+
+        let changes: Vec<Change<String, i32>> = watcher.take(10).try_collect().await.unwrap();
+        assert!(changes
+            .iter()
+            .all(|change| change.key().starts_with("user.")));
+    }
+}
@@ -0,0 +1,267 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::cache::{expiration, BincodeCodec, Codec, Error, InMemCache, JsonCodec};
+
+// Binary entries are prefixed by their length, as u32 little endian.
+const LEN: usize = 4;
+
+// Format of the snapshots written by `InMemCache::export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    // One JSON object per line, readable and editable by hand, such as for
+    // test fixtures.
+    JsonLines,
+    // Bincode entries, more compact but only readable with the same key and
+    // value types.
+    Binary,
+}
+
+// Entries keep their remaining TTL rather than their expiration, so they
+// last the same after being imported, whatever the clock of the importer. The
+// TTL can be left out of hand written JSON lines.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry<K, V> {
+    key: K,
+    value: V,
+    #[serde(default)]
+    ttl_ms: Option<u64>,
+}
+
+impl<K, V> InMemCache<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned,
+    V: Clone + Sync + Send + Serialize + DeserializeOwned,
+{
+    // Writes the live entries to `w` and returns how many were written. Tags
+    // and versions are not exported.
+    pub async fn export<W>(&self, w: &mut W, format: SnapshotFormat) -> Result<usize, Error>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let now = self.now();
+        let entries = self.entries().await;
+        let count = entries.len();
+
+        let mut buf = Vec::new();
+        for (key, value, expires_at) in entries {
+            // Rounded up, so entries about to expire are not exported as
+            // never expiring.
+            let ttl_ms = expires_at.map(|expires_at| {
+                let remaining = expires_at - now;
+                let ms = remaining.num_milliseconds().max(0) as u64;
+                if remaining > chrono::Duration::milliseconds(ms as i64) {
+                    ms + 1
+                } else {
+                    ms
+                }
+            });
+
+            let entry = SnapshotEntry { key, value, ttl_ms };
+
+            match format {
+                SnapshotFormat::JsonLines => {
+                    buf.extend(JsonCodec.encode(&entry)?);
+                    buf.push(b'\n');
+                }
+                SnapshotFormat::Binary => {
+                    let data = BincodeCodec.encode(&entry)?;
+                    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    buf.extend(data);
+                }
+            }
+        }
+
+        w.write_all(&buf).await.map_err(Error::Storage)?;
+        w.flush().await.map_err(Error::Storage)?;
+
+        Ok(count)
+    }
+
+    // Sets the entries read from `r`, written by `export`, and returns how
+    // many were set. Entries whose TTL was already over are skipped.
+    pub async fn import<R>(&self, r: &mut R, format: SnapshotFormat) -> Result<usize, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data).await.map_err(Error::Storage)?;
+
+        let entries: Vec<SnapshotEntry<K, V>> = match format {
+            SnapshotFormat::JsonLines => data
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|line| JsonCodec.decode(line))
+                .collect::<Result<_, _>>()?,
+            SnapshotFormat::Binary => decode_binary(&data)?,
+        };
+
+        // Entries are decoded before setting any, so a corrupted snapshot
+        // leaves the cache untouched.
+        let now = self.now();
+        let mut count = 0;
+        for entry in entries {
+            let expires_at = match entry.ttl_ms {
+                Some(0) => continue,
+                Some(ttl) => expiration(now, std::time::Duration::from_millis(ttl)),
+                None => None,
+            };
+
+            self.set_expiring(entry.key, entry.value, expires_at).await;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+fn decode_binary<T: DeserializeOwned>(data: &[u8]) -> Result<Vec<T>, Error> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        if data.len() - offset < LEN {
+            return Err(truncated());
+        }
+
+        let len = u32::from_le_bytes(data[offset..offset + LEN].try_into().unwrap()) as usize;
+        let start = offset + LEN;
+        if data.len() - start < len {
+            return Err(truncated());
+        }
+
+        entries.push(BincodeCodec.decode(&data[start..start + len])?);
+        offset = start + len;
+    }
+
+    Ok(entries)
+}
+
+fn truncated() -> Error {
+    Error::DeserializingValue("snapshot is truncated".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::cache::{Cache, ManualClock};
+
+    async fn exported(cache: &InMemCache<String, Vec<u32>>, format: SnapshotFormat) -> Vec<u8> {
+        let mut buf = Vec::new();
+        cache.export(&mut buf, format).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let clock = ManualClock::default();
+        let cache = InMemCache::new().with_clock(clock.clone());
+        cache.set("a".to_string(), vec![1, 2]).await.unwrap();
+        cache
+            .set_with_ttl("b".to_string(), vec![3], Duration::from_secs(10))
+            .await
+            .unwrap();
+        cache
+            .set_with_ttl("c".to_string(), vec![], Duration::from_secs(1))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(4));
+
+        for format in [SnapshotFormat::JsonLines, SnapshotFormat::Binary] {
+            let data = exported(&cache, format).await;
+
+            let other_clock = ManualClock::default();
+            let other = InMemCache::new().with_clock(other_clock.clone());
+            assert_eq!(other.import(&mut &data[..], format).await.unwrap(), 2);
+            assert_eq!(
+                other.all().await,
+                HashMap::from([("a".to_string(), vec![1, 2]), ("b".to_string(), vec![3])])
+            );
+
+            // The remaining TTL is kept.
+            other_clock.advance(Duration::from_secs(5));
+            assert!(other.get(&"b".to_string()).await.unwrap().is_some());
+            other_clock.advance(Duration::from_secs(1));
+            assert!(other.get(&"b".to_string()).await.unwrap().is_none());
+            assert!(other.get(&"a".to_string()).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn json_lines() {
+        let cache = InMemCache::new();
+
+        let data =
+            b"{\"key\":\"a\",\"value\":[1]}\n\n{\"key\":\"b\",\"value\":[2],\"ttl_ms\":500}\n";
+        assert_eq!(
+            cache
+                .import(&mut &data[..], SnapshotFormat::JsonLines)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(cache.get(&"b".to_string()).await.unwrap(), Some(vec![2]));
+
+        let data = exported(&cache, SnapshotFormat::JsonLines).await;
+        let mut lines: Vec<&str> = std::str::from_utf8(&data).unwrap().lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines[0], "{\"key\":\"a\",\"value\":[1],\"ttl_ms\":null}");
+        assert!(lines[1].starts_with("{\"key\":\"b\",\"value\":[2],\"ttl_ms\":"));
+    }
+
+    #[tokio::test]
+    async fn corrupted_snapshot() {
+        let cache = InMemCache::new();
+        cache.set("a".to_string(), vec![1]).await.unwrap();
+        cache.set("b".to_string(), vec![2]).await.unwrap();
+
+        let data = exported(&cache, SnapshotFormat::Binary).await;
+        let other: InMemCache<String, Vec<u32>> = InMemCache::new();
+        assert!(matches!(
+            other
+                .import(&mut &data[..data.len() - 1], SnapshotFormat::Binary)
+                .await,
+            Err(Error::DeserializingValue(_))
+        ));
+        assert!(other.is_empty().await.unwrap());
+
+        let data = b"{\"key\":\"a\",\"value\":[1]}\nnot json\n";
+        assert!(other
+            .import(&mut &data[..], SnapshotFormat::JsonLines)
+            .await
+            .is_err());
+        assert!(other.is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let dir = std::env::temp_dir().join(format!("core-lib-snapshot-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("cache.jsonl");
+
+        let cache = InMemCache::new();
+        cache.set("a".to_string(), vec![1]).await.unwrap();
+
+        let mut file = tokio::fs::File::create(&path).await.unwrap();
+        cache
+            .export(&mut file, SnapshotFormat::JsonLines)
+            .await
+            .unwrap();
+
+        let other: InMemCache<String, Vec<u32>> = InMemCache::new();
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        other
+            .import(&mut file, SnapshotFormat::JsonLines)
+            .await
+            .unwrap();
+        assert_eq!(other.get(&"a".to_string()).await.unwrap(), Some(vec![1]));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::{Cache, Error, EvictionCause};
+
+// CacheStats is a snapshot of what a cache has done since it was created.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub sets: u64,
+    pub deletes: u64,
+    pub evictions: u64,
+    pub size: u64,
+    // Total weight of the entries of caches bounded by weight.
+    pub weighted_size: u64,
+}
+
+impl CacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / reads as f64
+    }
+}
+
+// Observer is notified of every cache operation, key by key. All the hooks
+// do nothing by default.
+pub trait Observer<K>: Sync + Send {
+    fn on_hit(&self, _k: &K) {}
+    fn on_miss(&self, _k: &K) {}
+    fn on_set(&self, _k: &K) {}
+    fn on_delete(&self, _k: &K) {}
+    fn on_evict(&self, _k: &K, _cause: EvictionCause) {}
+}
+
+// StatsObserver counts operations to build `CacheStats`.
+#[derive(Debug, Default)]
+pub struct StatsObserver {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl StatsObserver {
+    pub fn new() -> StatsObserver {
+        StatsObserver::default()
+    }
+
+    pub fn snapshot(&self, size: u64) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            size,
+            weighted_size: 0,
+        }
+    }
+}
+
+impl<K> Observer<K> for StatsObserver {
+    fn on_hit(&self, _k: &K) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_miss(&self, _k: &K) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_set(&self, _k: &K) {
+        self.sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_delete(&self, _k: &K) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_evict(&self, _k: &K, _cause: EvictionCause) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Observers notifies the built-in stats and then the custom observers, in
+// the order they were added.
+pub(crate) struct Observers<K> {
+    stats: Arc<StatsObserver>,
+    custom: Vec<Arc<dyn Observer<K>>>,
+}
+
+impl<K> Observers<K> {
+    pub(crate) fn new() -> Observers<K> {
+        Observers {
+            stats: Arc::new(StatsObserver::new()),
+            custom: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with(&self, custom: Arc<dyn Observer<K>>) -> Observers<K> {
+        let mut observers = self.clone();
+        observers.custom.push(custom);
+        observers
+    }
+
+    pub(crate) fn stats(&self, size: u64) -> CacheStats {
+        self.stats.snapshot(size)
+    }
+
+    pub(crate) fn notify<F>(&self, f: F)
+    where
+        F: Fn(&dyn Observer<K>),
+    {
+        f(self.stats.as_ref());
+
+        for custom in self.custom.iter() {
+            f(custom.as_ref());
+        }
+    }
+}
+
+impl<K> Clone for Observers<K> {
+    fn clone(&self) -> Self {
+        Observers {
+            stats: self.stats.clone(),
+            custom: self.custom.clone(),
+        }
+    }
+}
+
+// ObservedCache instruments any cache. Evictions happen inside the backend,
+// so they are only counted by backends reporting them themselves (such as
+// `InMemCache::with_observer`).
+#[derive(Clone)]
+pub struct ObservedCache<K, C> {
+    cache: C,
+    observers: Observers<K>,
+}
+
+impl<K, C> ObservedCache<K, C> {
+    pub fn new(cache: C) -> ObservedCache<K, C> {
+        ObservedCache {
+            cache,
+            observers: Observers::new(),
+        }
+    }
+
+    // Adds an observer, notified after the ones added before.
+    pub fn with_observer(mut self, observer: Arc<dyn Observer<K>>) -> ObservedCache<K, C> {
+        self.observers = self.observers.with(observer);
+        self
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.cache
+    }
+
+    pub async fn stats<V>(&self) -> Result<CacheStats, Error>
+    where
+        C: Cache<K, V>,
+    {
+        let size = self.cache.len().await?;
+
+        Ok(self.observers.stats(size as u64))
+    }
+}
+
+#[async_trait]
+impl<K, V, C> Cache<K, V> for ObservedCache<K, C>
+where
+    K: Clone + Sync + Send + 'static,
+    V: Sync + Send + 'static,
+    C: Cache<K, V> + Sync + Send,
+{
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        let v = self.cache.get(k).await?;
+
+        match &v {
+            Some(_) => self.observers.notify(|o| o.on_hit(k)),
+            None => self.observers.notify(|o| o.on_miss(k)),
+        }
+
+        Ok(v)
+    }
+
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        self.cache.set(k.clone(), v).await?;
+        self.observers.notify(|o| o.on_set(&k));
+
+        Ok(())
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        self.cache.set_with_ttl(k.clone(), v, ttl).await?;
+        self.observers.notify(|o| o.on_set(&k));
+
+        Ok(())
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.cache.delete(k).await?;
+        self.observers.notify(|o| o.on_delete(k));
+
+        Ok(())
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        self.cache.len().await
+    }
+
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error> {
+        let values = self.cache.get_many(ks).await?;
+
+        for (k, v) in ks.iter().zip(values.iter()) {
+            match v {
+                Some(_) => self.observers.notify(|o| o.on_hit(k)),
+                None => self.observers.notify(|o| o.on_miss(k)),
+            }
+        }
+
+        Ok(values)
+    }
+
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error> {
+        let ks: Vec<K> = items.iter().map(|(k, _)| k.clone()).collect();
+        self.cache.set_many(items).await?;
+
+        for k in ks.iter() {
+            self.observers.notify(|o| o.on_set(k));
+        }
+
+        Ok(())
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        self.cache.delete_many(ks).await?;
+
+        for k in ks {
+            self.observers.notify(|o| o.on_delete(k));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::cache::resp_server::RespServer;
+    use crate::cache::{InMemCache, RedisCache};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Observer<&'static str> for Recorder {
+        fn on_hit(&self, k: &&'static str) {
+            self.events.lock().unwrap().push(format!("hit {}", k));
+        }
+
+        fn on_miss(&self, k: &&'static str) {
+            self.events.lock().unwrap().push(format!("miss {}", k));
+        }
+    }
+
+    // Failing rejects every write.
+    struct Failing;
+
+    #[async_trait]
+    impl Cache<&'static str, i64> for Failing {
+        async fn get(&self, _k: &&'static str) -> Result<Option<i64>, Error> {
+            Ok(None)
+        }
+
+        async fn set(&self, _k: &'static str, _v: i64) -> Result<(), Error> {
+            Err(Error::Internal)
+        }
+
+        async fn set_with_ttl(
+            &self,
+            _k: &'static str,
+            _v: i64,
+            _ttl: Duration,
+        ) -> Result<(), Error> {
+            Err(Error::Internal)
+        }
+
+        async fn delete(&self, _k: &&'static str) -> Result<(), Error> {
+            Err(Error::Internal)
+        }
+
+        async fn len(&self) -> Result<usize, Error> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn hit_ratio() {
+        let stats = CacheStats {
+            hits: 3,
+            misses: 1,
+            ..CacheStats::default()
+        };
+        assert_eq!(stats.hit_ratio(), 0.75);
+        assert_eq!(CacheStats::default().hit_ratio(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn observe_remote_backend() {
+        let server = RespServer::start().await;
+        let recorder = Arc::new(Recorder::default());
+        let cache = ObservedCache::new(RedisCache::connect(server.addr()).await.unwrap())
+            .with_observer(recorder.clone());
+
+        cache.get(&"a").await.unwrap();
+        cache.set("a", b"1".to_vec()).await.unwrap();
+        cache.set("b", b"2".to_vec()).await.unwrap();
+        cache.get(&"a").await.unwrap();
+        cache.get_many(&["a", "c"]).await.unwrap();
+        cache.delete(&"b").await.unwrap();
+
+        assert_eq!(
+            cache.stats().await.unwrap(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                sets: 2,
+                deletes: 1,
+                evictions: 0,
+                size: 1,
+                weighted_size: 0,
+            }
+        );
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec!["miss a", "hit a", "hit a", "miss c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn several_observers() {
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder::default());
+        let cache = ObservedCache::new(InMemCache::new())
+            .with_observer(first.clone())
+            .with_observer(second.clone());
+
+        Cache::<&str, i64>::get(&cache, &"a").await.unwrap();
+
+        assert_eq!(*first.events.lock().unwrap(), vec!["miss a"]);
+        assert_eq!(*second.events.lock().unwrap(), vec!["miss a"]);
+    }
+
+    #[tokio::test]
+    async fn failed_writes_not_counted() {
+        let cache = ObservedCache::new(Failing);
+
+        assert!(cache.set("a", 1).await.is_err());
+        assert!(cache
+            .set_with_ttl("a", 1, Duration::from_secs(1))
+            .await
+            .is_err());
+        assert!(cache.set_many(vec![("a", 1)]).await.is_err());
+        assert!(cache.delete(&"a").await.is_err());
+
+        assert_eq!(cache.stats::<i64>().await.unwrap(), CacheStats::default());
+    }
+}
@@ -0,0 +1,459 @@
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::cache::{Cache, Error, InMemCache};
+use crate::events::{Error as EventsError, Event, Handler, Publisher, Subscriber};
+
+#[derive(Serialize, Deserialize)]
+struct Invalidation<K> {
+    node: String,
+    keys: Vec<K>,
+}
+
+// Generations of the keys being read from the remote tier. Every
+// invalidation of such a key increments its generation, so a read that
+// overlapped one does not fill the local tier with the value it replaced.
+struct Generations<K> {
+    reads: Mutex<HashMap<K, Reads>>,
+}
+
+struct Reads {
+    count: usize,
+    generation: u64,
+}
+
+impl<K: Clone + Eq + Hash> Generations<K> {
+    fn new() -> Generations<K> {
+        Generations {
+            reads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Registers a read of `k`, returning its current generation.
+    fn start(&self, k: &K) -> u64 {
+        let mut reads = self.reads.lock().unwrap();
+        let entry = reads.entry(k.clone()).or_insert(Reads {
+            count: 0,
+            generation: 0,
+        });
+        entry.count += 1;
+
+        entry.generation
+    }
+
+    // Ends a read of `k`, returning whether `k` was invalidated since it
+    // started.
+    fn finish(&self, k: &K, generation: u64) -> bool {
+        let mut reads = self.reads.lock().unwrap();
+        let Some(entry) = reads.get_mut(k) else {
+            return false;
+        };
+
+        let invalidated = entry.generation != generation;
+        entry.count -= 1;
+        if entry.count == 0 {
+            reads.remove(k);
+        }
+
+        invalidated
+    }
+
+    fn invalidate(&self, ks: &[K]) {
+        let mut reads = self.reads.lock().unwrap();
+        for k in ks {
+            if let Some(entry) = reads.get_mut(k) {
+                entry.generation += 1;
+            }
+        }
+    }
+}
+
+struct InvalidationHandler<K, V> {
+    node: String,
+    local: InMemCache<K, V>,
+    generations: Arc<Generations<K>>,
+}
+
+#[async_trait]
+impl<K, V> Handler for InvalidationHandler<K, V>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned,
+    V: Clone + Sync + Send,
+{
+    async fn handle(&self, event: &Event) -> Result<(), EventsError> {
+        let invalidation: Invalidation<K> = event.deserialize_payload()?;
+
+        if invalidation.node != self.node {
+            self.generations.invalidate(&invalidation.keys);
+            // InMemCache never fails.
+            let _ = self.local.delete_many(&invalidation.keys).await;
+        }
+
+        Ok(())
+    }
+}
+
+// TieredCache keeps a local copy (L1) of the entries read from a shared cache
+// (L2). Writes go to both tiers and are announced on the event bus so the
+// other nodes drop their now stale local copies.
+#[derive(Clone)]
+pub struct TieredCache<K, V, C> {
+    name: String,
+    node: String,
+    local: InMemCache<K, V>,
+    remote: C,
+    publisher: Arc<dyn Publisher + Sync + Send>,
+    generations: Arc<Generations<K>>,
+}
+
+impl<K, V, C> TieredCache<K, V, C>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned + 'static,
+    V: Clone + Sync + Send + 'static,
+    C: Cache<K, V> + Sync + Send,
+{
+    // Nodes sharing the same remote cache must use the same name. The local
+    // cache decides how long entries are kept locally.
+    pub fn new<N, P>(
+        name: N,
+        local: InMemCache<K, V>,
+        remote: C,
+        publisher: P,
+    ) -> TieredCache<K, V, C>
+    where
+        N: Into<String>,
+        P: Publisher + Sync + Send + 'static,
+    {
+        TieredCache {
+            name: name.into(),
+            node: Uuid::new_v4().to_string(),
+            local,
+            remote,
+            publisher: Arc::new(publisher),
+            generations: Arc::new(Generations::new()),
+        }
+    }
+
+    pub fn topic(&self) -> String {
+        format!("cache.{}.invalidated", self.name)
+    }
+
+    pub fn local(&self) -> &InMemCache<K, V> {
+        &self.local
+    }
+
+    pub fn remote(&self) -> &C {
+        &self.remote
+    }
+
+    // Starts listening to the invalidations published by other nodes.
+    pub async fn subscribe<S>(&self, subscriber: &S) -> Result<(), Error>
+    where
+        S: Subscriber + ?Sized,
+    {
+        let handler = InvalidationHandler {
+            node: self.node.clone(),
+            local: self.local.clone(),
+            generations: self.generations.clone(),
+        };
+
+        subscriber
+            .subscribe(&self.topic(), Box::new(handler))
+            .await
+            .map_err(Error::Invalidating)
+    }
+
+    async fn invalidate(&self, keys: Vec<K>) -> Result<(), Error> {
+        let invalidation = Invalidation {
+            node: self.node.clone(),
+            keys,
+        };
+        let event =
+            Event::create(&self.name, self.topic(), &invalidation).map_err(Error::Invalidating)?;
+
+        self.publisher
+            .publish(&[event])
+            .await
+            .map_err(Error::Invalidating)
+    }
+}
+
+#[async_trait]
+impl<K, V, C> Cache<K, V> for TieredCache<K, V, C>
+where
+    K: Clone + Eq + Hash + Sync + Send + Serialize + DeserializeOwned + 'static,
+    V: Clone + Sync + Send + 'static,
+    C: Cache<K, V> + Sync + Send,
+{
+    async fn get(&self, k: &K) -> Result<Option<V>, Error> {
+        if let Some(v) = self.local.get(k).await? {
+            return Ok(Some(v));
+        }
+
+        let generation = self.generations.start(k);
+        let v = self.remote.get(k).await;
+        let filled = match &v {
+            Ok(Some(v)) => self.local.set(k.clone(), v.clone()).await,
+            _ => Ok(()),
+        };
+
+        // Checked once filled: an invalidation arriving later deletes the
+        // value itself.
+        if self.generations.finish(k, generation) {
+            self.local.delete(k).await?;
+        }
+        filled?;
+
+        v
+    }
+
+    // Writes invalidate the keys being read between the two tiers, so those
+    // reads cannot overwrite the local copy with the previous value.
+    async fn set(&self, k: K, v: V) -> Result<(), Error> {
+        self.remote.set(k.clone(), v.clone()).await?;
+        self.generations.invalidate(std::slice::from_ref(&k));
+        self.local.set(k.clone(), v).await?;
+
+        self.invalidate(vec![k]).await
+    }
+
+    async fn set_with_ttl(&self, k: K, v: V, ttl: Duration) -> Result<(), Error> {
+        self.remote.set_with_ttl(k.clone(), v.clone(), ttl).await?;
+        self.generations.invalidate(std::slice::from_ref(&k));
+        self.local.set_with_ttl(k.clone(), v, ttl).await?;
+
+        self.invalidate(vec![k]).await
+    }
+
+    async fn delete(&self, k: &K) -> Result<(), Error> {
+        self.remote.delete(k).await?;
+        self.generations.invalidate(std::slice::from_ref(k));
+        self.local.delete(k).await?;
+
+        self.invalidate(vec![k.clone()]).await
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        self.remote.len().await
+    }
+
+    async fn get_many(&self, ks: &[K]) -> Result<Vec<Option<V>>, Error> {
+        let mut values = self.local.get_many(ks).await?;
+
+        let missing: Vec<usize> = (0..ks.len()).filter(|i| values[*i].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(values);
+        }
+
+        let missing_ks: Vec<K> = missing.iter().map(|i| ks[*i].clone()).collect();
+        let generations: Vec<u64> = missing_ks
+            .iter()
+            .map(|k| self.generations.start(k))
+            .collect();
+        let found = self.remote.get_many(&missing_ks).await;
+
+        let mut filled = Ok(());
+        if let Ok(found) = &found {
+            let mut fill = Vec::new();
+            for (i, v) in missing.into_iter().zip(found) {
+                if let Some(v) = v {
+                    fill.push((ks[i].clone(), v.clone()));
+                }
+                values[i] = v.clone();
+            }
+            filled = self.local.set_many(fill).await;
+        }
+
+        let invalidated: Vec<K> = missing_ks
+            .into_iter()
+            .zip(generations)
+            .filter(|(k, generation)| self.generations.finish(k, *generation))
+            .map(|(k, _)| k)
+            .collect();
+        if !invalidated.is_empty() {
+            self.local.delete_many(&invalidated).await?;
+        }
+        found?;
+        filled?;
+
+        Ok(values)
+    }
+
+    // Batches publish a single invalidation.
+    async fn set_many(&self, items: Vec<(K, V)>) -> Result<(), Error> {
+        let keys: Vec<K> = items.iter().map(|(k, _)| k.clone()).collect();
+
+        self.remote.set_many(items.clone()).await?;
+        self.generations.invalidate(&keys);
+        self.local.set_many(items).await?;
+
+        self.invalidate(keys).await
+    }
+
+    async fn delete_many(&self, ks: &[K]) -> Result<(), Error> {
+        self.remote.delete_many(ks).await?;
+        self.generations.invalidate(ks);
+        self.local.delete_many(ks).await?;
+
+        self.invalidate(ks.to_vec()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::events::LocalEventBus;
+
+    // Remote tier whose reads wait, once done, until `paused` is released.
+    #[derive(Clone)]
+    struct Slow {
+        cache: InMemCache<String, i32>,
+        paused: Arc<tokio::sync::Mutex<()>>,
+    }
+
+    #[async_trait]
+    impl Cache<String, i32> for Slow {
+        async fn get(&self, k: &String) -> Result<Option<i32>, Error> {
+            let v = self.cache.get(k).await;
+            drop(self.paused.lock().await);
+            v
+        }
+
+        async fn set(&self, k: String, v: i32) -> Result<(), Error> {
+            self.cache.set(k, v).await
+        }
+
+        async fn set_with_ttl(&self, k: String, v: i32, ttl: Duration) -> Result<(), Error> {
+            self.cache.set_with_ttl(k, v, ttl).await
+        }
+
+        async fn delete(&self, k: &String) -> Result<(), Error> {
+            self.cache.delete(k).await
+        }
+
+        async fn len(&self) -> Result<usize, Error> {
+            self.cache.len().await
+        }
+    }
+
+    async fn node(
+        bus: &LocalEventBus,
+        remote: &InMemCache<String, i32>,
+    ) -> TieredCache<String, i32, InMemCache<String, i32>> {
+        let cache = TieredCache::new("users", InMemCache::new(), remote.clone(), bus.clone());
+        cache.subscribe(bus).await.unwrap();
+        cache
+    }
+
+    #[tokio::test]
+    async fn reads_through_the_remote_tier() {
+        let bus = LocalEventBus::new();
+        let remote = InMemCache::new();
+        let cache = node(&bus, &remote).await;
+
+        remote.set("key".to_string(), 1).await.unwrap();
+
+        assert_eq!(cache.get(&"key".to_string()).await.unwrap(), Some(1));
+        assert_eq!(
+            cache.local().get(&"key".to_string()).await.unwrap(),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidates_other_nodes() {
+        let bus = LocalEventBus::new();
+        let remote = InMemCache::new();
+        let node1 = node(&bus, &remote).await;
+        let node2 = node(&bus, &remote).await;
+        let key = "key".to_string();
+
+        node1.set(key.clone(), 1).await.unwrap();
+        assert_eq!(node2.get(&key).await.unwrap(), Some(1));
+
+        // Without invalidation node2 would keep serving its local copy.
+        node1.set(key.clone(), 2).await.unwrap();
+        assert!(node2.local().get(&key).await.unwrap().is_none());
+        assert_eq!(node2.get(&key).await.unwrap(), Some(2));
+
+        // The writer keeps its own fresh copy.
+        assert_eq!(node1.local().get(&key).await.unwrap(), Some(2));
+
+        node2.delete(&key).await.unwrap();
+        assert!(node1.get(&key).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_operations() {
+        let bus = LocalEventBus::new();
+        let remote = InMemCache::new();
+        let node1 = node(&bus, &remote).await;
+        let node2 = node(&bus, &remote).await;
+
+        let ks: Vec<String> = (0..3).map(|i| i.to_string()).collect();
+        node1
+            .set_many(ks.iter().cloned().zip(0..).collect())
+            .await
+            .unwrap();
+        assert_eq!(
+            node2.get_many(&ks).await.unwrap(),
+            vec![Some(0), Some(1), Some(2)]
+        );
+
+        node1.delete_many(&ks[..2]).await.unwrap();
+        assert_eq!(
+            node2.get_many(&ks).await.unwrap(),
+            vec![None, None, Some(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidated_during_remote_read() {
+        let bus = LocalEventBus::new();
+        let remote = InMemCache::new();
+        let slow = Slow {
+            cache: remote.clone(),
+            paused: Arc::new(tokio::sync::Mutex::new(())),
+        };
+        let node1 = TieredCache::new("users", InMemCache::new(), slow.clone(), bus.clone());
+        node1.subscribe(&bus).await.unwrap();
+        let node2 = node(&bus, &remote).await;
+        let key = "key".to_string();
+        remote.set(key.clone(), 1).await.unwrap();
+
+        // Written by another node while node1 reads the previous value.
+        let paused = slow.paused.lock().await;
+        let read = tokio::spawn({
+            let (node1, key) = (node1.clone(), key.clone());
+            async move { node1.get(&key).await }
+        });
+        tokio::task::yield_now().await;
+        node2.set(key.clone(), 2).await.unwrap();
+        drop(paused);
+
+        assert_eq!(read.await.unwrap().unwrap(), Some(1));
+        assert!(node1.local().get(&key).await.unwrap().is_none());
+        assert_eq!(node1.get(&key).await.unwrap(), Some(2));
+
+        // Written by node1 itself while it reads the previous value.
+        node1.local().delete(&key).await.unwrap();
+        let paused = slow.paused.lock().await;
+        let read = tokio::spawn({
+            let (node1, key) = (node1.clone(), key.clone());
+            async move { node1.get_many(&[key]).await }
+        });
+        tokio::task::yield_now().await;
+        node1.set(key.clone(), 3).await.unwrap();
+        drop(paused);
+
+        read.await.unwrap().unwrap();
+        assert_eq!(node1.get(&key).await.unwrap(), Some(3));
+    }
+}
@@ -56,11 +56,7 @@ impl Error {
         S: Into<String>,
         M: Into<Option<Metadata>>,
     {
-        Error::new(
-            code,
-            format!("{} ({})", message.into(), err.to_string()),
-            metadata,
-        )
+        Error::new(code, format!("{} ({})", message.into(), err), metadata)
     }
 
     pub fn code(&self) -> &str {
@@ -71,6 +67,7 @@ impl Error {
         &self.message
     }
 
+    #[allow(clippy::borrowed_box)]
     pub fn cause(&self) -> Option<&Box<Error>> {
         self.cause.as_ref()
     }
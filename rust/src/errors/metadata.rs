@@ -43,6 +43,12 @@ impl Metadata {
     }
 }
 
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -20,4 +20,14 @@ pub enum Error {
         #[source]
         err: Box<dyn std::error::Error + Sync + Send>,
     },
+    #[error("version conflict on {entity_id}: expected {expected:?}, found {actual:?}")]
+    VersionConflict {
+        entity_id: String,
+        expected: Option<i64>,
+        actual: Option<i64>,
+    },
+    #[error("event {0} already stored")]
+    DuplicateEvent(String),
+    #[error("event storage failed: {0}")]
+    Storage(#[source] Box<dyn std::error::Error + Sync + Send>),
 }
@@ -118,7 +118,7 @@ mod tests {
         let event = res.unwrap();
         assert_eq!(event.entity_id(), "entity#01");
         assert_eq!(event.topic(), "topic.code");
-        assert!(event.payload().len() > 0);
+        assert!(!event.payload().is_empty());
     }
 
     #[test]
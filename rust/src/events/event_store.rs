@@ -0,0 +1,390 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::events::{Error, Event};
+use crate::models::Version;
+
+// StoredEvent is an event appended to a store, with its version in the stream
+// of its entity and its position among every stored event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredEvent {
+    event: Event,
+    version: Version,
+    position: u64,
+}
+
+impl StoredEvent {
+    pub fn new(event: Event, version: Version, position: u64) -> StoredEvent {
+        StoredEvent {
+            event,
+            version,
+            position,
+        }
+    }
+
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_event(self) -> Event {
+        self.event
+    }
+}
+
+// EventStore keeps the events of every entity as an append-only stream. Like
+// an aggregate, a stream gets one version per unit of work: every append of
+// events increments it once and the events share it. An empty stream has no
+// version.
+#[async_trait]
+pub trait EventStore: Sync + Send {
+    // Appends events to the stream of `entity_id` if it is still at the stored
+    // value of `expected_version`, or fails with `Error::VersionConflict`. The
+    // version of an aggregate can be passed as is: once updated, it expects
+    // the version before the unit of work. Events already stored fail with
+    // `Error::DuplicateEvent`, appending none of them. Empty appends only
+    // check the version. Returns the version of the stream.
+    async fn append(
+        &self,
+        entity_id: &str,
+        expected_version: Option<&Version>,
+        events: &[Event],
+    ) -> Result<Option<Version>, Error>;
+
+    // Returns the events of `entity_id` appended after `from_version`, or all
+    // of them if `None`.
+    async fn read_stream(
+        &self,
+        entity_id: &str,
+        from_version: Option<&Version>,
+    ) -> Result<Vec<StoredEvent>, Error>;
+
+    // Returns up to `limit` events of every stream after `from_position`, in
+    // the order they were appended. Positions start at 1.
+    async fn read_all(&self, from_position: u64, limit: usize) -> Result<Vec<StoredEvent>, Error>;
+}
+
+// Checks an append to a stream currently at version `actual`.
+pub(crate) fn check_append(
+    entity_id: &str,
+    expected: Option<&Version>,
+    actual: i64,
+    events: &[Event],
+) -> Result<(), Error> {
+    let expected = expected.map_or(0, Version::stored_value);
+    if expected != actual {
+        return Err(Error::VersionConflict {
+            entity_id: entity_id.to_string(),
+            expected: Some(expected).filter(|v| *v > 0),
+            actual: Some(actual).filter(|v| *v > 0),
+        });
+    }
+
+    if events.iter().any(|e| e.entity_id() != entity_id) {
+        return Err(Error::InvalidEvent);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn stream_version(version: i64) -> Result<Option<Version>, Error> {
+    if version == 0 {
+        return Ok(None);
+    }
+
+    Version::new(version)
+        .map(Some)
+        .map_err(|err| Error::Storage(Box::new(err)))
+}
+
+#[derive(Default)]
+struct Log {
+    events: Vec<StoredEvent>,
+    // Positions of the events of every stream, minus one.
+    streams: HashMap<String, Vec<usize>>,
+    ids: HashSet<String>,
+}
+
+// InMemEventStore keeps events in memory, for tests and prototypes.
+#[derive(Clone, Default)]
+pub struct InMemEventStore {
+    log: Arc<RwLock<Log>>,
+}
+
+impl InMemEventStore {
+    pub fn new() -> InMemEventStore {
+        InMemEventStore::default()
+    }
+}
+
+#[async_trait]
+impl EventStore for InMemEventStore {
+    async fn append(
+        &self,
+        entity_id: &str,
+        expected_version: Option<&Version>,
+        events: &[Event],
+    ) -> Result<Option<Version>, Error> {
+        let mut log = self.log.write().await;
+
+        let mut version = log
+            .streams
+            .get(entity_id)
+            .and_then(|indexes| indexes.last())
+            .map_or(0, |i| log.events[*i].version().value());
+        check_append(entity_id, expected_version, version, events)?;
+        if events.is_empty() {
+            return stream_version(version);
+        }
+
+        let mut ids = HashSet::new();
+        for event in events {
+            if log.ids.contains(event.id()) || !ids.insert(event.id()) {
+                return Err(Error::DuplicateEvent(event.id().to_string()));
+            }
+        }
+
+        version += 1;
+        for event in events {
+            let stored = StoredEvent::new(
+                event.clone(),
+                Version::new(version).map_err(|err| Error::Storage(Box::new(err)))?,
+                log.events.len() as u64 + 1,
+            );
+
+            let index = log.events.len();
+            log.ids.insert(event.id().to_string());
+            log.events.push(stored);
+            log.streams
+                .entry(entity_id.to_string())
+                .or_default()
+                .push(index);
+        }
+
+        stream_version(version)
+    }
+
+    async fn read_stream(
+        &self,
+        entity_id: &str,
+        from_version: Option<&Version>,
+    ) -> Result<Vec<StoredEvent>, Error> {
+        let log = self.log.read().await;
+        let from = from_version.map_or(0, Version::value);
+
+        Ok(log
+            .streams
+            .get(entity_id)
+            .map(|indexes| {
+                indexes
+                    .iter()
+                    .map(|i| &log.events[*i])
+                    .filter(|stored| stored.version().value() > from)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn read_all(&self, from_position: u64, limit: usize) -> Result<Vec<StoredEvent>, Error> {
+        let log = self.log.read().await;
+
+        Ok(log
+            .events
+            .iter()
+            .skip(from_position as usize)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn events(entity_id: &str, n: usize) -> Vec<Event> {
+        (0..n)
+            .map(|i| Event::create(entity_id, "topic.code", &i).unwrap())
+            .collect()
+    }
+
+    fn values(stored: &[StoredEvent]) -> Vec<(String, i64, u64)> {
+        stored
+            .iter()
+            .map(|s| {
+                (
+                    s.event().entity_id().to_string(),
+                    s.version().value(),
+                    s.position(),
+                )
+            })
+            .collect()
+    }
+
+    // Behavior every store must have.
+    pub(crate) async fn event_store<S: EventStore>(store: S) {
+        let a = events("a", 2);
+        let version = store.append("a", None, &a).await.unwrap().unwrap();
+        assert_eq!(version.value(), 1);
+
+        let b = events("b", 1);
+        store.append("b", None, &b).await.unwrap();
+        let more_a = events("a", 1);
+        let version = store
+            .append("a", Some(&version), &more_a)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(version.value(), 2);
+
+        // Conflicts, also for a unit of work started at a stale version.
+        let res = store
+            .append("a", Some(&Version::new(1).unwrap()), &events("a", 1))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::VersionConflict {
+                expected: Some(1),
+                actual: Some(2),
+                ..
+            })
+        ));
+        let res = store
+            .append("a", Some(&Version::new(1).unwrap().incr()), &events("a", 1))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::VersionConflict {
+                expected: Some(1),
+                actual: Some(2),
+                ..
+            })
+        ));
+        assert!(matches!(
+            store.append("a", None, &events("a", 1)).await,
+            Err(Error::VersionConflict { expected: None, .. })
+        ));
+        assert!(matches!(
+            store
+                .append("c", Some(&Version::new(1).unwrap()), &events("c", 1))
+                .await,
+            Err(Error::VersionConflict { actual: None, .. })
+        ));
+        assert!(matches!(
+            store.append("c", None, &events("b", 1)).await,
+            Err(Error::InvalidEvent)
+        ));
+
+        // Streams
+        let stream = store.read_stream("a", None).await.unwrap();
+        assert_eq!(
+            stream.iter().map(|s| s.event().clone()).collect::<Vec<_>>(),
+            [a.clone(), more_a.clone()].concat()
+        );
+        assert_eq!(
+            values(&stream),
+            vec![
+                ("a".to_string(), 1, 1),
+                ("a".to_string(), 1, 2),
+                ("a".to_string(), 2, 4)
+            ]
+        );
+        assert_eq!(
+            values(
+                &store
+                    .read_stream("a", Some(&Version::new(1).unwrap()))
+                    .await
+                    .unwrap()
+            ),
+            vec![("a".to_string(), 2, 4)]
+        );
+        assert!(store.read_stream("c", None).await.unwrap().is_empty());
+
+        // Global feed
+        let all = store.read_all(0, 10).await.unwrap();
+        assert_eq!(
+            all.iter().map(|s| s.event().clone()).collect::<Vec<_>>(),
+            [a, b, more_a].concat()
+        );
+        assert_eq!(
+            values(&store.read_all(1, 2).await.unwrap()),
+            vec![("a".to_string(), 1, 2), ("b".to_string(), 1, 3)]
+        );
+        assert!(store.read_all(4, 10).await.unwrap().is_empty());
+
+        // Duplicates, stored or in the same batch, append nothing.
+        let stored = stream[0].event().clone();
+        assert!(matches!(
+            store
+                .append("a", Some(&Version::new(2).unwrap()), &[stored])
+                .await,
+            Err(Error::DuplicateEvent(_))
+        ));
+        let event = Event::create("a", "topic.code", &4).unwrap();
+        assert!(matches!(
+            store
+                .append(
+                    "a",
+                    Some(&Version::new(2).unwrap()),
+                    &[event.clone(), event]
+                )
+                .await,
+            Err(Error::DuplicateEvent(_))
+        ));
+        assert_eq!(store.read_stream("a", None).await.unwrap().len(), 3);
+
+        // Empty appends only check the version.
+        assert_eq!(store.append("c", None, &[]).await.unwrap(), None);
+        assert_eq!(
+            store
+                .append("a", Some(&Version::new(2).unwrap()), &[])
+                .await
+                .unwrap()
+                .map(|v| v.value()),
+            Some(2)
+        );
+    }
+
+    // Concurrent appends expecting the same version: only one succeeds.
+    pub(crate) async fn concurrent_appends<S: EventStore + Clone + 'static>(store: S) {
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                store.append("a", None, &events("a", 1)).await
+            }));
+        }
+
+        let mut appended = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => appended += 1,
+                Err(Error::VersionConflict { .. }) => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+
+        assert_eq!(appended, 1);
+        assert_eq!(store.read_all(0, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        event_store(InMemEventStore::new()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn in_memory_concurrent_appends() {
+        concurrent_appends(InMemEventStore::new()).await;
+    }
+}
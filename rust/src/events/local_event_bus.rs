@@ -22,6 +22,15 @@ impl LocalEventBus {
     }
 }
 
+impl Default for LocalEventBus {
+    fn default() -> Self {
+        LocalEventBus::new()
+    }
+}
+
+// Subjects are matched segment by segment, ignoring case, and a `*` segment
+// matches any segment. Cache key patterns (see `ScanCache`) differ: they are
+// case sensitive and allow a `*` within a segment.
 fn subject_has_topic(subject: &str, topic: &str) -> bool {
     if subject == topic {
         return true;
@@ -113,29 +122,38 @@ mod tests {
         let res = event_bus
             .subscribe("topic.*", Box::new(counter.clone()))
             .await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
 
         let res = event_bus
             .subscribe("topic.*", Box::new(counter.clone()))
             .await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
 
         // Publish
         let event = Event::create("entity#01", "topic.code", &1).unwrap();
 
         let res = event_bus.publish(&[event]).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
 
         assert_eq!(*counter.count.lock().await, 2);
 
         let event = Event::create("entity#02", "topic.code", &2).unwrap();
 
         let res = event_bus.publish(&[event]).await;
-        assert!(!res.is_err());
+        assert!(res.is_ok());
 
         assert_eq!(*counter.count.lock().await, 6);
     }
 
+    #[test]
+    fn subjects() {
+        assert!(subject_has_topic("topic.*", "topic.code"));
+        assert!(subject_has_topic("TOPIC.*", "topic.Code"));
+
+        assert!(!subject_has_topic("topic.*", "topic.code.other"));
+        assert!(!subject_has_topic("topic.c*", "topic.code"));
+    }
+
     #[tokio::test]
     async fn thread_safe() {
         let event_bus = LocalEventBus::new();
@@ -1,15 +1,26 @@
 mod collector;
 mod errors;
 mod event;
+mod event_store;
 mod local_event_bus;
 mod nats_event_bus;
+mod outbox;
 mod publisher;
+mod relay;
+mod sqlite;
+mod sqlite_event_store;
+mod sqlite_outbox;
 mod subscriber;
 
 pub use collector::*;
 pub use errors::*;
 pub use event::*;
+pub use event_store::*;
 pub use local_event_bus::*;
 pub use nats_event_bus::*;
+pub use outbox::*;
 pub use publisher::*;
+pub use relay::*;
+pub use sqlite_event_store::*;
+pub use sqlite_outbox::*;
 pub use subscriber::*;
@@ -38,7 +38,7 @@ impl Publisher for NatsEventBus {
                 entity_id: event.entity_id().to_string(),
                 topic: event.topic().to_string(),
                 payload: event.payload().to_vec(),
-                timestamp: event.timestamp().clone(),
+                timestamp: *event.timestamp(),
             };
 
             let msg = serde_json::to_vec(&nats_event).map_err(Error::SerializingEvent)?;
@@ -0,0 +1,202 @@
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::events::{Collector, Error, Event};
+
+// Outbox stores events until they are published, so recording them can be part
+// of the same transaction as the changes they describe. A `Relay` then
+// publishes them.
+#[async_trait]
+pub trait Outbox: Sync + Send {
+    // Stores events to be published. Events already stored are ignored.
+    async fn save(&self, events: &[Event]) -> Result<(), Error>;
+
+    // Returns up to `limit` undelivered events, oldest first.
+    async fn pending(&self, limit: usize) -> Result<Vec<Event>, Error>;
+
+    // Marks events as delivered, so they are not published again, even if
+    // saved again.
+    async fn mark_delivered(&self, ids: &[String]) -> Result<(), Error>;
+
+    // Saves the events recorded in `collector` and drains it. They are kept
+    // in it if saving fails.
+    async fn save_collected(&self, collector: &mut Collector) -> Result<(), Error> {
+        self.save(collector.all()).await?;
+        collector.drain();
+
+        Ok(())
+    }
+}
+
+// InMemOutbox keeps events in memory, so they are lost with the process. It
+// suits tests and single process applications.
+//
+// Unlike `SqliteOutbox`, it only remembers the ids of the last delivered
+// events, 10000 by default. Older delivered events are forgotten, so saving
+// them again publishes them again.
+#[derive(Clone)]
+pub struct InMemOutbox {
+    store: Arc<Mutex<Store>>,
+    delivered_capacity: usize,
+}
+
+#[derive(Default)]
+struct Store {
+    // Pending events by sequence, with the sequence of every id.
+    pending: BTreeMap<u64, Event>,
+    sequences: HashMap<String, u64>,
+    next: u64,
+    // Ids of the last delivered events, oldest first.
+    delivered: HashSet<String>,
+    delivered_order: VecDeque<String>,
+}
+
+impl InMemOutbox {
+    pub fn new() -> InMemOutbox {
+        InMemOutbox {
+            store: Arc::new(Mutex::new(Store::default())),
+            delivered_capacity: 10_000,
+        }
+    }
+
+    // Number of delivered ids remembered, to ignore those events if saved
+    // again.
+    pub fn with_delivered_capacity(mut self, capacity: usize) -> InMemOutbox {
+        self.delivered_capacity = capacity;
+        self
+    }
+}
+
+impl Default for InMemOutbox {
+    fn default() -> InMemOutbox {
+        InMemOutbox::new()
+    }
+}
+
+#[async_trait]
+impl Outbox for InMemOutbox {
+    async fn save(&self, events: &[Event]) -> Result<(), Error> {
+        let mut store = self.store.lock().await;
+
+        for event in events {
+            if store.delivered.contains(event.id()) || store.sequences.contains_key(event.id()) {
+                continue;
+            }
+
+            let seq = store.next;
+            store.next += 1;
+            store.sequences.insert(event.id().to_string(), seq);
+            store.pending.insert(seq, event.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn pending(&self, limit: usize) -> Result<Vec<Event>, Error> {
+        let store = self.store.lock().await;
+
+        Ok(store.pending.values().take(limit).cloned().collect())
+    }
+
+    async fn mark_delivered(&self, ids: &[String]) -> Result<(), Error> {
+        let mut store = self.store.lock().await;
+
+        for id in ids {
+            let Some(seq) = store.sequences.remove(id) else {
+                continue;
+            };
+            store.pending.remove(&seq);
+
+            if self.delivered_capacity == 0 {
+                continue;
+            }
+            if store.delivered_order.len() >= self.delivered_capacity {
+                if let Some(oldest) = store.delivered_order.pop_front() {
+                    store.delivered.remove(&oldest);
+                }
+            }
+            store.delivered.insert(id.clone());
+            store.delivered_order.push_back(id.clone());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Serialize;
+
+    use crate::events::Publishable;
+
+    #[derive(Serialize)]
+    struct Created;
+
+    impl Publishable for Created {
+        fn entity_id(&self) -> &str {
+            "entity#01"
+        }
+
+        fn topic(&self) -> &str {
+            "entity.created"
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_and_delivered() {
+        let outbox = InMemOutbox::new();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event::create("entity#01", "topic.code", &i).unwrap())
+            .collect();
+
+        outbox.save(&events).await.unwrap();
+        outbox.save(&events[..1]).await.unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap(), events);
+        assert_eq!(outbox.pending(2).await.unwrap(), events[..2]);
+
+        outbox
+            .mark_delivered(&[events[0].id().to_string()])
+            .await
+            .unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap(), events[1..]);
+
+        // Delivered events are not saved again.
+        outbox.save(&events[..1]).await.unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap(), events[1..]);
+    }
+
+    #[tokio::test]
+    async fn forgets_oldest_delivered() {
+        let outbox = InMemOutbox::new().with_delivered_capacity(2);
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event::create("entity#01", "topic.code", &i).unwrap())
+            .collect();
+
+        outbox.save(&events).await.unwrap();
+        let ids: Vec<String> = events.iter().map(|e| e.id().to_string()).collect();
+        outbox.mark_delivered(&ids).await.unwrap();
+        assert!(outbox.pending(10).await.unwrap().is_empty());
+
+        // Only the first one was forgotten.
+        outbox.save(&events).await.unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap(), events[..1]);
+    }
+
+    #[tokio::test]
+    async fn save_collected() {
+        let outbox = InMemOutbox::new();
+        let mut collector = Collector::create();
+        collector.record(Created).unwrap();
+
+        outbox.save_collected(&mut collector).await.unwrap();
+        assert!(collector.all().is_empty());
+
+        let pending = outbox.pending(10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].topic(), "entity.created");
+    }
+}
@@ -0,0 +1,289 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::events::{Error, Outbox, Publisher};
+
+const BATCH_SIZE: usize = 100;
+
+type ErrorHandler = dyn Fn(&Error) + Sync + Send;
+
+// Relay publishes the events stored in an outbox and marks them as delivered.
+//
+// Delivery is at least once: events are marked after being published, so a
+// crash in between publishes them again. Handlers should then be idempotent,
+// for instance by keeping the ids of the events already handled.
+pub struct Relay<O, P> {
+    outbox: O,
+    publisher: P,
+    batch_size: usize,
+    on_error: Option<Arc<ErrorHandler>>,
+}
+
+impl<O, P> Relay<O, P>
+where
+    O: Outbox,
+    P: Publisher + Sync + Send,
+{
+    pub fn new(outbox: O, publisher: P) -> Relay<O, P> {
+        Relay {
+            outbox,
+            publisher,
+            batch_size: BATCH_SIZE,
+            on_error: None,
+        }
+    }
+
+    // Maximum number of events published at once.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Relay<O, P> {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    // Called with the failures of the task started by `start`, for instance
+    // to log them.
+    pub fn with_error_handler<F>(mut self, on_error: F) -> Relay<O, P>
+    where
+        F: Fn(&Error) + Sync + Send + 'static,
+    {
+        self.on_error = Some(Arc::new(on_error));
+        self
+    }
+
+    // Publishes pending events, in order, until there are none left. Returns
+    // how many were published. On failure, the events not delivered yet stay
+    // pending.
+    pub async fn relay(&self) -> Result<usize, Error> {
+        let mut relayed = 0;
+
+        loop {
+            let events = self.outbox.pending(self.batch_size).await?;
+            if events.is_empty() {
+                return Ok(relayed);
+            }
+
+            self.publisher.publish(&events).await?;
+
+            let ids: Vec<String> = events.iter().map(|e| e.id().to_string()).collect();
+            self.outbox.mark_delivered(&ids).await?;
+
+            relayed += events.len();
+            if events.len() < self.batch_size {
+                return Ok(relayed);
+            }
+        }
+    }
+
+    // Spawns a task relaying pending events every `interval`. Failures are
+    // reported to the error handler, if any, and retried on the next tick.
+    // The task runs until aborted.
+    pub fn start(self, interval: Duration) -> JoinHandle<()>
+    where
+        O: 'static,
+        P: 'static,
+    {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(err) = self.relay().await {
+                    if let Some(on_error) = &self.on_error {
+                        on_error(&err);
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    use crate::events::{Event, InMemOutbox, SqliteOutbox};
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        published: Arc<Mutex<Vec<String>>>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl Recorder {
+        fn published(&self) -> Vec<String> {
+            self.published.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Publisher for Recorder {
+        async fn publish(&self, events: &[Event]) -> Result<(), Error> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(Error::PublishingEvent("broker is down".into()));
+            }
+
+            let mut published = self.published.lock().unwrap();
+            published.extend(events.iter().map(|e| e.id().to_string()));
+
+            Ok(())
+        }
+    }
+
+    // Outbox dying right after publishing, before marking events delivered.
+    struct Crashing(SqliteOutbox);
+
+    #[async_trait]
+    impl Outbox for Crashing {
+        async fn save(&self, events: &[Event]) -> Result<(), Error> {
+            self.0.save(events).await
+        }
+
+        async fn pending(&self, limit: usize) -> Result<Vec<Event>, Error> {
+            self.0.pending(limit).await
+        }
+
+        async fn mark_delivered(&self, _ids: &[String]) -> Result<(), Error> {
+            Err(Error::Storage("process crashed".into()))
+        }
+    }
+
+    fn events(n: usize) -> Vec<Event> {
+        (0..n)
+            .map(|i| Event::create("entity#01", "topic.code", &i).unwrap())
+            .collect()
+    }
+
+    fn ids(events: &[Event]) -> Vec<String> {
+        events.iter().map(|e| e.id().to_string()).collect()
+    }
+
+    fn temp_db() -> PathBuf {
+        std::env::temp_dir().join(format!("core-lib-outbox-{}.db", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn relay_in_batches() {
+        let outbox = InMemOutbox::new();
+        let recorder = Recorder::default();
+        let relay = Relay::new(outbox.clone(), recorder.clone()).with_batch_size(2);
+
+        let events = events(5);
+        outbox.save(&events).await.unwrap();
+
+        assert_eq!(relay.relay().await.unwrap(), 5);
+        assert_eq!(recorder.published(), ids(&events));
+        assert_eq!(relay.relay().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn publishing_failure() {
+        let outbox = InMemOutbox::new();
+        let recorder = Recorder::default();
+        let relay = Relay::new(outbox.clone(), recorder.clone());
+
+        let events = events(2);
+        outbox.save(&events).await.unwrap();
+
+        recorder.failing.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            relay.relay().await,
+            Err(Error::PublishingEvent(_))
+        ));
+        assert_eq!(outbox.pending(10).await.unwrap(), events);
+
+        recorder.failing.store(false, Ordering::SeqCst);
+        assert_eq!(relay.relay().await.unwrap(), 2);
+        assert!(outbox.pending(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn crash_before_publishing() {
+        let path = temp_db();
+        let events = events(3);
+
+        // The process dies once the events are committed.
+        SqliteOutbox::open(&path)
+            .unwrap()
+            .save(&events)
+            .await
+            .unwrap();
+
+        let recorder = Recorder::default();
+        let relay = Relay::new(SqliteOutbox::open(&path).unwrap(), recorder.clone());
+        assert_eq!(relay.relay().await.unwrap(), 3);
+        assert_eq!(recorder.published(), ids(&events));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn crash_before_marking_delivered() {
+        let path = temp_db();
+        let events = events(3);
+        let recorder = Recorder::default();
+
+        let outbox = SqliteOutbox::open(&path).unwrap();
+        outbox.save(&events).await.unwrap();
+        let relay = Relay::new(Crashing(outbox), recorder.clone());
+        assert!(relay.relay().await.is_err());
+        drop(relay);
+
+        // Published again after restarting, but only once delivered.
+        let relay = Relay::new(SqliteOutbox::open(&path).unwrap(), recorder.clone());
+        assert_eq!(relay.relay().await.unwrap(), 3);
+        assert_eq!(relay.relay().await.unwrap(), 0);
+        assert_eq!(recorder.published(), [ids(&events), ids(&events)].concat());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn background_relay() {
+        let outbox = InMemOutbox::new();
+        let recorder = Recorder::default();
+        let task = Relay::new(outbox.clone(), recorder.clone()).start(Duration::from_secs(1));
+
+        let events = events(2);
+        outbox.save(&events).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(recorder.published(), ids(&events));
+
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn background_relay_errors() {
+        let outbox = InMemOutbox::new();
+        let recorder = Recorder::default();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+
+        outbox.save(&events(1)).await.unwrap();
+        recorder.failing.store(true, Ordering::SeqCst);
+
+        let task = {
+            let errors = errors.clone();
+            Relay::new(outbox.clone(), recorder.clone())
+                .with_error_handler(move |err| errors.lock().unwrap().push(err.to_string()))
+                .start(Duration::from_secs(1))
+        };
+
+        // Ticks at 0, 1 and 2 seconds.
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(errors.lock().unwrap().len(), 3);
+
+        recorder.failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(errors.lock().unwrap().len(), 3);
+        assert_eq!(recorder.published().len(), 1);
+
+        task.abort();
+    }
+}
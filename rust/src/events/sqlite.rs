@@ -0,0 +1,26 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::events::{Error, Event};
+
+// Columns of a stored event: id, entity id, topic, payload and timestamp.
+pub(crate) type Row = (String, String, String, Vec<u8>, String);
+
+pub(crate) fn storage<E>(err: E) -> Error
+where
+    E: std::error::Error + Sync + Send + 'static,
+{
+    Error::Storage(Box::new(err))
+}
+
+// Timestamps are stored as RFC 3339 text, keeping their precision.
+pub(crate) fn timestamp(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+pub(crate) fn event((id, entity_id, topic, payload, timestamp): Row) -> Result<Event, Error> {
+    let timestamp = DateTime::parse_from_rfc3339(&timestamp)
+        .map_err(storage)?
+        .with_timezone(&Utc);
+
+    Event::new(id, entity_id, topic, payload, timestamp)
+}
@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, TransactionBehavior};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::events::event_store::{check_append, stream_version};
+use crate::events::sqlite::{event, storage, timestamp, Row};
+use crate::events::{Error, Event, EventStore, StoredEvent};
+use crate::models::Version;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        position INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL UNIQUE,
+        entity_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        topic TEXT NOT NULL,
+        payload BLOB NOT NULL,
+        timestamp TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_stream ON events (entity_id, version);
+";
+
+// SqliteEventStore stores events in the `events` table of a SQLite database.
+//
+// Appends take the write lock before reading the version of the stream, so
+// conflicts are also detected between processes sharing the database.
+#[derive(Clone)]
+pub struct SqliteEventStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteEventStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteEventStore, Error> {
+        let conn = Connection::open(path).map_err(storage)?;
+
+        SqliteEventStore::new(conn)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteEventStore, Error> {
+        let conn = Connection::open_in_memory().map_err(storage)?;
+
+        SqliteEventStore::new(conn)
+    }
+
+    // Uses a connection to the application database, creating the table if
+    // needed.
+    pub fn new(conn: Connection) -> Result<SqliteEventStore, Error> {
+        conn.execute_batch(SCHEMA).map_err(storage)?;
+
+        Ok(SqliteEventStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Connections are blocking, so they are used from the blocking pool.
+    async fn call<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await
+        .map_err(storage)?
+    }
+
+    async fn query<P>(&self, sql: &'static str, params: P) -> Result<Vec<StoredEvent>, Error>
+    where
+        P: rusqlite::Params + Send + 'static,
+    {
+        let rows = self
+            .call(move |conn| {
+                let mut stmt = conn.prepare_cached(sql).map_err(storage)?;
+                let rows = stmt
+                    .query_map(params, |row| {
+                        let event: Row = (
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        );
+                        Ok((event, row.get::<_, i64>(5)?, row.get::<_, i64>(6)?))
+                    })
+                    .map_err(storage)?;
+
+                rows.collect::<rusqlite::Result<Vec<_>>>().map_err(storage)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|(row, version, position)| {
+                Ok(StoredEvent::new(
+                    event(row)?,
+                    Version::new(version).map_err(storage)?,
+                    position as u64,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl EventStore for SqliteEventStore {
+    async fn append(
+        &self,
+        entity_id: &str,
+        expected_version: Option<&Version>,
+        events: &[Event],
+    ) -> Result<Option<Version>, Error> {
+        let entity_id = entity_id.to_string();
+        let expected_version = expected_version.cloned();
+        let events = events.to_vec();
+
+        self.call(move |conn| {
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(storage)?;
+
+            let mut version: i64 = tx
+                .query_row(
+                    "SELECT COALESCE(MAX(version), 0) FROM events WHERE entity_id = ?1",
+                    [&entity_id],
+                    |row| row.get(0),
+                )
+                .map_err(storage)?;
+            check_append(&entity_id, expected_version.as_ref(), version, &events)?;
+            if events.is_empty() {
+                return stream_version(version);
+            }
+
+            version += 1;
+            {
+                let mut stmt = tx
+                    .prepare_cached(
+                        "INSERT INTO events (id, entity_id, version, topic, payload, timestamp)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    )
+                    .map_err(storage)?;
+
+                let mut exists = tx
+                    .prepare_cached("SELECT EXISTS (SELECT 1 FROM events WHERE id = ?1)")
+                    .map_err(storage)?;
+
+                for event in events.iter() {
+                    // Also sees the events inserted just before in the batch.
+                    let duplicate: bool = exists
+                        .query_row([event.id()], |row| row.get(0))
+                        .map_err(storage)?;
+                    if duplicate {
+                        return Err(Error::DuplicateEvent(event.id().to_string()));
+                    }
+
+                    stmt.execute(params![
+                        event.id(),
+                        event.entity_id(),
+                        version,
+                        event.topic(),
+                        event.payload(),
+                        timestamp(event.timestamp()),
+                    ])
+                    .map_err(storage)?;
+                }
+            }
+
+            tx.commit().map_err(storage)?;
+
+            stream_version(version)
+        })
+        .await
+    }
+
+    async fn read_stream(
+        &self,
+        entity_id: &str,
+        from_version: Option<&Version>,
+    ) -> Result<Vec<StoredEvent>, Error> {
+        let from = from_version.map_or(0, Version::value);
+
+        self.query(
+            "SELECT id, entity_id, topic, payload, timestamp, version, position FROM events
+             WHERE entity_id = ?1 AND version > ?2 ORDER BY position",
+            (entity_id.to_string(), from),
+        )
+        .await
+    }
+
+    async fn read_all(&self, from_position: u64, limit: usize) -> Result<Vec<StoredEvent>, Error> {
+        self.query(
+            "SELECT id, entity_id, topic, payload, timestamp, version, position FROM events
+             WHERE position > ?1 ORDER BY position LIMIT ?2",
+            (from_position as i64, limit as i64),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    use crate::events::event_store::tests::{concurrent_appends, event_store};
+
+    #[tokio::test]
+    async fn sqlite() {
+        event_store(SqliteEventStore::open_in_memory().unwrap()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn sqlite_concurrent_appends() {
+        concurrent_appends(SqliteEventStore::open_in_memory().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn across_connections() {
+        let path = std::env::temp_dir().join(format!("core-lib-events-{}.db", Uuid::new_v4()));
+        let store = SqliteEventStore::open(&path).unwrap();
+        let other = SqliteEventStore::open(&path).unwrap();
+
+        let event = Event::create("a", "topic.code", &1).unwrap();
+        store.append("a", None, &[event]).await.unwrap();
+
+        let event = Event::create("a", "topic.code", &2).unwrap();
+        assert!(matches!(
+            other.append("a", None, &[event]).await,
+            Err(Error::VersionConflict { .. })
+        ));
+        assert_eq!(other.read_stream("a", None).await.unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
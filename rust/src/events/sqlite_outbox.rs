@@ -0,0 +1,247 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, Transaction};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::events::sqlite::{event, storage, timestamp, Row};
+use crate::events::{Collector, Error, Event, Outbox};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS outbox (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL UNIQUE,
+        entity_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        payload BLOB NOT NULL,
+        timestamp TEXT NOT NULL,
+        delivered_at TEXT
+    );
+    CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (seq) WHERE delivered_at IS NULL;
+";
+
+// SqliteOutbox stores events in the `outbox` table of a SQLite database.
+//
+// Events are meant to be saved with `save_with`, in the same transaction as
+// the application writes, so they are stored if and only if the writes are
+// committed. Delivered events are kept, marked with the time of delivery.
+#[derive(Clone)]
+pub struct SqliteOutbox {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteOutbox {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteOutbox, Error> {
+        let conn = Connection::open(path).map_err(storage)?;
+
+        SqliteOutbox::new(conn)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteOutbox, Error> {
+        let conn = Connection::open_in_memory().map_err(storage)?;
+
+        SqliteOutbox::new(conn)
+    }
+
+    // Uses a connection to the application database, creating the table if
+    // needed.
+    pub fn new(conn: Connection) -> Result<SqliteOutbox, Error> {
+        conn.execute_batch(SCHEMA).map_err(storage)?;
+
+        Ok(SqliteOutbox {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Runs `f` and saves `events` in a single transaction, committed only if
+    // `f` succeeds.
+    pub async fn save_with<F, T>(&self, events: &[Event], f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Transaction) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let events = events.to_vec();
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let res = f(&tx)?;
+            insert(&tx, &events)?;
+            tx.commit()?;
+
+            Ok(res)
+        })
+        .await
+    }
+
+    // Like `save_with`, saving the events recorded in `collector`, which is
+    // drained once committed.
+    pub async fn save_collected_with<F, T>(
+        &self,
+        collector: &mut Collector,
+        f: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&Transaction) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let res = self.save_with(collector.all(), f).await?;
+        collector.drain();
+
+        Ok(res)
+    }
+
+    // Connections are blocking, so they are used from the blocking pool.
+    async fn call<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await
+        .map_err(storage)?
+        .map_err(storage)
+    }
+}
+
+fn insert(conn: &Connection, events: &[Event]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO outbox (id, entity_id, topic, payload, timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+
+    for event in events {
+        stmt.execute(params![
+            event.id(),
+            event.entity_id(),
+            event.topic(),
+            event.payload(),
+            timestamp(event.timestamp()),
+        ])?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Outbox for SqliteOutbox {
+    async fn save(&self, events: &[Event]) -> Result<(), Error> {
+        self.save_with(events, |_| Ok(())).await
+    }
+
+    async fn pending(&self, limit: usize) -> Result<Vec<Event>, Error> {
+        let rows = self
+            .call(move |conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT id, entity_id, topic, payload, timestamp FROM outbox
+                     WHERE delivered_at IS NULL ORDER BY seq LIMIT ?1",
+                )?;
+
+                let rows = stmt.query_map([limit as i64], |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                })?;
+
+                rows.collect::<rusqlite::Result<Vec<Row>>>()
+            })
+            .await?;
+
+        rows.into_iter().map(event).collect()
+    }
+
+    async fn mark_delivered(&self, ids: &[String]) -> Result<(), Error> {
+        let ids = ids.to_vec();
+        let now = timestamp(&Utc::now());
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "UPDATE outbox SET delivered_at = ?1 WHERE id = ?2 AND delivered_at IS NULL",
+                )?;
+                for id in ids.iter() {
+                    stmt.execute(params![now, id])?;
+                }
+            }
+            tx.commit()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pending_and_delivered() {
+        let outbox = SqliteOutbox::open_in_memory().unwrap();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event::create("entity#01", "topic.code", &i).unwrap())
+            .collect();
+
+        outbox.save(&events).await.unwrap();
+        outbox.save(&events[..1]).await.unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap(), events);
+        assert_eq!(outbox.pending(2).await.unwrap(), events[..2]);
+
+        outbox
+            .mark_delivered(&[events[0].id().to_string()])
+            .await
+            .unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap(), events[1..]);
+    }
+
+    #[tokio::test]
+    async fn transactional_save() {
+        let outbox = SqliteOutbox::open_in_memory().unwrap();
+        outbox
+            .save_with(&[], |tx| {
+                tx.execute("CREATE TABLE users (name TEXT NOT NULL UNIQUE)", [])
+            })
+            .await
+            .unwrap();
+
+        let event = Event::create("user#01", "user.created", &"alan").unwrap();
+        let inserted = outbox
+            .save_with(std::slice::from_ref(&event), |tx| {
+                tx.execute("INSERT INTO users (name) VALUES ('alan')", [])
+            })
+            .await
+            .unwrap();
+        assert_eq!(inserted, 1);
+        assert_eq!(outbox.pending(10).await.unwrap(), vec![event.clone()]);
+
+        // The write fails, so the event is not saved either and stays in the
+        // collector.
+        let mut collector = Collector::new(vec![
+            Event::create("user#02", "user.created", &"alan").unwrap()
+        ]);
+        let res = outbox
+            .save_collected_with(&mut collector, |tx| {
+                tx.execute("INSERT INTO users (name) VALUES ('alan')", [])
+            })
+            .await;
+        assert!(matches!(res, Err(Error::Storage(_))));
+        assert_eq!(collector.all().len(), 1);
+        assert_eq!(outbox.pending(10).await.unwrap().len(), 1);
+
+        outbox
+            .save_collected_with(&mut collector, |tx| {
+                tx.execute("INSERT INTO users (name) VALUES ('ada')", [])
+            })
+            .await
+            .unwrap();
+        assert!(collector.all().is_empty());
+        assert_eq!(outbox.pending(10).await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::events::{Collector, Error as EventsError, Event, Publishable, Publisher};
+use crate::models::{StrId, Timestamps, Version};
+
+// AggregateRoot holds what every entity has: its id, version, timestamps and
+// the events recorded since it was loaded. Entities embed it and record an
+// event for every change.
+#[derive(Debug, Clone)]
+pub struct AggregateRoot {
+    id: StrId,
+    version: Version,
+    timestamps: Timestamps,
+    events: Collector,
+}
+
+impl AggregateRoot {
+    // Aggregate loaded from storage, without pending events.
+    pub fn new(id: StrId, version: Version, timestamps: Timestamps) -> AggregateRoot {
+        AggregateRoot {
+            id,
+            version,
+            timestamps,
+            events: Collector::create(),
+        }
+    }
+
+    // New aggregate, at the first version.
+    pub fn create(id: StrId) -> AggregateRoot {
+        AggregateRoot {
+            id,
+            version: Version::init_version(),
+            timestamps: Timestamps::create(),
+            events: Collector::create(),
+        }
+    }
+
+    pub fn id(&self) -> &StrId {
+        &self.id
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn timestamps(&self) -> &Timestamps {
+        &self.timestamps
+    }
+
+    pub fn events(&self) -> &[Event] {
+        self.events.all()
+    }
+
+    // Records an event and marks the aggregate as updated. The version is
+    // only incremented once per unit of work, until `mark_persisted`. Events
+    // of other entities are rejected.
+    pub fn record_event<P>(&mut self, event: P) -> Result<(), EventsError>
+    where
+        P: Publishable,
+    {
+        if event.entity_id() != self.id.value() {
+            return Err(EventsError::InvalidEvent);
+        }
+
+        self.events.record(event)?;
+        self.update();
+
+        Ok(())
+    }
+
+    pub fn update(&mut self) {
+        self.version = self.version.incr();
+        self.timestamps = self.timestamps.update();
+    }
+
+    pub fn delete(&mut self) {
+        self.version = self.version.incr();
+        self.timestamps = self.timestamps.delete();
+    }
+
+    // Ends the unit of work once the aggregate is stored, so the next change
+    // increments the version again.
+    pub fn mark_persisted(&mut self) {
+        self.version = self.version.persisted();
+    }
+
+    pub fn drain_events(&mut self) -> Vec<Event> {
+        self.events.drain()
+    }
+
+    // Publishes the pending events. If publishing fails, they are kept to be
+    // published again.
+    pub async fn publish_events<P>(&mut self, publisher: &P) -> Result<(), EventsError>
+    where
+        P: Publisher + Sync,
+    {
+        let events = self.events.drain();
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(err) = publisher.publish(&events).await {
+            self.events = Collector::new(events);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use chrono::{Duration, Utc};
+    use serde::Serialize;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    use crate::events::{EventStore, InMemEventStore};
+
+    #[derive(Serialize)]
+    struct Renamed {
+        id: String,
+        name: String,
+    }
+
+    impl Publishable for Renamed {
+        fn entity_id(&self) -> &str {
+            &self.id
+        }
+
+        fn topic(&self) -> &str {
+            "user.renamed"
+        }
+    }
+
+    fn renamed(name: &str) -> Renamed {
+        Renamed {
+            id: "user#01".to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        published: Mutex<Vec<Event>>,
+        failing: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Publisher for Recorder {
+        async fn publish(&self, events: &[Event]) -> Result<(), EventsError> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(EventsError::PublishingEvent("broker is down".into()));
+            }
+
+            self.published.lock().unwrap().extend_from_slice(events);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn version_bumped_once() {
+        let created_at = Utc::now() - Duration::days(1);
+        let mut user = AggregateRoot::new(
+            StrId::new("user#01").unwrap(),
+            Version::new(3).unwrap(),
+            Timestamps::new(created_at, created_at, None).unwrap(),
+        );
+
+        user.record_event(renamed("Alan")).unwrap();
+        user.record_event(renamed("Ada")).unwrap();
+
+        assert_eq!(user.version().value(), 4);
+        assert!(user.timestamps().updated_at() > user.timestamps().created_at());
+        assert_eq!(user.events().len(), 2);
+
+        let mut user = AggregateRoot::create(StrId::new("user#01").unwrap());
+        user.record_event(renamed("Alan")).unwrap();
+        assert_eq!(user.version().value(), 1);
+    }
+
+    #[test]
+    fn consecutive_units_of_work() {
+        let mut user = AggregateRoot::create(StrId::new("user#01").unwrap());
+        user.record_event(renamed("Alan")).unwrap();
+        user.mark_persisted();
+        assert_eq!(user.version().value(), 1);
+
+        user.record_event(renamed("Ada")).unwrap();
+        user.record_event(renamed("Grace")).unwrap();
+        assert_eq!(user.version().value(), 2);
+        user.mark_persisted();
+
+        user.delete();
+        assert_eq!(user.version().value(), 3);
+        assert!(user.timestamps().deleted_at().is_some());
+    }
+
+    // The version of an aggregate is the version of its stream.
+    #[tokio::test]
+    async fn append_to_event_store() {
+        let store = InMemEventStore::new();
+        let mut user = AggregateRoot::create(StrId::new("user#01").unwrap());
+        user.record_event(renamed("Alan")).unwrap();
+        user.record_event(renamed("Ada")).unwrap();
+
+        let events = user.drain_events();
+        let version = store
+            .append(user.id().value(), Some(user.version()), &events)
+            .await
+            .unwrap();
+        assert_eq!(version.as_ref(), Some(&user.version().persisted()));
+        user.mark_persisted();
+
+        let mut stale = user.clone();
+        user.record_event(renamed("Grace")).unwrap();
+        let events = user.drain_events();
+        let version = store
+            .append(user.id().value(), Some(user.version()), &events)
+            .await
+            .unwrap();
+        assert_eq!(version.map(|v| v.value()), Some(2));
+        user.mark_persisted();
+
+        stale.record_event(renamed("Barbara")).unwrap();
+        assert!(matches!(
+            store
+                .append(stale.id().value(), Some(stale.version()), stale.events())
+                .await,
+            Err(EventsError::VersionConflict { .. })
+        ));
+
+        let loaded = AggregateRoot::new(
+            user.id().clone(),
+            store.read_stream("user#01", None).await.unwrap()[2]
+                .version()
+                .clone(),
+            user.timestamps().clone(),
+        );
+        assert_eq!(loaded.version(), user.version());
+    }
+
+    #[test]
+    fn events_of_other_entities() {
+        let mut user = AggregateRoot::create(StrId::new("user#02").unwrap());
+
+        assert!(matches!(
+            user.record_event(renamed("Alan")),
+            Err(EventsError::InvalidEvent)
+        ));
+        assert!(user.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn publish_events() {
+        let publisher = Recorder::default();
+        let mut user = AggregateRoot::create(StrId::new("user#01").unwrap());
+        user.record_event(renamed("Alan")).unwrap();
+
+        publisher.failing.store(true, Ordering::SeqCst);
+        assert!(user.publish_events(&publisher).await.is_err());
+        assert_eq!(user.events().len(), 1);
+
+        publisher.failing.store(false, Ordering::SeqCst);
+        user.publish_events(&publisher).await.unwrap();
+        assert!(user.events().is_empty());
+
+        let published = publisher.published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].topic(), "user.renamed");
+    }
+}
@@ -1,8 +1,10 @@
+mod aggregate_root;
 mod errors;
 mod str_id;
 mod timestamps;
 mod version;
 
+pub use aggregate_root::*;
 pub use errors::*;
 pub use str_id::*;
 pub use timestamps::*;
@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use uuid::Uuid;
 
 use crate::models::Error;
@@ -32,8 +33,8 @@ impl StrId {
     }
 }
 
-impl ToString for StrId {
-    fn to_string(&self) -> String {
-        self.id.to_owned()
+impl Display for StrId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.id)
     }
 }
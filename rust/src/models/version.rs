@@ -29,6 +29,15 @@ impl Version {
         self.version
     }
 
+    // Version stored before the current unit of work, 0 if there is none yet.
+    pub fn stored_value(&self) -> i64 {
+        if self.updated {
+            self.version - 1
+        } else {
+            self.version
+        }
+    }
+
     pub fn incr(&self) -> Version {
         if self.updated {
             return self.clone();
@@ -39,4 +48,12 @@ impl Version {
             updated: true,
         }
     }
+
+    // Same version once stored, so the next unit of work increments it again.
+    pub fn persisted(&self) -> Version {
+        Version {
+            version: self.version,
+            updated: false,
+        }
+    }
 }